@@ -16,4 +16,5 @@
 
 pub mod api;
 pub mod db;
+pub mod event_bus;
 pub mod repository;
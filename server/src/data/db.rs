@@ -14,5 +14,13 @@
  * limitations under the License.
  */
 
+pub mod cached_chat_table;
+pub mod chat_store;
+pub mod comment_archive;
+pub mod database_cipher;
+pub mod database_lock;
 pub mod dev_flex_chat_database;
 pub mod entity;
+pub mod in_memory_chat_database;
+pub mod sqlite_chat_database;
+pub mod wal_chat_database;
@@ -0,0 +1,305 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+
+use crate::data::db::dev_flex_chat_database::DevFlexChatTable;
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::prelude::*;
+
+/// `DevFlexChatTable` with the indexes of the channels and the comments of each channel.
+///
+/// The comments of each channel are ordered by the `sequence`.
+pub struct CachedChatTable {
+    table: DevFlexChatTable,
+    channel_indexes: HashMap<ChannelID, usize>,
+    comment_indexes: HashMap<ChannelID, Vec<usize>>,
+}
+
+impl CachedChatTable {
+    pub fn new(table: DevFlexChatTable) -> Self {
+        let mut cached_table = Self {
+            table: DevFlexChatTable {
+                version_code: table.version_code,
+                comments: Vec::with_capacity(table.comments.len()),
+                channels: Vec::with_capacity(table.channels.len()),
+                migration_history: table.migration_history,
+            },
+            channel_indexes: Default::default(),
+            comment_indexes: Default::default(),
+        };
+
+        for channel in table.channels {
+            cached_table.push_channel(channel);
+        }
+
+        // keep the comments of the unknown channel to write back them as is.
+        for comment in table.comments {
+            cached_table.push_comment_unchecked(comment);
+        }
+
+        // e.g. the comments that are replayed from the log.
+        for (channel_id, indexes) in &cached_table.comment_indexes {
+            if let (Some(channel_index), Some(last_index)) =
+                (cached_table.channel_indexes.get(channel_id), indexes.last())
+            {
+                let last_sequence = cached_table.table.comments[*last_index].sequence;
+                let channel = &mut cached_table.table.channels[*channel_index];
+                channel.last_sequence = channel.last_sequence.max(last_sequence);
+            }
+        }
+
+        cached_table
+    }
+
+    pub fn table(&self) -> &DevFlexChatTable {
+        &self.table
+    }
+
+    pub fn channels(&self) -> &[ChannelEntity] {
+        &self.table.channels
+    }
+
+    pub fn find_channel(&self, channel_id: &ChannelID) -> Option<&ChannelEntity> {
+        self.channel_indexes
+            .get(channel_id)
+            .map(|index| &self.table.channels[*index])
+    }
+
+    pub fn comments(&self, channel_id: &ChannelID) -> Vec<CommentEntity> {
+        match self.comment_indexes.get(channel_id) {
+            Some(indexes) => indexes
+                .iter()
+                .map(|index| self.table.comments[*index].clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    pub fn push_channel(&mut self, entity: ChannelEntity) {
+        self.channel_indexes
+            .insert(entity.id.clone(), self.table.channels.len());
+        self.table.channels.push(entity);
+    }
+
+    pub fn find_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Option<&CommentEntity> {
+        self.comment_indexes
+            .get(channel_id)?
+            .iter()
+            .map(|index| &self.table.comments[*index])
+            .find(|data| data.id == *id)
+    }
+
+    /// Returns the comments whose `sequence` is greater than the `sequence`.
+    pub fn comments_after(&self, channel_id: &ChannelID, sequence: u64) -> Vec<CommentEntity> {
+        let indexes = match self.comment_indexes.get(channel_id) {
+            Some(data) => data,
+            None => return vec![],
+        };
+
+        let start = match indexes
+            .binary_search_by_key(&sequence, |index| self.table.comments[*index].sequence)
+        {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+        indexes[start..]
+            .iter()
+            .map(|index| self.table.comments[*index].clone())
+            .collect()
+    }
+
+    /// Returns the comments whose `sequence` is less than or equal to the `sequence`.
+    pub fn comments_until(&self, channel_id: &ChannelID, sequence: u64) -> Vec<CommentEntity> {
+        let indexes = match self.comment_indexes.get(channel_id) {
+            Some(data) => data,
+            None => return vec![],
+        };
+
+        let end = match indexes
+            .binary_search_by_key(&sequence, |index| self.table.comments[*index].sequence)
+        {
+            Ok(position) => position + 1,
+            Err(position) => position,
+        };
+        indexes[..end]
+            .iter()
+            .map(|index| self.table.comments[*index].clone())
+            .collect()
+    }
+
+    /// Returns the `entity` with the next `sequence` of the channel.
+    pub fn assign_sequence(&self, mut entity: CommentEntity) -> Fallible<CommentEntity> {
+        match self.find_channel(&entity.channel_id) {
+            Some(channel) => {
+                entity.sequence = channel.last_sequence + 1;
+                Ok(entity)
+            }
+            None => failure::bail!("channel not found"),
+        }
+    }
+
+    pub fn push_comment(&mut self, entity: CommentEntity) -> Fallible<()> {
+        let channel_index = match self.channel_indexes.get(&entity.channel_id) {
+            Some(data) => *data,
+            None => failure::bail!("channel not found"),
+        };
+
+        let channel = &mut self.table.channels[channel_index];
+        if entity.sequence <= channel.last_sequence {
+            failure::bail!(
+                "sequence must be greater than {}: {}",
+                channel.last_sequence,
+                entity.sequence
+            );
+        }
+        channel.last_sequence = entity.sequence;
+
+        self.push_comment_unchecked(entity);
+        Ok(())
+    }
+
+    /// Returns a copy with the `entities` that keep their `sequence`. See the
+    /// `ChatStore::insert_comments`.
+    pub fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<Self> {
+        let mut table = self.filter_comments(|_| true);
+        for entity in entities {
+            let channel_index = match table.channel_indexes.get(&entity.channel_id) {
+                Some(data) => *data,
+                None => failure::bail!("channel not found"),
+            };
+
+            let last_comment_index = table
+                .comment_indexes
+                .get(&entity.channel_id)
+                .and_then(|indexes| indexes.last());
+            if let Some(index) = last_comment_index {
+                let last_sequence = table.table.comments[*index].sequence;
+                if entity.sequence <= last_sequence {
+                    failure::bail!(
+                        "sequence must be greater than {}: {}",
+                        last_sequence,
+                        entity.sequence
+                    );
+                }
+            }
+
+            let channel = &mut table.table.channels[channel_index];
+            channel.last_sequence = channel.last_sequence.max(entity.sequence);
+            table.push_comment_unchecked(entity);
+        }
+        Ok(table)
+    }
+
+    /// Returns the channel of the `entity` with the `name` and the `updated_at` of the `entity`.
+    pub fn merge_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        match self.find_channel(&entity.id) {
+            Some(channel) => Ok(ChannelEntity {
+                name: entity.name,
+                updated_at: entity.updated_at,
+                ..channel.clone()
+            }),
+            None => failure::bail!("channel not found"),
+        }
+    }
+
+    /// Returns the comment of the `entity` with the `message` and the `updated_at` of the
+    /// `entity`.
+    pub fn merge_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        match self.find_comment(&entity.channel_id, &entity.id) {
+            Some(comment) => Ok(CommentEntity {
+                message: entity.message,
+                updated_at: entity.updated_at,
+                ..comment.clone()
+            }),
+            None => failure::bail!("comment not found"),
+        }
+    }
+
+    /// Replaces the channel of the same `id` with the `entity`, and returns the previous one.
+    pub fn replace_channel(&mut self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        match self.channel_indexes.get(&entity.id) {
+            Some(index) => Ok(std::mem::replace(&mut self.table.channels[*index], entity)),
+            None => failure::bail!("channel not found"),
+        }
+    }
+
+    /// Replaces the comment of the same `id` with the `entity`, and returns the previous one.
+    pub fn replace_comment(&mut self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let index = self
+            .comment_indexes
+            .get(&entity.channel_id)
+            .and_then(|indexes| {
+                indexes
+                    .iter()
+                    .find(|index| self.table.comments[**index].id == entity.id)
+            })
+            .cloned();
+        match index {
+            Some(index) => Ok(std::mem::replace(&mut self.table.comments[index], entity)),
+            None => failure::bail!("comment not found"),
+        }
+    }
+
+    /// Returns a copy without the comment of the `id`.
+    pub fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Self {
+        self.filter_comments(|data| data.channel_id != *channel_id || data.id != *id)
+    }
+
+    /// Returns a copy without the comments of the channel whose `sequence` is less than or equal
+    /// to the `sequence`.
+    pub fn remove_comments_until(&self, channel_id: &ChannelID, sequence: u64) -> Self {
+        self.filter_comments(|data| data.channel_id != *channel_id || sequence < data.sequence)
+    }
+
+    /// Removes the last channel to roll back the `push_channel`.
+    pub fn pop_channel(&mut self) -> Option<ChannelEntity> {
+        let entity = self.table.channels.pop()?;
+        self.channel_indexes.remove(&entity.id);
+        Some(entity)
+    }
+
+    /// Removes the last comment to roll back the `push_comment`.
+    pub fn pop_comment(&mut self) -> Option<CommentEntity> {
+        let entity = self.table.comments.pop()?;
+        if let Some(indexes) = self.comment_indexes.get_mut(&entity.channel_id) {
+            indexes.pop();
+        }
+        if let Some(channel_index) = self.channel_indexes.get(&entity.channel_id) {
+            self.table.channels[*channel_index].last_sequence = entity.sequence.saturating_sub(1);
+        }
+        Some(entity)
+    }
+
+    fn filter_comments<F: FnMut(&&CommentEntity) -> bool>(&self, f: F) -> Self {
+        Self::new(DevFlexChatTable {
+            version_code: self.table.version_code,
+            comments: self.table.comments.iter().filter(f).cloned().collect(),
+            channels: self.table.channels.clone(),
+            migration_history: self.table.migration_history.clone(),
+        })
+    }
+
+    fn push_comment_unchecked(&mut self, entity: CommentEntity) {
+        self.comment_indexes
+            .entry(entity.channel_id.clone())
+            .or_default()
+            .push(self.table.comments.len());
+        self.table.comments.push(entity);
+    }
+}
@@ -0,0 +1,150 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::model::version::Version;
+use crate::prelude::*;
+
+/// Storage backend of the `DevFlexChatRepository`.
+///
+/// The required methods are the primitives of a backend. The provided methods are built on top of
+/// them and can be overridden when a backend can answer them more efficiently.
+pub trait ChatStore: Send + Sync {
+    fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>>;
+
+    fn database_version(&self) -> Fallible<(Version, u16)>;
+
+    fn find_channel(&self, channel_id: &ChannelID) -> Fallible<Option<ChannelEntity>>;
+
+    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>>;
+
+    fn save_channel(&self, entity: ChannelEntity) -> Fallible<()>;
+
+    /// Saves the `entity` with the next `sequence` of the channel, and returns the saved one.
+    fn save_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity>;
+
+    /// Saves the `entities` with their `sequence` at once. The `sequence` must be ascending in the
+    /// channel and greater than those of the saved comments of the channel, and the
+    /// `last_sequence` of the channel is raised to it. Nothing is saved if one of them fails.
+    fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<()>;
+
+    /// Replaces the `name` and the `updated_at` of the channel of the `entity`, and returns the
+    /// updated one.
+    fn update_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity>;
+
+    /// Replaces the `message` and the `updated_at` of the comment of the `entity`, and returns the
+    /// updated one.
+    fn update_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity>;
+
+    /// Removes the comment of the `id` and returns it. The `last_sequence` of the channel is not
+    /// changed.
+    fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Fallible<CommentEntity>;
+
+    /// Removes the comments of the channel whose `sequence` is less than or equal to the
+    /// `sequence` at once, and returns the removed ones in the `sequence` order. The
+    /// `last_sequence` of the channel is not changed.
+    fn take_comments_until(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+    ) -> Fallible<Vec<CommentEntity>>;
+
+    /// Saves the `entities` in order. The backends that write the whole database override this to
+    /// write it once.
+    fn save_channels(&self, entities: Vec<ChannelEntity>) -> Fallible<()> {
+        for entity in entities {
+            self.save_channel(entity)?;
+        }
+        Ok(())
+    }
+
+    fn channels_after_created_asc(&self, channel_id: &ChannelID) -> Fallible<Vec<ChannelEntity>> {
+        let channels = self.channels_created_asc()?;
+        for (index, channel) in channels.iter().enumerate() {
+            if &channel.id == channel_id {
+                return Ok(channels[index + 1..].to_vec());
+            }
+        }
+
+        failure::bail!("id not found: {:?}", channel_id)
+    }
+
+    fn find_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+    ) -> Fallible<Option<CommentEntity>> {
+        Ok(self
+            .retrieve_comments(channel_id)?
+            .into_iter()
+            .find(|data| data.id == *id))
+    }
+
+    /// Returns at most `count` comments whose `sequence` is greater than the `sequence`.
+    fn retrieve_after_sequence_asc(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut comments = self
+            .retrieve_comments(channel_id)?
+            .into_iter()
+            .filter(|data| sequence < data.sequence)
+            .collect::<Vec<_>>();
+        if let Some(count) = count {
+            comments.truncate(count as usize);
+        }
+        Ok(comments)
+    }
+
+    fn retrieve_after_sequence_desc(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut comments = self.retrieve_after_sequence_asc(channel_id, sequence, count)?;
+        comments.reverse();
+        Ok(comments)
+    }
+
+    fn retrieve_first_created_at_asc(
+        &self,
+        channel_id: &ChannelID,
+        count: u32,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let comments = self.retrieve_comments(channel_id)?;
+        let range_start = if comments.len() < count as usize {
+            0
+        } else {
+            comments.len() - count as usize
+        };
+        Ok(comments[range_start..comments.len()].to_vec())
+    }
+
+    fn retrieve_first_created_at_desc(
+        &self,
+        channel_id: &ChannelID,
+        count: u32,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut comments = self.retrieve_first_created_at_asc(channel_id, count)?;
+        comments.reverse();
+        Ok(comments)
+    }
+}
@@ -0,0 +1,172 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::info;
+
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelID, CommentEntity};
+use crate::prelude::*;
+
+/// Compressed files of the comments that are removed from the `ChatStore` by the retention.
+///
+/// The comments of a channel are written to `<archive_dir>/<channel-id>/<first>-<last>.jsonl.gz`
+/// with the range of the `sequence`. The files may overlap if an archival is interrupted before
+/// the removal or a comment is edited during it, so that the latest of the duplicates is kept at
+/// reading. The compressed data is encrypted if the `cipher` is given.
+pub struct CommentArchive {
+    archive_dir: PathBuf,
+    cipher: Option<DatabaseCipher>,
+}
+
+impl CommentArchive {
+    pub fn new<T: Into<PathBuf>>(archive_dir: T, cipher: Option<DatabaseCipher>) -> Self {
+        Self {
+            archive_dir: archive_dir.into(),
+            cipher,
+        }
+    }
+
+    /// Writes the `comments` of the channel to a new file.
+    pub fn append(&self, channel_id: &ChannelID, comments: &[CommentEntity]) -> Fallible<()> {
+        let (first, last) = match (comments.first(), comments.last()) {
+            (Some(first), Some(last)) => (first.sequence, last.sequence),
+            _ => return Ok(()),
+        };
+
+        let channel_dir = self.archive_dir.join(channel_id.0.to_string());
+        std::fs::create_dir_all(&channel_dir)?;
+
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        for comment in comments {
+            serde_json::to_writer(&mut encoder, comment)?;
+            encoder.write_all(b"\n")?;
+        }
+
+        let file_path = channel_dir.join(format!("{}-{}.jsonl.gz", first, last));
+        info!("archive {} comments to {:?}", comments.len(), file_path);
+        database_cipher::write_file_atomically(file_path, &encoder.finish()?, self.cipher.as_ref())
+    }
+
+    /// Returns the archived comments of the channel in the `sequence` order.
+    pub fn retrieve(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        let channel_dir = self.archive_dir.join(channel_id.0.to_string());
+        if !channel_dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut comments = vec![];
+        for file_path in list_files(&channel_dir)? {
+            let data = database_cipher::read_file(&file_path, self.cipher.as_ref())?;
+            let reader = BufReader::new(GzDecoder::new(data.as_slice()));
+            for line in reader.lines() {
+                let comment = serde_json::from_str::<CommentEntity>(&line?)?;
+                comments.push(comment);
+            }
+        }
+
+        comments.sort_by(|a, b| {
+            a.sequence
+                .cmp(&b.sequence)
+                .then_with(|| b.updated_at.cmp(&a.updated_at))
+        });
+        comments.dedup_by_key(|data| data.sequence);
+        Ok(comments)
+    }
+}
+
+/// Returns the archive files of all channels in the `archive_dir`.
+pub fn list_all_files(archive_dir: &Path) -> Fallible<Vec<PathBuf>> {
+    if !archive_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(archive_dir)? {
+        let channel_dir = entry?.path();
+        if channel_dir.is_dir() {
+            files.extend(list_files(&channel_dir)?);
+        }
+    }
+    Ok(files)
+}
+
+fn list_files(channel_dir: &Path) -> Fallible<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(channel_dir)? {
+        let file_path = entry?.path();
+        if file_path.to_string_lossy().ends_with(".jsonl.gz") {
+            files.push(file_path);
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::CommentID;
+
+    use super::*;
+
+    #[test]
+    fn test_retrieve_overlapped() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let archive = CommentArchive::new(dir.path(), None);
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let comments = (1..=3)
+            .map(|sequence| CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: sequence.to_string(),
+                sequence,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })
+            .collect::<Vec<_>>();
+
+        archive.append(&channel_id, &comments[..2])?;
+        archive.append(&channel_id, &comments)?;
+        archive.append(
+            &channel_id,
+            &[CommentEntity {
+                message: "edited".into(),
+                updated_at: Utc::now() + chrono::Duration::seconds(1),
+                ..comments[0].clone()
+            }],
+        )?;
+
+        let actual = archive
+            .retrieve(&channel_id)?
+            .into_iter()
+            .map(|data| data.sequence)
+            .collect::<Vec<_>>();
+        assert_eq!(actual, vec![1, 2, 3]);
+        assert_eq!(archive.retrieve(&channel_id)?[0].message, "edited");
+        assert!(archive
+            .retrieve(&ChannelID(uuid::Uuid::new_v4()))?
+            .is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::prelude::*;
+use std::path::Path;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+use crate::prelude::*;
+
+/// Environment variable of the base64 key.
+pub const KEY_ENV: &str = "CHAT_DATABASE_KEY";
+
+/// Environment variable of the base64 key to rekey to.
+pub const NEW_KEY_ENV: &str = "CHAT_DATABASE_NEW_KEY";
+
+/// Prefix of the encrypted data.
+const MAGIC: &[u8] = b"CHATENC1";
+
+const KEY_LEN: usize = 32;
+
+const NONCE_LEN: usize = 24;
+
+/// At-rest encryption of the database files with XChaCha20-Poly1305.
+///
+/// An encrypted file is `CHATENC1`, a random nonce and the ciphertext with the tag. An encrypted
+/// line of the log is the base64 of the same. The key is 32 bytes in base64, e.g. the output of
+/// `openssl rand -base64 32`.
+#[derive(Clone)]
+pub struct DatabaseCipher {
+    key: [u8; KEY_LEN],
+}
+
+impl DatabaseCipher {
+    pub fn new(key: &[u8]) -> Fallible<Self> {
+        if key.len() != KEY_LEN {
+            failure::bail!("key must be {} bytes: {}", KEY_LEN, key.len());
+        }
+
+        let mut data = [0; KEY_LEN];
+        data.copy_from_slice(key);
+        Ok(Self { key: data })
+    }
+
+    /// Reads the key from the `key_file`, or the `key_env` if the `key_file` is `None`. Returns
+    /// `None` if neither is given.
+    pub fn load(key_file: Option<&Path>, key_env: &str) -> Fallible<Option<Self>> {
+        let encoded_key = match key_file {
+            Some(key_file) => {
+                let mut data = String::new();
+                std::fs::File::open(key_file)?.read_to_string(&mut data)?;
+                data
+            }
+            None => match std::env::var(key_env) {
+                Ok(data) => data,
+                Err(std::env::VarError::NotPresent) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            },
+        };
+
+        Ok(Some(Self::new(&base64::decode(encoded_key.trim())?)?))
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Fallible<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+        let ciphertext = XChaCha20Poly1305::new(&Key::from(self.key))
+            .encrypt(&XNonce::from(nonce), data)
+            .map_err(|_| failure::format_err!("failed to encrypt"))?;
+
+        let mut ret = Vec::with_capacity(MAGIC.len() + NONCE_LEN + ciphertext.len());
+        ret.extend_from_slice(MAGIC);
+        ret.extend_from_slice(&nonce);
+        ret.extend_from_slice(&ciphertext);
+        Ok(ret)
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Fallible<Vec<u8>> {
+        if !is_encrypted(data) {
+            failure::bail!("data is not encrypted");
+        }
+
+        let (nonce_slice, ciphertext) = data[MAGIC.len()..].split_at(NONCE_LEN);
+        let mut nonce = [0; NONCE_LEN];
+        nonce.copy_from_slice(nonce_slice);
+        XChaCha20Poly1305::new(&Key::from(self.key))
+            .decrypt(&XNonce::from(nonce), ciphertext)
+            .map_err(|_| {
+                failure::format_err!("failed to decrypt. the key is wrong or the data is broken")
+            })
+    }
+}
+
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC) && MAGIC.len() + NONCE_LEN <= data.len()
+}
+
+/// Reads the file, and decrypts it with the `cipher`.
+///
+/// Fails if the file is not encrypted with the `cipher`, or if the file is encrypted but the
+/// `cipher` is `None`.
+pub fn read_file(file_path: &Path, cipher: Option<&DatabaseCipher>) -> Fallible<Vec<u8>> {
+    let data = std::fs::read(file_path)?;
+    decode(&data, cipher).map_err(|e| failure::format_err!("{:?}: {}", file_path, e))
+}
+
+pub fn read_file_to_string(file_path: &Path, cipher: Option<&DatabaseCipher>) -> Fallible<String> {
+    Ok(String::from_utf8(read_file(file_path, cipher)?)?)
+}
+
+/// Encrypts the `data` with the `cipher`, and writes it by the `util::write_file_atomically`.
+pub fn write_file_atomically<T: AsRef<Path>>(
+    file_path: T,
+    data: &[u8],
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    match cipher {
+        Some(cipher) => crate::util::write_file_atomically(file_path, &cipher.encrypt(data)?),
+        None => crate::util::write_file_atomically(file_path, data),
+    }
+}
+
+/// Returns a line of JSON Lines file such as the log. The newline is not included.
+pub fn encode_line(line: &[u8], cipher: Option<&DatabaseCipher>) -> Fallible<Vec<u8>> {
+    match cipher {
+        Some(cipher) => Ok(base64::encode(&cipher.encrypt(line)?).into_bytes()),
+        None => Ok(line.to_vec()),
+    }
+}
+
+/// Decodes a line that is written by the `encode_line`.
+pub fn decode_line(line: &str, cipher: Option<&DatabaseCipher>) -> Fallible<String> {
+    // a plaintext line is a JSON object.
+    let data = if line.starts_with('{') {
+        line.as_bytes().to_vec()
+    } else {
+        base64::decode(line)?
+    };
+    Ok(String::from_utf8(decode(&data, cipher)?)?)
+}
+
+fn decode(data: &[u8], cipher: Option<&DatabaseCipher>) -> Fallible<Vec<u8>> {
+    match (cipher, is_encrypted(data)) {
+        (Some(cipher), true) => cipher.decrypt(data),
+        (Some(_), false) => failure::bail!("data is not encrypted"),
+        (None, true) => failure::bail!(
+            "data is encrypted. set the key by {} or the key file",
+            KEY_ENV
+        ),
+        (None, false) => Ok(data.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generate_cipher() -> Fallible<DatabaseCipher> {
+        let mut key = [0; KEY_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut key);
+        DatabaseCipher::new(&key)
+    }
+
+    #[test]
+    fn test_line() -> Fallible<()> {
+        let cipher = generate_cipher()?;
+        let line = encode_line(b"{\"a\":1}", Some(&cipher))?;
+        let line = String::from_utf8(line)?;
+        assert!(!line.contains('\n'));
+        assert_eq!(decode_line(&line, Some(&cipher))?, "{\"a\":1}");
+        assert!(decode_line(&line, None).is_err());
+        assert!(decode_line(&line, Some(&generate_cipher()?)).is_err());
+        assert!(decode_line("{\"a\":1}", Some(&cipher)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tampered() -> Fallible<()> {
+        let cipher = generate_cipher()?;
+        let mut data = cipher.encrypt(b"message")?;
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(cipher.decrypt(&data).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,127 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use fs2::FileExt;
+use log::info;
+
+use crate::prelude::*;
+
+/// Advisory lock of the database directory.
+///
+/// The `database.lock` file is locked exclusively while this is alive, and has the PID of the
+/// holder for the error message of the others.
+pub struct DatabaseLock {
+    file: File,
+}
+
+impl DatabaseLock {
+    /// Takes the lock, or fails if another process holds it.
+    pub fn try_acquire(database_dir: Option<PathBuf>) -> Fallible<Self> {
+        let lock_path = crate::util::get_database_lock_file_path(database_dir);
+        let file = open_lock_file(&lock_path)?;
+        if file.try_lock_exclusive().is_err() {
+            failure::bail!(
+                "database is locked by pid {}: {:?}",
+                read_pid(&lock_path),
+                lock_path
+            );
+        }
+
+        Self::hold(file)
+    }
+
+    /// Takes the lock, waiting for another process to release it.
+    pub fn acquire(database_dir: Option<PathBuf>) -> Fallible<Self> {
+        let lock_path = crate::util::get_database_lock_file_path(database_dir);
+        let file = open_lock_file(&lock_path)?;
+        if file.try_lock_exclusive().is_err() {
+            info!(
+                "wait for the lock held by pid {}: {:?}",
+                read_pid(&lock_path),
+                lock_path
+            );
+            file.lock_exclusive()?;
+        }
+
+        Self::hold(file)
+    }
+
+    fn hold(mut file: File) -> Fallible<Self> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Self { file })
+    }
+}
+
+impl Drop for DatabaseLock {
+    fn drop(&mut self) {
+        self.file.set_len(0).ok();
+        self.file.unlock().ok();
+    }
+}
+
+fn open_lock_file(lock_path: &Path) -> Fallible<File> {
+    if let Some(parent) = lock_path.parent() {
+        if parent != Path::new("") {
+            std::fs::create_dir_all(parent)?;
+        }
+    }
+
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(lock_path)?)
+}
+
+fn read_pid(lock_path: &Path) -> String {
+    match std::fs::read_to_string(lock_path) {
+        Ok(ref data) if !data.trim().is_empty() => data.trim().to_owned(),
+        _ => "unknown".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_try_acquire() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+
+        let lock = DatabaseLock::try_acquire(database_dir.clone())?;
+        let e = match DatabaseLock::try_acquire(database_dir.clone()) {
+            Ok(_) => failure::bail!("unexpected lock"),
+            Err(e) => e,
+        };
+        assert!(e
+            .to_string()
+            .contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        DatabaseLock::try_acquire(database_dir)?;
+        Ok(())
+    }
+}
@@ -14,305 +14,309 @@
  * limitations under the License.
  */
 
-use std::collections::HashMap;
-use std::io::prelude::*;
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 use log::info;
 use serde_derive::{Deserialize, Serialize};
 
+use crate::data::db::cached_chat_table::CachedChatTable;
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::database_cipher::{self, DatabaseCipher};
 use crate::data::db::entity::dev_flex_chat_entity::{
-    ChannelEntity, ChannelID, CommentEntity, CommentID,
+    ChannelEntity, ChannelID, CommentEntity, CommentID, MigrationHistoryEntity,
 };
-use crate::model::juniper_object::OrderDirection;
 use crate::model::version::Version;
 use crate::prelude::*;
 
 // for force update.
 const FLUSH_CODE: u16 = 0;
 
+/// `ChatStore` of `database.toml`.
+///
+/// The table is loaded at the creation, and the mutations are written through to the file. The
+/// file is encrypted if the `cipher` is given.
 pub struct DevFlexChatDatabase {
     database_path: PathBuf,
-    channel_senders: Mutex<Vec<Sender<ChannelEntity>>>,
-    comment_senders: Mutex<HashMap<ChannelID, Vec<Sender<CommentEntity>>>>,
+    cipher: Option<DatabaseCipher>,
+    table: RwLock<CachedChatTable>,
 }
 
 #[derive(Deserialize, Serialize)]
-struct DevFlexChatTable {
+pub struct DevFlexChatTable {
     #[serde(rename = "version-code")]
-    version_code: u64,
-    comments: Vec<CommentEntity>,
-    channels: Vec<ChannelEntity>,
+    pub version_code: u64,
+    pub comments: Vec<CommentEntity>,
+    pub channels: Vec<ChannelEntity>,
+
+    /// Written by the migration. Old databases do not have it, and an empty one is not written
+    /// because an array after the tables cannot be serialized.
+    #[serde(
+        default,
+        rename = "migration-history",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub migration_history: Vec<MigrationHistoryEntity>,
 }
 
 impl DevFlexChatTable {
-    fn new() -> Fallible<Self> {
+    pub fn new() -> Fallible<Self> {
         let (version, flush_code) = generate_latest_database_version()?;
         Ok(Self {
             version_code: convert_to_version_code(&version, flush_code),
             comments: Default::default(),
             channels: Default::default(),
+            migration_history: Default::default(),
         })
     }
 }
 
 impl DevFlexChatDatabase {
-    pub fn create<T: Into<PathBuf>>(database_path: T) -> Fallible<Self> {
-        let db = Self {
-            database_path: database_path.into(),
-            channel_senders: Default::default(),
-            comment_senders: Default::default(),
-        };
-
-        if !db.database_path.exists() {
-            info!("create dir");
-            let parent = db.database_path.parent().ok_or_err()?;
-            std::fs::create_dir_all(parent)?;
+    pub fn create<T: Into<PathBuf>>(
+        database_path: T,
+        cipher: Option<DatabaseCipher>,
+    ) -> Fallible<Self> {
+        let database_path = database_path.into();
 
-            info!("create file");
-            let file = std::fs::File::create(&db.database_path)?;
-            let mut writer = BufWriter::new(file);
-            let database = DevFlexChatTable::new()?;
-            writer.write_all(&toml::to_vec(&database)?)?;
-        }
-
-        let version = db.database_version()?;
-        let latest_version = generate_latest_database_version()?;
-        if version != latest_version {
-            failure::bail!(
-                "need to update database. current: {:?}, expect: {:?}",
-                version,
-                latest_version
-            );
-        }
+        prepare_database_file(&database_path, cipher.as_ref())?;
+        ensure_latest_database_version(&database_path, cipher.as_ref())?;
 
-        Ok(db)
+        Ok(Self {
+            table: RwLock::new(CachedChatTable::new(read_table(
+                &database_path,
+                cipher.as_ref(),
+            )?)),
+            database_path,
+            cipher,
+        })
     }
 
-    pub fn channel_long_polling(&self) -> Fallible<ChannelEntity> {
-        let (tx, rx) = channel();
-        match self.channel_senders.lock() {
-            Ok(mut senders) => senders.push(tx),
-            Err(e) => failure::bail!("failed to long polling: {:?}", e),
+    fn read(&self) -> Fallible<RwLockReadGuard<'_, CachedChatTable>> {
+        match self.table.read() {
+            Ok(data) => Ok(data),
+            Err(e) => failure::bail!("failed to lock the table: {:?}", e),
         }
-
-        Ok(rx.recv()?)
     }
 
-    pub fn channels_after_created_asc(
-        &self,
-        channel_id: &ChannelID,
-    ) -> Fallible<Vec<ChannelEntity>> {
-        let channels = self.channels_created_asc()?;
-        for (index, channel) in channels.iter().enumerate() {
-            if &channel.id == channel_id {
-                return Ok(channels[index + 1..].to_vec());
-            }
+    fn write(&self) -> Fallible<RwLockWriteGuard<'_, CachedChatTable>> {
+        match self.table.write() {
+            Ok(data) => Ok(data),
+            Err(e) => failure::bail!("failed to lock the table: {:?}", e),
         }
-
-        failure::bail!("id not found: {:?}", channel_id)
     }
+}
 
-    pub fn channels_after_long_polling(
-        &self,
-        channel_id: &ChannelID,
-        _order_direction: &OrderDirection,
-    ) -> Fallible<Vec<ChannelEntity>> {
-        // TODO: use order.
+impl ChatStore for DevFlexChatDatabase {
+    fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>> {
+        Ok(self.read()?.channels().to_vec())
+    }
 
-        let channels = self.channels_after_created_asc(channel_id)?;
-        if !channels.is_empty() {
-            return Ok(channels);
-        }
+    fn database_version(&self) -> Fallible<(Version, u16)> {
+        Ok(convert_to_version(self.read()?.table().version_code))
+    }
 
-        Ok(vec![self.channel_long_polling()?])
+    fn find_channel(&self, channel_id: &ChannelID) -> Fallible<Option<ChannelEntity>> {
+        Ok(self.read()?.find_channel(channel_id).cloned())
     }
 
-    pub fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>> {
-        Ok(self.retrieve()?.channels)
+    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        Ok(self.read()?.comments(channel_id))
     }
 
-    pub fn database_version(&self) -> Fallible<(Version, u16)> {
-        let file = std::fs::File::open(&self.database_path)?;
-        let mut reader = BufReader::new(file);
-        let mut file_string = String::new();
-        reader.read_to_string(&mut file_string)?;
-        match toml::from_str::<toml::Value>(&file_string)?.get("version-code") {
-            Some(data) => Ok(convert_to_version(data.as_integer().ok_or_err()? as u64)),
-            None => failure::bail!("failed to retrieve version-code"),
+    fn save_channel(&self, entity: ChannelEntity) -> Fallible<()> {
+        {
+            let mut table = self.write()?;
+            table.push_channel(entity);
+            if let Err(e) = write_table(&self.database_path, table.table(), self.cipher.as_ref()) {
+                table.pop_channel();
+                return Err(e);
+            }
         }
+
+        Ok(())
     }
 
-    pub fn find_channel<T: AsRef<ChannelID>>(
-        &self,
-        channel_id: T,
-    ) -> Fallible<Option<ChannelEntity>> {
-        for channel in self.retrieve()?.channels {
-            if channel.id == *channel_id.as_ref() {
-                return Ok(Some(channel));
+    fn save_channels(&self, entities: Vec<ChannelEntity>) -> Fallible<()> {
+        let mut table = self.write()?;
+        let count = entities.len();
+        for entity in entities {
+            table.push_channel(entity);
+        }
+        if let Err(e) = write_table(&self.database_path, table.table(), self.cipher.as_ref()) {
+            for _ in 0..count {
+                table.pop_channel();
             }
+            return Err(e);
         }
-        Ok(None)
+        Ok(())
     }
 
-    pub fn save_channel<T: Into<ChannelEntity>>(&self, entity: T) -> Fallible<()> {
-        let entity = entity.into();
-        let mut table = self.retrieve()?;
-        table.channels.push(entity.clone());
-        let file = std::fs::File::create(&self.database_path)?;
-        let mut writer = BufWriter::new(file);
-        writer.write_all(&toml::to_vec(&table)?)?;
-
-        match self.channel_senders.lock() {
-            Ok(mut senders) => {
-                for tx in senders.iter() {
-                    tx.send(entity.clone())?;
-                }
-                senders.clear();
+    fn save_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let entity = {
+            let mut table = self.write()?;
+            let entity = table.assign_sequence(entity)?;
+            table.push_comment(entity.clone())?;
+            if let Err(e) = write_table(&self.database_path, table.table(), self.cipher.as_ref()) {
+                table.pop_comment();
+                return Err(e);
             }
-            Err(e) => failure::bail!("failed to send entity: {:?}", e),
-        }
+            entity
+        };
 
-        Ok(())
+        Ok(entity)
     }
 
-    pub fn save_comment<T: Into<CommentEntity>>(&self, entity: T) -> Fallible<()> {
-        let entity = entity.into();
+    fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<()> {
+        let mut table = self.write()?;
+        let new_table = table.insert_comments(entities)?;
+        write_table(&self.database_path, new_table.table(), self.cipher.as_ref())?;
+        *table = new_table;
+        Ok(())
+    }
 
-        if self.find_channel(&entity.channel_id)?.is_none() {
-            failure::bail!("channel not found")
+    fn update_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        let mut table = self.write()?;
+        let entity = table.merge_channel(entity)?;
+        let previous = table.replace_channel(entity.clone())?;
+        if let Err(e) = write_table(&self.database_path, table.table(), self.cipher.as_ref()) {
+            table.replace_channel(previous)?;
+            return Err(e);
         }
+        Ok(entity)
+    }
 
-        let mut table = self.retrieve()?;
-        table.comments.push(entity.clone());
-        let file = std::fs::File::create(&self.database_path)?;
-        let mut writer = BufWriter::new(file);
-        writer.write_all(&toml::to_vec(&table)?)?;
-
-        match self.comment_senders.lock() {
-            Ok(mut senders_map) => {
-                let senders = match senders_map.get_mut(&entity.channel_id) {
-                    Some(data) => data,
-                    None => return Ok(()),
-                };
-
-                for tx in senders.iter() {
-                    tx.send(entity.clone())?;
-                }
-                senders.clear();
-                Ok(())
-            }
-            Err(e) => failure::bail!("failed to send entity: {:?}", e),
+    fn update_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let mut table = self.write()?;
+        let entity = table.merge_comment(entity)?;
+        let previous = table.replace_comment(entity.clone())?;
+        if let Err(e) = write_table(&self.database_path, table.table(), self.cipher.as_ref()) {
+            table.replace_comment(previous)?;
+            return Err(e);
         }
+        Ok(entity)
     }
 
-    pub fn retrieve_after_created_asc(
+    fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Fallible<CommentEntity> {
+        let mut table = self.write()?;
+        let entity = match table.find_comment(channel_id, id) {
+            Some(data) => data.clone(),
+            None => failure::bail!("comment not found"),
+        };
+        let new_table = table.remove_comment(channel_id, id);
+        write_table(&self.database_path, new_table.table(), self.cipher.as_ref())?;
+        *table = new_table;
+        Ok(entity)
+    }
+
+    fn take_comments_until(
         &self,
         channel_id: &ChannelID,
-        id: &CommentID,
+        sequence: u64,
     ) -> Fallible<Vec<CommentEntity>> {
-        let comments = self.retrieve_comments(channel_id)?;
-        for (index, comment) in comments.iter().enumerate() {
-            if &comment.id == id {
-                return Ok(comments[index + 1..comments.len()].to_vec());
-            }
-        }
-
-        failure::bail!("id not found: {:?}", id)
+        let mut table = self.write()?;
+        let entities = table.comments_until(channel_id, sequence);
+        let new_table = table.remove_comments_until(channel_id, sequence);
+        write_table(&self.database_path, new_table.table(), self.cipher.as_ref())?;
+        *table = new_table;
+        Ok(entities)
     }
 
-    pub fn retrieve_after_created_desc(
+    fn find_comment(
         &self,
         channel_id: &ChannelID,
         id: &CommentID,
-    ) -> Fallible<Vec<CommentEntity>> {
-        let mut comments = self.retrieve_after_created_asc(channel_id, id)?;
-        comments.reverse();
-        Ok(comments)
+    ) -> Fallible<Option<CommentEntity>> {
+        Ok(self.read()?.find_comment(channel_id, id).cloned())
     }
 
-    pub fn retrieve_after_long_polling(
+    fn retrieve_after_sequence_asc(
         &self,
         channel_id: &ChannelID,
-        id: &CommentID,
-        order_direction: &OrderDirection,
+        sequence: u64,
+        count: Option<u32>,
     ) -> Fallible<Vec<CommentEntity>> {
-        let ret_retrieve_after = match order_direction {
-            OrderDirection::ASC => self.retrieve_after_created_asc(channel_id, id)?,
-            OrderDirection::DESC => self.retrieve_after_created_desc(channel_id, id)?,
-        };
-
-        if !ret_retrieve_after.is_empty() {
-            return Ok(ret_retrieve_after);
+        let mut comments = self.read()?.comments_after(channel_id, sequence);
+        if let Some(count) = count {
+            comments.truncate(count as usize);
         }
+        Ok(comments)
+    }
+}
 
-        Ok(vec![self.long_polling(channel_id)?])
+/// Creates a `database.toml` of the latest version if it does not exist.
+pub fn prepare_database_file(
+    database_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    if database_path.exists() {
+        return Ok(());
     }
 
-    pub fn long_polling(&self, channel_id: &ChannelID) -> Fallible<CommentEntity> {
-        let (tx, rx) = channel();
-        match self.comment_senders.lock() {
-            Ok(mut senders_map) => {
-                let senders = match senders_map.get_mut(&channel_id) {
-                    Some(data) => data,
-                    None => {
-                        senders_map.insert(channel_id.clone(), vec![]);
-                        senders_map.get_mut(channel_id).ok_or_err()?
-                    }
-                };
-                senders.push(tx);
-            }
-            Err(e) => failure::bail!("failed to long polling: {:?}", e),
-        }
+    info!("create dir");
+    let parent = database_path.parent().ok_or_err()?;
+    std::fs::create_dir_all(parent)?;
 
-        Ok(rx.recv()?)
-    }
+    info!("create file");
+    write_table(database_path, &DevFlexChatTable::new()?, cipher)
+}
 
-    pub fn retrieve_first_created_at_asc(
-        &self,
-        channel_id: &ChannelID,
-        count: u32,
-    ) -> Fallible<Vec<CommentEntity>> {
-        let comments = self.retrieve_comments(channel_id)?;
-        let range_start = if comments.len() < count as usize {
-            0
-        } else {
-            comments.len() - count as usize
-        };
-        Ok(comments[range_start..comments.len()].to_vec())
+pub fn read_database_version(
+    database_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<(Version, u16)> {
+    let file_string = database_cipher::read_file_to_string(database_path, cipher)?;
+    match toml::from_str::<toml::Value>(&file_string)?.get("version-code") {
+        Some(data) => Ok(convert_to_version(data.as_integer().ok_or_err()? as u64)),
+        None => failure::bail!("failed to retrieve version-code"),
     }
+}
 
-    pub fn retrieve_first_created_at_desc(
-        &self,
-        channel_id: &ChannelID,
-        count: u32,
-    ) -> Fallible<Vec<CommentEntity>> {
-        let mut comments = self.retrieve_first_created_at_asc(channel_id, count)?;
-        comments.reverse();
-        Ok(comments)
+pub fn ensure_latest_database_version(
+    database_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let version = read_database_version(database_path, cipher)?;
+    let latest_version = generate_latest_database_version()?;
+    if version != latest_version {
+        failure::bail!(
+            "need to update database. current: {:?}, expect: {:?}",
+            version,
+            latest_version
+        );
     }
 
-    fn retrieve(&self) -> Fallible<DevFlexChatTable> {
-        let file = std::fs::File::open(&self.database_path)?;
-        let mut reader = BufReader::new(file);
-        let mut file_string = String::new();
-        reader.read_to_string(&mut file_string)?;
-        Ok(toml::from_str(&file_string)?)
-    }
+    Ok(())
+}
 
-    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
-        Ok(self
-            .retrieve()?
-            .comments
-            .into_iter()
-            .filter(|data| data.channel_id == *channel_id)
-            .collect())
+pub fn read_table(
+    database_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<DevFlexChatTable> {
+    let file_string = database_cipher::read_file_to_string(database_path, cipher)?;
+    match toml::from_str(&file_string) {
+        Ok(data) => Ok(data),
+        Err(e) => failure::bail!(
+            "malformed database: {:?}, {}. the check subcommand shows the details",
+            database_path,
+            e
+        ),
     }
 }
 
+pub fn write_table(
+    database_path: &Path,
+    table: &DevFlexChatTable,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    database_cipher::write_file_atomically(database_path, &toml::to_vec(table)?, cipher)
+}
+
+/// Returns the version-code of the database.
+///
+/// The pre-release and the build metadata are not encoded, so that a pre-release shares the
+/// version-code of its release, e.g. `0.6.0-beta.1` and `0.6.0` are `0.6.0`. The schema of a
+/// release must be fixed at its first pre-release, or the `FLUSH_CODE` must be bumped to migrate
+/// the databases of the previous pre-releases.
 pub fn convert_to_version_code(version: &Version, flush_code: u16) -> u64 {
     // flush:                                                 1111111111111111
     // patch:                                 11111111111111110000000000000000
@@ -341,14 +345,64 @@ pub fn convert_to_version(version_code: u64) -> (Version, u16) {
     ([major, minor, patch].into(), flush_code)
 }
 
+/// Returns the database version of the app. The pre-release and the build metadata of the
+/// `CARGO_PKG_VERSION` are removed as the `convert_to_version_code`.
 pub fn generate_latest_database_version() -> Fallible<(Version, u16)> {
-    Ok((env!("CARGO_PKG_VERSION").parse()?, FLUSH_CODE))
+    Ok((
+        Version::parse(env!("CARGO_PKG_VERSION"))?.to_release(),
+        FLUSH_CODE,
+    ))
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::Utc;
+
     use super::*;
 
+    #[test]
+    fn test_write_through() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_path = dir.path().join("database.toml");
+        let db = DevFlexChatDatabase::create(&database_path, None)?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        db.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        db.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "message".into(),
+            sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        assert!(db
+            .save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: ChannelID(uuid::Uuid::new_v4()),
+                name: "name".into(),
+                message: "message".into(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })
+            .is_err());
+
+        let table = read_table(&database_path, None)?;
+        assert_eq!(table.channels.len(), 1);
+        assert_eq!(table.comments.len(), 1);
+
+        let db = DevFlexChatDatabase::create(&database_path, None)?;
+        assert_eq!(db.retrieve_comments(&channel_id)?[0].message, "message");
+        Ok(())
+    }
+
     #[test]
     fn test_convert_to_version_code_max() {
         assert_eq!(
@@ -14,10 +14,11 @@
  * limitations under the License.
  */
 
+use chrono::{DateTime, Utc};
 use serde_derive::{Deserialize, Serialize};
 use uuid::Uuid;
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub struct CommentID(pub uuid::Uuid);
 
 #[derive(Clone, Deserialize, Serialize)]
@@ -29,6 +30,15 @@ pub struct CommentEntity {
 
     pub name: String,
     pub message: String,
+
+    /// Monotonically increasing number in the channel. Assigned by the `ChatStore` at saving.
+    pub sequence: u64,
+
+    #[serde(rename = "created-at")]
+    pub created_at: DateTime<Utc>,
+
+    #[serde(rename = "updated-at")]
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
@@ -50,4 +60,27 @@ impl AsRef<ChannelID> for ChannelID {
 pub struct ChannelEntity {
     pub id: ChannelID,
     pub name: String,
+
+    /// `sequence` of the last comment of the channel.
+    #[serde(rename = "last-sequence")]
+    pub last_sequence: u64,
+
+    #[serde(rename = "created-at")]
+    pub created_at: DateTime<Utc>,
+
+    #[serde(rename = "updated-at")]
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A migration of the database by the `chat migration`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MigrationHistoryEntity {
+    #[serde(rename = "from-version-code")]
+    pub from_version_code: u64,
+
+    #[serde(rename = "to-version-code")]
+    pub to_version_code: u64,
+
+    #[serde(rename = "migrated-at")]
+    pub migrated_at: DateTime<Utc>,
 }
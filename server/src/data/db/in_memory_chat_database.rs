@@ -0,0 +1,153 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::{Mutex, MutexGuard};
+
+use crate::data::db::cached_chat_table::CachedChatTable;
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::dev_flex_chat_database::{convert_to_version, DevFlexChatTable};
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::model::version::Version;
+use crate::prelude::*;
+
+/// Volatile `ChatStore` for tests and throwaway servers.
+pub struct InMemoryChatDatabase {
+    table: Mutex<CachedChatTable>,
+}
+
+impl InMemoryChatDatabase {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the database of the entities of the `table`, e.g. to read a snapshot.
+    pub fn with_table(table: DevFlexChatTable) -> Self {
+        Self {
+            table: Mutex::new(CachedChatTable::new(table)),
+        }
+    }
+
+    fn lock(&self) -> Fallible<MutexGuard<'_, CachedChatTable>> {
+        match self.table.lock() {
+            Ok(data) => Ok(data),
+            Err(e) => failure::bail!("failed to lock the table: {:?}", e),
+        }
+    }
+}
+
+impl Default for InMemoryChatDatabase {
+    fn default() -> Self {
+        Self::with_table(DevFlexChatTable::new().expect("failed to parse the package version"))
+    }
+}
+
+impl ChatStore for InMemoryChatDatabase {
+    fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>> {
+        Ok(self.lock()?.channels().to_vec())
+    }
+
+    fn database_version(&self) -> Fallible<(Version, u16)> {
+        Ok(convert_to_version(self.lock()?.table().version_code))
+    }
+
+    fn find_channel(&self, channel_id: &ChannelID) -> Fallible<Option<ChannelEntity>> {
+        Ok(self.lock()?.find_channel(channel_id).cloned())
+    }
+
+    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        Ok(self.lock()?.comments(channel_id))
+    }
+
+    fn save_channel(&self, entity: ChannelEntity) -> Fallible<()> {
+        self.lock()?.push_channel(entity);
+        Ok(())
+    }
+
+    fn save_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let entity = {
+            let mut table = self.lock()?;
+            let entity = table.assign_sequence(entity)?;
+            table.push_comment(entity.clone())?;
+            entity
+        };
+
+        Ok(entity)
+    }
+
+    fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<()> {
+        let mut table = self.lock()?;
+        *table = table.insert_comments(entities)?;
+        Ok(())
+    }
+
+    fn update_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        let mut table = self.lock()?;
+        let entity = table.merge_channel(entity)?;
+        table.replace_channel(entity.clone())?;
+        Ok(entity)
+    }
+
+    fn update_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let mut table = self.lock()?;
+        let entity = table.merge_comment(entity)?;
+        table.replace_comment(entity.clone())?;
+        Ok(entity)
+    }
+
+    fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Fallible<CommentEntity> {
+        let mut table = self.lock()?;
+        let entity = match table.find_comment(channel_id, id) {
+            Some(data) => data.clone(),
+            None => failure::bail!("comment not found"),
+        };
+        *table = table.remove_comment(channel_id, id);
+        Ok(entity)
+    }
+
+    fn take_comments_until(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut table = self.lock()?;
+        let entities = table.comments_until(channel_id, sequence);
+        *table = table.remove_comments_until(channel_id, sequence);
+        Ok(entities)
+    }
+
+    fn find_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+    ) -> Fallible<Option<CommentEntity>> {
+        Ok(self.lock()?.find_comment(channel_id, id).cloned())
+    }
+
+    fn retrieve_after_sequence_asc(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut comments = self.lock()?.comments_after(channel_id, sequence);
+        if let Some(count) = count {
+            comments.truncate(count as usize);
+        }
+        Ok(comments)
+    }
+}
@@ -0,0 +1,836 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::dev_flex_chat_database::{
+    convert_to_version, convert_to_version_code, generate_latest_database_version, DevFlexChatTable,
+};
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::model::version::Version;
+use crate::prelude::*;
+
+/// `ChatStore` backed by SQLite.
+///
+/// The version-code is stored in the `metadata` table, and the schema is updated by the
+/// `migrate` with the steps of the `schema_migrations`.
+pub struct SqliteChatDatabase {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteChatDatabase {
+    pub fn create<T: Into<PathBuf>>(database_path: T) -> Fallible<Self> {
+        let mut connection = open_connection(&database_path.into())?;
+
+        if retrieve_version_code(&connection)?.is_none() {
+            let transaction = connection.transaction()?;
+            create_tables(&transaction)?;
+            let (version, flush_code) = generate_latest_database_version()?;
+            set_version_code(&transaction, convert_to_version_code(&version, flush_code))?;
+            transaction.commit()?;
+        }
+
+        let db = Self {
+            connection: Mutex::new(connection),
+        };
+
+        let version = db.database_version()?;
+        let latest_version = generate_latest_database_version()?;
+        if version != latest_version {
+            failure::bail!(
+                "need to update database. current: {:?}, expect: {:?}",
+                version,
+                latest_version
+            );
+        }
+
+        Ok(db)
+    }
+
+    fn connection(&self) -> Fallible<MutexGuard<'_, Connection>> {
+        match self.connection.lock() {
+            Ok(data) => Ok(data),
+            Err(e) => failure::bail!("failed to lock the connection: {:?}", e),
+        }
+    }
+
+    fn query_comments<P>(&self, sql: &str, params: P) -> Fallible<Vec<CommentEntity>>
+    where
+        P: IntoIterator,
+        P::Item: rusqlite::ToSql,
+    {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(sql)?;
+        let comments = statement
+            .query_map(params, convert_to_comment)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(comments)
+    }
+}
+
+impl ChatStore for SqliteChatDatabase {
+    fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>> {
+        let connection = self.connection()?;
+        let mut statement = connection.prepare(
+            "SELECT id, name, last_sequence, created_at, updated_at FROM channels ORDER BY row_id",
+        )?;
+        let channels = statement
+            .query_map(params![], convert_to_channel)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(channels)
+    }
+
+    fn database_version(&self) -> Fallible<(Version, u16)> {
+        Ok(convert_to_version(
+            retrieve_version_code(&*self.connection()?)?.ok_or_err()?,
+        ))
+    }
+
+    fn find_channel(&self, channel_id: &ChannelID) -> Fallible<Option<ChannelEntity>> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT id, name, last_sequence, created_at, updated_at FROM channels WHERE id = ?1",
+                params![channel_id.0.to_string()],
+                convert_to_channel,
+            )
+            .optional()?)
+    }
+
+    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        self.query_comments(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments WHERE channel_id = ?1
+             ORDER BY row_id",
+            params![channel_id.0.to_string()],
+        )
+    }
+
+    fn save_channel(&self, entity: ChannelEntity) -> Fallible<()> {
+        insert_channel(&*self.connection()?, &entity)
+    }
+
+    fn save_comment(&self, mut entity: CommentEntity) -> Fallible<CommentEntity> {
+        {
+            let mut connection = self.connection()?;
+            let transaction = connection.transaction()?;
+            let last_sequence = match transaction
+                .query_row(
+                    "SELECT last_sequence FROM channels WHERE id = ?1",
+                    params![entity.channel_id.0.to_string()],
+                    |row| row.get::<_, i64>(0),
+                )
+                .optional()?
+            {
+                Some(data) => data as u64,
+                None => failure::bail!("channel not found"),
+            };
+            entity.sequence = last_sequence + 1;
+
+            insert_comment(&transaction, &entity)?;
+            transaction.execute(
+                "UPDATE channels SET last_sequence = ?2 WHERE id = ?1",
+                params![entity.channel_id.0.to_string(), entity.sequence as i64],
+            )?;
+            transaction.commit()?;
+        }
+
+        Ok(entity)
+    }
+
+    fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<()> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        for entity in &entities {
+            let last_sequence = transaction.query_row(
+                "SELECT MAX(sequence) FROM comments WHERE channel_id = ?1",
+                params![entity.channel_id.0.to_string()],
+                |row| row.get::<_, Option<i64>>(0),
+            )?;
+            if let Some(last_sequence) = last_sequence {
+                if entity.sequence <= last_sequence as u64 {
+                    failure::bail!(
+                        "sequence must be greater than {}: {}",
+                        last_sequence,
+                        entity.sequence
+                    );
+                }
+            }
+
+            let updated_count = transaction.execute(
+                "UPDATE channels SET last_sequence = MAX(last_sequence, ?2) WHERE id = ?1",
+                params![entity.channel_id.0.to_string(), entity.sequence as i64],
+            )?;
+            if updated_count == 0 {
+                failure::bail!("channel not found");
+            }
+            insert_comment(&transaction, entity)?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn update_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        let updated_count = transaction.execute(
+            "UPDATE channels SET name = ?2, updated_at = ?3 WHERE id = ?1",
+            params![
+                entity.id.0.to_string(),
+                entity.name,
+                entity.updated_at.to_rfc3339()
+            ],
+        )?;
+        if updated_count == 0 {
+            failure::bail!("channel not found");
+        }
+        let entity = transaction.query_row(
+            "SELECT id, name, last_sequence, created_at, updated_at FROM channels WHERE id = ?1",
+            params![entity.id.0.to_string()],
+            convert_to_channel,
+        )?;
+        transaction.commit()?;
+        Ok(entity)
+    }
+
+    fn update_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        let updated_count = transaction.execute(
+            "UPDATE comments SET message = ?3, updated_at = ?4 WHERE channel_id = ?1 AND id = ?2",
+            params![
+                entity.channel_id.0.to_string(),
+                entity.id.0.to_string(),
+                entity.message,
+                entity.updated_at.to_rfc3339()
+            ],
+        )?;
+        if updated_count == 0 {
+            failure::bail!("comment not found");
+        }
+        let entity = transaction.query_row(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+             WHERE channel_id = ?1 AND id = ?2",
+            params![entity.channel_id.0.to_string(), entity.id.0.to_string()],
+            convert_to_comment,
+        )?;
+        transaction.commit()?;
+        Ok(entity)
+    }
+
+    fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Fallible<CommentEntity> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        let entity = match transaction
+            .query_row(
+                "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+                 WHERE channel_id = ?1 AND id = ?2",
+                params![channel_id.0.to_string(), id.0.to_string()],
+                convert_to_comment,
+            )
+            .optional()?
+        {
+            Some(data) => data,
+            None => failure::bail!("comment not found"),
+        };
+        transaction.execute(
+            "DELETE FROM comments WHERE channel_id = ?1 AND id = ?2",
+            params![channel_id.0.to_string(), id.0.to_string()],
+        )?;
+        transaction.commit()?;
+        Ok(entity)
+    }
+
+    fn take_comments_until(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut connection = self.connection()?;
+        let transaction = connection.transaction()?;
+        let entities = transaction
+            .prepare(
+                "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+                 WHERE channel_id = ?1 AND sequence <= ?2 ORDER BY sequence ASC",
+            )?
+            .query_map(
+                params![channel_id.0.to_string(), sequence as i64],
+                convert_to_comment,
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        transaction.execute(
+            "DELETE FROM comments WHERE channel_id = ?1 AND sequence <= ?2",
+            params![channel_id.0.to_string(), sequence as i64],
+        )?;
+        transaction.commit()?;
+        Ok(entities)
+    }
+
+    fn channels_after_created_asc(&self, channel_id: &ChannelID) -> Fallible<Vec<ChannelEntity>> {
+        let connection = self.connection()?;
+        let row_id = match connection
+            .query_row(
+                "SELECT row_id FROM channels WHERE id = ?1",
+                params![channel_id.0.to_string()],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?
+        {
+            Some(data) => data,
+            None => failure::bail!("id not found: {:?}", channel_id),
+        };
+
+        let mut statement = connection.prepare(
+            "SELECT id, name, last_sequence, created_at, updated_at FROM channels
+             WHERE ?1 < row_id ORDER BY row_id",
+        )?;
+        let channels = statement
+            .query_map(params![row_id], convert_to_channel)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(channels)
+    }
+
+    fn find_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+    ) -> Fallible<Option<CommentEntity>> {
+        Ok(self
+            .connection()?
+            .query_row(
+                "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+                 WHERE channel_id = ?1 AND id = ?2",
+                params![channel_id.0.to_string(), id.0.to_string()],
+                convert_to_comment,
+            )
+            .optional()?)
+    }
+
+    fn retrieve_after_sequence_asc(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        // negative LIMIT means no limit.
+        let limit = count.map_or(-1, i64::from);
+        self.query_comments(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+             WHERE channel_id = ?1 AND ?2 < sequence ORDER BY sequence ASC LIMIT ?3",
+            params![channel_id.0.to_string(), sequence as i64, limit],
+        )
+    }
+
+    fn retrieve_first_created_at_asc(
+        &self,
+        channel_id: &ChannelID,
+        count: u32,
+    ) -> Fallible<Vec<CommentEntity>> {
+        self.query_comments(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM (
+                 SELECT row_id, id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+                 WHERE channel_id = ?1 ORDER BY row_id DESC LIMIT ?2
+             ) ORDER BY row_id ASC",
+            params![channel_id.0.to_string(), count],
+        )
+    }
+
+    fn retrieve_first_created_at_desc(
+        &self,
+        channel_id: &ChannelID,
+        count: u32,
+    ) -> Fallible<Vec<CommentEntity>> {
+        self.query_comments(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+             WHERE channel_id = ?1 ORDER BY row_id DESC LIMIT ?2",
+            params![channel_id.0.to_string(), count],
+        )
+    }
+}
+
+/// Schema changes of each version-code. The steps newer than the version-code of the database
+/// are applied by the `migrate`.
+fn schema_migrations() -> Vec<(u64, &'static str)> {
+    vec![
+        (
+            convert_to_version_code(&[0, 3, 1].into(), 0),
+            "CREATE TABLE metadata (
+                 key TEXT PRIMARY KEY NOT NULL,
+                 value INTEGER NOT NULL
+             );
+             CREATE TABLE channels (
+                 row_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 id TEXT NOT NULL UNIQUE,
+                 name TEXT NOT NULL
+             );
+             CREATE TABLE comments (
+                 row_id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 id TEXT NOT NULL UNIQUE,
+                 channel_id TEXT NOT NULL REFERENCES channels (id),
+                 name TEXT NOT NULL,
+                 message TEXT NOT NULL
+             );
+             CREATE INDEX comments_channel_id_row_id ON comments (channel_id, row_id);",
+        ),
+        (
+            convert_to_version_code(&[0, 4, 0].into(), 0),
+            "ALTER TABLE channels ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+             ALTER TABLE channels ADD COLUMN updated_at TEXT NOT NULL DEFAULT '';
+             ALTER TABLE comments ADD COLUMN created_at TEXT NOT NULL DEFAULT '';
+             ALTER TABLE comments ADD COLUMN updated_at TEXT NOT NULL DEFAULT '';
+             UPDATE channels SET
+                 created_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+                 updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now');
+             UPDATE comments SET
+                 created_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now'),
+                 updated_at = strftime('%Y-%m-%dT%H:%M:%fZ', 'now');",
+        ),
+        (
+            convert_to_version_code(&[0, 5, 0].into(), 0),
+            "ALTER TABLE channels ADD COLUMN last_sequence INTEGER NOT NULL DEFAULT 0;
+             ALTER TABLE comments ADD COLUMN sequence INTEGER NOT NULL DEFAULT 0;
+             UPDATE comments SET sequence = (
+                 SELECT COUNT(*) FROM comments AS c
+                 WHERE c.channel_id = comments.channel_id AND c.row_id <= comments.row_id
+             );
+             UPDATE channels SET last_sequence = (
+                 SELECT COALESCE(MAX(sequence), 0) FROM comments
+                 WHERE comments.channel_id = channels.id
+             );
+             CREATE UNIQUE INDEX comments_channel_id_sequence ON comments (channel_id, sequence);",
+        ),
+    ]
+}
+
+pub fn migrate(database_path: &Path) -> Fallible<()> {
+    let (current_version, current_version_flush_code) = generate_latest_database_version()?;
+    let current_version_code =
+        convert_to_version_code(&current_version, current_version_flush_code);
+
+    let mut connection = open_connection(database_path)?;
+    let database_version_code = match retrieve_version_code(&connection)? {
+        Some(data) => data,
+        None => failure::bail!("failed to retrieve version-code"),
+    };
+    let (database_version, database_flush_code) = convert_to_version(database_version_code);
+
+    info!(
+        "database version: {}, flush_code: {}",
+        database_version, database_flush_code
+    );
+
+    if current_version_code < database_version_code {
+        failure::bail!("need to upgrade the app to migrate")
+    }
+
+    let transaction = connection.transaction()?;
+    for (target_code, statements) in schema_migrations() {
+        if database_version_code < target_code {
+            let (target_version, target_flush_code) = convert_to_version(target_code);
+            info!(
+                "migrate to v{}, flush_code: {}",
+                target_version, target_flush_code
+            );
+            transaction.execute_batch(statements)?;
+        }
+    }
+    set_version_code(&transaction, current_version_code)?;
+    transaction.commit()?;
+
+    info!("succeeded to set a version to the database");
+    Ok(())
+}
+
+/// Returns the descriptions of the steps of the `migrate`. Empty if the database is not created.
+pub fn pending_migrations(database_path: &Path) -> Fallible<Vec<String>> {
+    if !database_path.exists() {
+        return Ok(vec![]);
+    }
+
+    let (current_version, current_version_flush_code) = generate_latest_database_version()?;
+    let current_version_code =
+        convert_to_version_code(&current_version, current_version_flush_code);
+
+    let connection = open_connection(database_path)?;
+    let database_version_code = match retrieve_version_code(&connection)? {
+        Some(data) => data,
+        None => return Ok(vec![]),
+    };
+    if current_version_code < database_version_code {
+        failure::bail!("need to upgrade the app to migrate")
+    }
+
+    let mut pending = vec![];
+    let mut version_code = database_version_code;
+    for (target_code, _) in schema_migrations() {
+        if database_version_code < target_code {
+            pending.push(format!(
+                "migrate up to v{}",
+                convert_to_version(target_code).0
+            ));
+            version_code = target_code;
+        }
+    }
+    if version_code != current_version_code {
+        pending.push(format!("set version to v{}", current_version));
+    }
+    Ok(pending)
+}
+
+/// Reads all entities in a transaction. The database is not modified.
+pub fn read_table(database_path: &Path) -> Fallible<DevFlexChatTable> {
+    if !database_path.exists() {
+        failure::bail!("database not found: {:?}", database_path);
+    }
+
+    let mut connection = open_connection(database_path)?;
+    let transaction = connection.transaction()?;
+    let version_code = match retrieve_version_code(&transaction)? {
+        Some(data) => data,
+        None => failure::bail!("failed to retrieve version-code"),
+    };
+
+    let channels = transaction
+        .prepare(
+            "SELECT id, name, last_sequence, created_at, updated_at FROM channels ORDER BY row_id",
+        )?
+        .query_map(params![], convert_to_channel)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let comments = transaction
+        .prepare(
+            "SELECT id, channel_id, name, message, sequence, created_at, updated_at FROM comments
+             ORDER BY row_id",
+        )?
+        .query_map(params![], convert_to_comment)?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(DevFlexChatTable {
+        version_code,
+        comments,
+        channels,
+        // the migrations of the sqlite storage are not recorded.
+        migration_history: vec![],
+    })
+}
+
+/// Replaces the database with the `table`.
+///
+/// The new database is built in a temporary file and renamed to the `database_path`, and the
+/// previous one is kept as the backup file.
+pub fn write_table(database_path: &Path, table: &DevFlexChatTable) -> Fallible<()> {
+    let tmp_path = crate::util::get_temporary_file_path(database_path)?;
+    if tmp_path.exists() {
+        std::fs::remove_file(&tmp_path)?;
+    }
+
+    {
+        let mut connection = open_connection(&tmp_path)?;
+        let transaction = connection.transaction()?;
+        create_tables(&transaction)?;
+        for entity in &table.channels {
+            insert_channel(&transaction, entity)?;
+        }
+        for entity in &table.comments {
+            insert_comment(&transaction, entity)?;
+        }
+        set_version_code(&transaction, table.version_code)?;
+        transaction.commit()?;
+    }
+
+    crate::util::replace_file(&tmp_path, database_path)
+}
+
+/// Returns the problems of the database: the version-code, the SQLite integrity, the foreign
+/// keys and the malformed rows.
+pub fn check(database_path: &Path) -> Fallible<Vec<String>> {
+    if !database_path.exists() {
+        failure::bail!("database not found: {:?}", database_path);
+    }
+
+    let connection = open_connection(database_path)?;
+    let mut problems = vec![];
+
+    match retrieve_version_code(&connection)? {
+        Some(version_code) => {
+            let version = convert_to_version(version_code);
+            let latest_version = generate_latest_database_version()?;
+            if version != latest_version {
+                problems.push(format!(
+                    "need to update database. current: {:?}, expect: {:?}",
+                    version, latest_version
+                ));
+                return Ok(problems);
+            }
+        }
+        None => {
+            problems.push("version-code not found".into());
+            return Ok(problems);
+        }
+    }
+
+    let mut statement = connection.prepare("PRAGMA integrity_check")?;
+    for row in statement.query_map(params![], |row| row.get::<_, String>(0))? {
+        let row = row?;
+        if row != "ok" {
+            problems.push(format!("integrity: {}", row));
+        }
+    }
+
+    let mut statement = connection.prepare("PRAGMA foreign_key_check")?;
+    for row in statement.query_map(params![], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?))
+    })? {
+        let (table, row_id) = row?;
+        problems.push(format!("orphaned row {} of {}", row_id, table));
+    }
+
+    let mut statement = connection
+        .prepare("SELECT row_id, id, name, last_sequence, created_at, updated_at FROM channels")?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        if let Err(e) = convert_to_channel_at(row, 1) {
+            problems.push(format!(
+                "malformed channel {}: {}",
+                row.get::<_, i64>(0)?,
+                e
+            ));
+        }
+    }
+
+    let mut statement = connection.prepare(
+        "SELECT row_id, id, channel_id, name, message, sequence, created_at, updated_at
+         FROM comments",
+    )?;
+    let mut rows = statement.query(params![])?;
+    while let Some(row) = rows.next()? {
+        if let Err(e) = convert_to_comment_at(row, 1) {
+            problems.push(format!(
+                "malformed comment {}: {}",
+                row.get::<_, i64>(0)?,
+                e
+            ));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn create_tables(connection: &Connection) -> Fallible<()> {
+    info!("create tables");
+    for (_, statements) in schema_migrations() {
+        connection.execute_batch(statements)?;
+    }
+    Ok(())
+}
+
+fn insert_channel(connection: &Connection, entity: &ChannelEntity) -> Fallible<()> {
+    connection.execute(
+        "INSERT INTO channels (id, name, last_sequence, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            entity.id.0.to_string(),
+            entity.name,
+            entity.last_sequence as i64,
+            entity.created_at.to_rfc3339(),
+            entity.updated_at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+fn insert_comment(connection: &Connection, entity: &CommentEntity) -> Fallible<()> {
+    connection.execute(
+        "INSERT INTO comments (id, channel_id, name, message, sequence, created_at, updated_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            entity.id.0.to_string(),
+            entity.channel_id.0.to_string(),
+            entity.name,
+            entity.message,
+            entity.sequence as i64,
+            entity.created_at.to_rfc3339(),
+            entity.updated_at.to_rfc3339()
+        ],
+    )?;
+    Ok(())
+}
+
+fn open_connection(database_path: &Path) -> Fallible<Connection> {
+    if let Some(parent) = database_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let connection = Connection::open(database_path)?;
+    connection.execute_batch("PRAGMA foreign_keys = ON;")?;
+    Ok(connection)
+}
+
+fn retrieve_version_code(connection: &Connection) -> Fallible<Option<u64>> {
+    let table_count: i64 = connection.query_row(
+        "SELECT COUNT(*) FROM sqlite_master WHERE type = 'table' AND name = 'metadata'",
+        params![],
+        |row| row.get(0),
+    )?;
+    if table_count == 0 {
+        return Ok(None);
+    }
+
+    Ok(connection
+        .query_row(
+            "SELECT value FROM metadata WHERE key = 'version-code'",
+            params![],
+            |row| row.get::<_, i64>(0),
+        )
+        .optional()?
+        .map(|data| data as u64))
+}
+
+fn set_version_code(connection: &Connection, version_code: u64) -> Fallible<()> {
+    info!("set database version-code: {}", version_code);
+    connection.execute(
+        "INSERT OR REPLACE INTO metadata (key, value) VALUES ('version-code', ?1)",
+        params![version_code as i64],
+    )?;
+    Ok(())
+}
+
+fn convert_to_channel(row: &Row) -> rusqlite::Result<ChannelEntity> {
+    convert_to_channel_at(row, 0)
+}
+
+/// Converts the columns from the `offset` to a `ChannelEntity`.
+fn convert_to_channel_at(row: &Row, offset: usize) -> rusqlite::Result<ChannelEntity> {
+    Ok(ChannelEntity {
+        id: ChannelID(get_uuid(row, offset)?),
+        name: row.get(offset + 1)?,
+        last_sequence: row.get::<_, i64>(offset + 2)? as u64,
+        created_at: get_date_time(row, offset + 3)?,
+        updated_at: get_date_time(row, offset + 4)?,
+    })
+}
+
+fn convert_to_comment(row: &Row) -> rusqlite::Result<CommentEntity> {
+    convert_to_comment_at(row, 0)
+}
+
+/// Converts the columns from the `offset` to a `CommentEntity`.
+fn convert_to_comment_at(row: &Row, offset: usize) -> rusqlite::Result<CommentEntity> {
+    Ok(CommentEntity {
+        id: CommentID(get_uuid(row, offset)?),
+        channel_id: ChannelID(get_uuid(row, offset + 1)?),
+        name: row.get(offset + 2)?,
+        message: row.get(offset + 3)?,
+        sequence: row.get::<_, i64>(offset + 4)? as u64,
+        created_at: get_date_time(row, offset + 5)?,
+        updated_at: get_date_time(row, offset + 6)?,
+    })
+}
+
+fn get_date_time(row: &Row, index: usize) -> rusqlite::Result<DateTime<Utc>> {
+    row.get::<_, String>(index)?.parse().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+fn get_uuid(row: &Row, index: usize) -> rusqlite::Result<uuid::Uuid> {
+    row.get::<_, String>(index)?.parse().map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ordering() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let db = SqliteChatDatabase::create(dir.path().join("database.sqlite"))?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        db.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        let mut sequences = vec![];
+        for message in &["one", "two", "three", "four"] {
+            let entity = db.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: message.to_string(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            sequences.push(entity.sequence);
+        }
+        assert_eq!(sequences, vec![1, 2, 3, 4]);
+
+        let messages = |comments: Vec<CommentEntity>| {
+            comments
+                .into_iter()
+                .map(|data| data.message)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            messages(db.retrieve_first_created_at_asc(&channel_id, 2)?),
+            vec!["three", "four"]
+        );
+        assert_eq!(
+            messages(db.retrieve_first_created_at_desc(&channel_id, 2)?),
+            vec!["four", "three"]
+        );
+        assert_eq!(
+            messages(db.retrieve_after_sequence_desc(&channel_id, 2, None)?),
+            vec!["four", "three"]
+        );
+        assert_eq!(
+            messages(db.retrieve_after_sequence_asc(&channel_id, 1, Some(2))?),
+            vec!["two", "three"]
+        );
+        assert_eq!(db.find_channel(&channel_id)?.ok_or_err()?.last_sequence, 4);
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_path = dir.path().join("database.sqlite");
+        SqliteChatDatabase::create(&database_path)?.save_channel(ChannelEntity {
+            id: ChannelID(uuid::Uuid::new_v4()),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        let db = SqliteChatDatabase::create(&database_path)?;
+        assert_eq!(db.channels_created_asc()?[0].name, "General");
+        assert_eq!(db.database_version()?, generate_latest_database_version()?);
+        Ok(())
+    }
+}
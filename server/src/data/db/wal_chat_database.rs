@@ -0,0 +1,820 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data::db::cached_chat_table::CachedChatTable;
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::dev_flex_chat_database::{
+    convert_to_version, ensure_latest_database_version, prepare_database_file, read_table,
+    write_table, DevFlexChatTable,
+};
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::model::version::Version;
+use crate::prelude::*;
+
+/// Number of the log records to trigger a checkpoint.
+const CHECKPOINT_THRESHOLD: usize = 1000;
+
+/// `ChatStore` that appends entities to `database.log` and writes `database.toml` only at
+/// checkpoints.
+///
+/// The log is a JSON Lines file. The first record is the version-code of the snapshot that the
+/// log is based on, and the rest are the entities saved after the snapshot. Each record is
+/// encrypted if the `cipher` is given.
+pub struct WalChatDatabase {
+    snapshot_path: PathBuf,
+    log_path: PathBuf,
+    cipher: Option<DatabaseCipher>,
+    state: Mutex<WalState>,
+}
+
+struct WalState {
+    table: CachedChatTable,
+    cipher: Option<DatabaseCipher>,
+    log: File,
+    record_count: usize,
+}
+
+#[derive(Deserialize, Serialize)]
+enum LogRecord {
+    #[serde(rename = "version-code")]
+    VersionCode(u64),
+
+    #[serde(rename = "channel")]
+    Channel(ChannelEntity),
+
+    #[serde(rename = "comment")]
+    Comment(CommentEntity),
+
+    #[serde(rename = "channel-update")]
+    ChannelUpdate(ChannelEntity),
+
+    #[serde(rename = "comment-update")]
+    CommentUpdate(CommentEntity),
+
+    #[serde(rename = "comment-removal")]
+    CommentRemoval(Vec<CommentID>),
+}
+
+impl WalChatDatabase {
+    pub fn create<T: Into<PathBuf>, U: Into<PathBuf>>(
+        snapshot_path: T,
+        log_path: U,
+        cipher: Option<DatabaseCipher>,
+    ) -> Fallible<Self> {
+        let snapshot_path = snapshot_path.into();
+        let log_path = log_path.into();
+
+        // read the log before the snapshot. the snapshot that is written by a checkpoint in the
+        // meantime contains all records of the log, and the duplicates are skipped at replay.
+        let log_string = read_log(&log_path)?;
+
+        prepare_database_file(&snapshot_path, cipher.as_ref())?;
+        ensure_latest_database_version(&snapshot_path, cipher.as_ref())?;
+        let mut table = read_table(&snapshot_path, cipher.as_ref())?;
+
+        let (records, valid_len) = split_records(&log_string);
+        replay(&mut table, &decode_records(&records, cipher.as_ref())?)?;
+
+        if valid_len != log_string.len() {
+            warn!("discard a torn record of the log");
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+        log.set_len(valid_len as u64)?;
+
+        let mut state = WalState {
+            table: CachedChatTable::new(table),
+            cipher: cipher.clone(),
+            log,
+            record_count: records.len(),
+        };
+
+        if records.is_empty() {
+            let version_code = state.table.table().version_code;
+            append_record(&mut state, &LogRecord::VersionCode(version_code))?;
+        }
+
+        Ok(Self {
+            snapshot_path,
+            log_path,
+            cipher,
+            state: Mutex::new(state),
+        })
+    }
+
+    /// Writes the state to the snapshot and truncates the log.
+    fn checkpoint(&self, state: &mut WalState) -> Fallible<()> {
+        info!("checkpoint: {:?}", self.log_path);
+
+        write_table(
+            &self.snapshot_path,
+            state.table.table(),
+            self.cipher.as_ref(),
+        )?;
+
+        state.log.set_len(0)?;
+        state.record_count = 0;
+        let version_code = state.table.table().version_code;
+        append_record(state, &LogRecord::VersionCode(version_code))
+    }
+
+    fn append<T, F>(&self, f: F) -> Fallible<T>
+    where
+        F: FnOnce(&mut CachedChatTable) -> Fallible<(LogRecord, T)>,
+    {
+        let mut state = match self.state.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to lock the log: {:?}", e),
+        };
+
+        let (record, ret) = f(&mut state.table)?;
+        append_record(&mut state, &record)?;
+        match record {
+            LogRecord::Channel(entity) => state.table.push_channel(entity),
+            LogRecord::Comment(entity) => state.table.push_comment(entity)?,
+            LogRecord::ChannelUpdate(entity) => {
+                state.table.replace_channel(entity)?;
+            }
+            LogRecord::CommentUpdate(entity) => {
+                state.table.replace_comment(entity)?;
+            }
+            LogRecord::VersionCode(_) | LogRecord::CommentRemoval(_) => {
+                failure::bail!("unexpected record")
+            }
+        }
+
+        if CHECKPOINT_THRESHOLD <= state.record_count {
+            self.checkpoint(&mut state)?;
+        }
+
+        Ok(ret)
+    }
+
+    /// Replaces the table with the one that the `f` returns and writes the snapshot at once, so
+    /// that the removed comments do not remain in the log. The IDs of the removed comments are
+    /// logged before the checkpoint, and the `replay` removes them again if the checkpoint is
+    /// interrupted before the log is truncated.
+    fn rewrite<T, F>(&self, f: F) -> Fallible<T>
+    where
+        F: FnOnce(&CachedChatTable) -> Fallible<(CachedChatTable, Vec<CommentID>, T)>,
+    {
+        let mut state = match self.state.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to lock the log: {:?}", e),
+        };
+
+        let (table, ids, ret) = f(&state.table)?;
+        if ids.is_empty() {
+            return Ok(ret);
+        }
+
+        append_record(&mut state, &LogRecord::CommentRemoval(ids))?;
+        state.table = table;
+        self.checkpoint(&mut state)?;
+
+        Ok(ret)
+    }
+
+    fn with_table<T, F: FnOnce(&CachedChatTable) -> T>(&self, f: F) -> Fallible<T> {
+        match self.state.lock() {
+            Ok(state) => Ok(f(&state.table)),
+            Err(e) => failure::bail!("failed to lock the table: {:?}", e),
+        }
+    }
+}
+
+impl ChatStore for WalChatDatabase {
+    fn channels_created_asc(&self) -> Fallible<Vec<ChannelEntity>> {
+        self.with_table(|table| table.channels().to_vec())
+    }
+
+    fn database_version(&self) -> Fallible<(Version, u16)> {
+        self.with_table(|table| convert_to_version(table.table().version_code))
+    }
+
+    fn find_channel(&self, channel_id: &ChannelID) -> Fallible<Option<ChannelEntity>> {
+        self.with_table(|table| table.find_channel(channel_id).cloned())
+    }
+
+    fn retrieve_comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        self.with_table(|table| table.comments(channel_id))
+    }
+
+    fn save_channel(&self, entity: ChannelEntity) -> Fallible<()> {
+        self.append(|_| Ok((LogRecord::Channel(entity), ())))
+    }
+
+    fn save_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        let entity = self.append(|table| {
+            let entity = table.assign_sequence(entity)?;
+            Ok((LogRecord::Comment(entity.clone()), entity))
+        })?;
+        Ok(entity)
+    }
+
+    fn insert_comments(&self, entities: Vec<CommentEntity>) -> Fallible<()> {
+        let mut state = match self.state.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to lock the log: {:?}", e),
+        };
+
+        // check the entities before the log so that the log has no record that the table rejects.
+        let table = state.table.insert_comments(entities.clone())?;
+        let records = entities
+            .into_iter()
+            .map(LogRecord::Comment)
+            .collect::<Vec<_>>();
+        append_records(&mut state, &records)?;
+        state.table = table;
+
+        if CHECKPOINT_THRESHOLD <= state.record_count {
+            self.checkpoint(&mut state)?;
+        }
+
+        Ok(())
+    }
+
+    fn update_channel(&self, entity: ChannelEntity) -> Fallible<ChannelEntity> {
+        self.append(|table| {
+            let entity = table.merge_channel(entity)?;
+            Ok((LogRecord::ChannelUpdate(entity.clone()), entity))
+        })
+    }
+
+    fn update_comment(&self, entity: CommentEntity) -> Fallible<CommentEntity> {
+        self.append(|table| {
+            let entity = table.merge_comment(entity)?;
+            Ok((LogRecord::CommentUpdate(entity.clone()), entity))
+        })
+    }
+
+    fn remove_comment(&self, channel_id: &ChannelID, id: &CommentID) -> Fallible<CommentEntity> {
+        self.rewrite(|table| match table.find_comment(channel_id, id) {
+            Some(data) => Ok((
+                table.remove_comment(channel_id, id),
+                vec![id.clone()],
+                data.clone(),
+            )),
+            None => failure::bail!("comment not found"),
+        })
+    }
+
+    fn take_comments_until(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+    ) -> Fallible<Vec<CommentEntity>> {
+        self.rewrite(|table| {
+            let entities = table.comments_until(channel_id, sequence);
+            let ids = entities.iter().map(|data| data.id.clone()).collect();
+            Ok((
+                table.remove_comments_until(channel_id, sequence),
+                ids,
+                entities,
+            ))
+        })
+    }
+
+    fn find_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+    ) -> Fallible<Option<CommentEntity>> {
+        self.with_table(|table| table.find_comment(channel_id, id).cloned())
+    }
+
+    fn retrieve_after_sequence_asc(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let mut comments = self.with_table(|table| table.comments_after(channel_id, sequence))?;
+        if let Some(count) = count {
+            comments.truncate(count as usize);
+        }
+        Ok(comments)
+    }
+}
+
+/// Reads the snapshot and the records of the log without opening the database for writing.
+///
+/// The result is consistent even if a `WalChatDatabase` of another process is appending to the
+/// log.
+pub fn read_table_with_log(
+    snapshot_path: &Path,
+    log_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<DevFlexChatTable> {
+    // same order as the `WalChatDatabase::create`.
+    let log_string = read_log(log_path)?;
+    let mut table = read_table(snapshot_path, cipher)?;
+    replay(
+        &mut table,
+        &decode_records(&split_records(&log_string).0, cipher)?,
+    )?;
+    Ok(table)
+}
+
+/// Returns `true` if the log has entities that are not checkpointed yet.
+pub fn has_pending_records(log_path: &Path) -> Fallible<bool> {
+    Ok(1 < split_records(&read_log(log_path)?).0.len())
+}
+
+/// Moves the records of the log into the snapshot.
+///
+/// The log is merged as `toml::Value` so that the snapshot of an old version can be merged before
+/// the migration.
+pub fn merge_log_into_snapshot(
+    snapshot_path: &Path,
+    log_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    if !log_path.exists() {
+        return Ok(());
+    }
+
+    info!("merge the log into the snapshot");
+
+    let snapshot = read_value_with_log(snapshot_path, log_path, cipher)?;
+    database_cipher::write_file_atomically(
+        snapshot_path,
+        toml::to_string(&snapshot)?.as_bytes(),
+        cipher,
+    )?;
+    std::fs::remove_file(log_path)?;
+
+    info!("succeeded to merge the log");
+    Ok(())
+}
+
+/// Reads the snapshot as `toml::Value` and merges the records of the log into it without
+/// modifying the files.
+pub fn read_value_with_log(
+    snapshot_path: &Path,
+    log_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<toml::Value> {
+    // same order as the `WalChatDatabase::create`.
+    let log_string = read_log(log_path)?;
+    let mut snapshot = toml::from_str::<toml::Value>(&database_cipher::read_file_to_string(
+        snapshot_path,
+        cipher,
+    )?)?;
+    let snapshot_version_code = snapshot.get("version-code").cloned();
+    let snapshot_table = snapshot.as_table_mut().ok_or_err()?;
+
+    let records = decode_records(&split_records(&log_string).0, cipher)?;
+    for (index, record) in records.iter().enumerate() {
+        let record = serde_json::from_str::<serde_json::Value>(record)?;
+        let record = record.as_object().ok_or_err()?;
+        let (key, entity) = record.iter().next().ok_or_err()?;
+        let entity = toml::Value::try_from(entity)?;
+        let array_key = match key.as_str() {
+            "version-code" => {
+                if index != 0 || snapshot_version_code.as_ref() != Some(&entity) {
+                    failure::bail!(
+                        "unexpected version-code of the log. snapshot: {:?}, log: {:?}",
+                        snapshot_version_code,
+                        entity
+                    );
+                }
+                continue;
+            }
+            "comment-removal" => {
+                let ids = entity.as_array().ok_or_err()?;
+                if let Some(comments) = snapshot_table
+                    .get_mut("comments")
+                    .and_then(|data| data.as_array_mut())
+                {
+                    comments.retain(|data| data.get("id").filter(|id| ids.contains(id)).is_none());
+                }
+                continue;
+            }
+            "channel" | "channel-update" => "channels",
+            "comment" | "comment-update" => "comments",
+            _ => failure::bail!("unexpected record: {}", key),
+        };
+
+        let entities = snapshot_table
+            .entry(array_key)
+            .or_insert_with(|| toml::Value::Array(vec![]))
+            .as_array_mut()
+            .ok_or_err()?;
+        let updated_keys = match key.as_str() {
+            "channel-update" => ["name", "updated-at"],
+            "comment-update" => ["message", "updated-at"],
+            _ => {
+                if entities
+                    .iter()
+                    .all(|data| data.get("id") != entity.get("id"))
+                {
+                    entities.push(entity);
+                }
+                continue;
+            }
+        };
+
+        // same as the `replay`.
+        let current = entities
+            .iter_mut()
+            .filter_map(|data| data.as_table_mut())
+            .find(|data| data.get("id") == entity.get("id"));
+        if let Some(current) = current {
+            let updated_at = |data: &toml::value::Table| {
+                data.get("updated-at")
+                    .and_then(|data| data.as_str())
+                    .and_then(|data| data.parse::<DateTime<Utc>>().ok())
+            };
+            if updated_at(current) <= entity.as_table().and_then(updated_at) {
+                for updated_key in &updated_keys {
+                    if let Some(value) = entity.get(updated_key) {
+                        current.insert(updated_key.to_string(), value.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+/// Applies the records to the `table`. The entities already in the `table` are skipped, the
+/// updates older than the entities of the `table` are ignored, and the removed comments are
+/// removed at last.
+fn replay(table: &mut DevFlexChatTable, records: &[String]) -> Fallible<()> {
+    let mut channel_indexes = table
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(index, data)| (data.id.clone(), index))
+        .collect::<HashMap<_, _>>();
+    let mut comment_indexes = table
+        .comments
+        .iter()
+        .enumerate()
+        .map(|(index, data)| (data.id.clone(), index))
+        .collect::<HashMap<_, _>>();
+    let mut last_sequences = HashMap::<ChannelID, u64>::new();
+    let mut removed_ids = HashSet::<CommentID>::new();
+
+    info!("replay {} records", records.len());
+    for (index, record) in records.iter().enumerate() {
+        match serde_json::from_str(record)? {
+            LogRecord::VersionCode(version_code) => {
+                if index != 0 || version_code != table.version_code {
+                    failure::bail!(
+                        "unexpected version-code of the log. snapshot: {:?}, log: {:?}",
+                        convert_to_version(table.version_code),
+                        convert_to_version(version_code)
+                    );
+                }
+            }
+            LogRecord::Channel(entity) => {
+                if !channel_indexes.contains_key(&entity.id) {
+                    channel_indexes.insert(entity.id.clone(), table.channels.len());
+                    table.channels.push(entity);
+                }
+            }
+            LogRecord::Comment(entity) => {
+                if !comment_indexes.contains_key(&entity.id) {
+                    let last_sequence =
+                        last_sequences.entry(entity.channel_id.clone()).or_default();
+                    *last_sequence = entity.sequence.max(*last_sequence);
+                    comment_indexes.insert(entity.id.clone(), table.comments.len());
+                    table.comments.push(entity);
+                }
+            }
+            LogRecord::ChannelUpdate(entity) => {
+                if let Some(index) = channel_indexes.get(&entity.id) {
+                    let channel = &mut table.channels[*index];
+                    if channel.updated_at <= entity.updated_at {
+                        channel.name = entity.name;
+                        channel.updated_at = entity.updated_at;
+                    }
+                }
+            }
+            LogRecord::CommentUpdate(entity) => {
+                if let Some(index) = comment_indexes.get(&entity.id) {
+                    let comment = &mut table.comments[*index];
+                    if comment.updated_at <= entity.updated_at {
+                        comment.message = entity.message;
+                        comment.updated_at = entity.updated_at;
+                    }
+                }
+            }
+            LogRecord::CommentRemoval(ids) => removed_ids.extend(ids),
+        }
+    }
+
+    // the indexes are not used after here.
+    if !removed_ids.is_empty() {
+        table
+            .comments
+            .retain(|data| !removed_ids.contains(&data.id));
+    }
+
+    // the channel records are not updated by the comments.
+    for channel in &mut table.channels {
+        if let Some(last_sequence) = last_sequences.get(&channel.id) {
+            channel.last_sequence = channel.last_sequence.max(*last_sequence);
+        }
+    }
+
+    Ok(())
+}
+
+/// Appends the `record` to the log. See the `append_records`.
+fn append_record(state: &mut WalState, record: &LogRecord) -> Fallible<()> {
+    append_records(state, std::slice::from_ref(record))
+}
+
+/// Appends the `records` to the log by a write. The log is truncated to the previous length if the
+/// write fails, so that the next record is not appended to the torn one.
+fn append_records(state: &mut WalState, records: &[LogRecord]) -> Fallible<()> {
+    let mut lines = vec![];
+    for record in records {
+        lines.extend(database_cipher::encode_line(
+            &serde_json::to_vec(record)?,
+            state.cipher.as_ref(),
+        )?);
+        lines.push(b'\n');
+    }
+    let len = state.log.metadata()?.len();
+    if let Err(e) = write_lines(&mut state.log, &lines) {
+        if let Err(e) = state.log.set_len(len) {
+            warn!("failed to truncate the torn record: {:?}", e);
+        }
+        return Err(e.into());
+    }
+    state.record_count += records.len();
+    Ok(())
+}
+
+fn write_lines(log: &mut File, lines: &[u8]) -> std::io::Result<()> {
+    log.write_all(lines)?;
+    log.sync_data()
+}
+
+fn read_log(log_path: &Path) -> Fallible<String> {
+    if !log_path.exists() {
+        return Ok(String::new());
+    }
+
+    Ok(std::fs::read_to_string(log_path)?)
+}
+
+fn decode_records(records: &[&str], cipher: Option<&DatabaseCipher>) -> Fallible<Vec<String>> {
+    records
+        .iter()
+        .map(|data| database_cipher::decode_line(data, cipher))
+        .collect()
+}
+
+/// Splits the log into the records and returns them with the length of the valid part.
+///
+/// A record without the trailing newline is a torn write of a crash and is not included.
+fn split_records(log_string: &str) -> (Vec<&str>, usize) {
+    let mut records = vec![];
+    let mut offset = 0;
+    while let Some(len) = log_string[offset..].find('\n') {
+        records.push(&log_string[offset..offset + len]);
+        offset += len + 1;
+    }
+    (records, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn prepare_database(dir: &Path) -> Fallible<WalChatDatabase> {
+        WalChatDatabase::create(dir.join("database.toml"), dir.join("database.log"), None)
+    }
+
+    #[test]
+    fn test_replay() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        {
+            let db = prepare_database(dir.path())?;
+            db.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            db.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: "message".into(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+
+        // torn write.
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("database.log"))?;
+        log.write_all(b"{\"comment\":{\"id\"")?;
+
+        let db = prepare_database(dir.path())?;
+        assert_eq!(db.channels_created_asc()?.len(), 1);
+        assert_eq!(db.retrieve_comments(&channel_id)?[0].message, "message");
+        assert!(read_table(&dir.path().join("database.toml"), None)?
+            .channels
+            .is_empty());
+
+        let entity = db.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "message2".into(),
+            sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        assert_eq!(entity.sequence, 2);
+        drop(db);
+
+        let db = prepare_database(dir.path())?;
+        assert_eq!(db.retrieve_comments(&channel_id)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_update() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let comment_ids = (0..2)
+            .map(|_| CommentID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+        {
+            let db = prepare_database(dir.path())?;
+            let channel = ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            };
+            db.save_channel(channel.clone())?;
+            for id in &comment_ids {
+                db.save_comment(CommentEntity {
+                    id: id.clone(),
+                    channel_id: channel_id.clone(),
+                    name: "name".into(),
+                    message: "message".into(),
+                    sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })?;
+            }
+            db.remove_comment(&channel_id, &comment_ids[0])?;
+
+            let renamed = db.update_channel(ChannelEntity {
+                name: "Random".into(),
+                updated_at: Utc::now(),
+                ..channel
+            })?;
+            assert_eq!(renamed.last_sequence, 2);
+            let comment = db.retrieve_comments(&channel_id)?.remove(0);
+            db.update_comment(CommentEntity {
+                message: "edited".into(),
+                updated_at: Utc::now(),
+                ..comment
+            })?;
+        }
+
+        let db = prepare_database(dir.path())?;
+        let channel = db.find_channel(&channel_id)?.ok_or_err()?;
+        assert_eq!(channel.name, "Random");
+        assert_eq!(channel.last_sequence, 2);
+        let comments = db.retrieve_comments(&channel_id)?;
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].id, comment_ids[1]);
+        assert_eq!(comments[0].message, "edited");
+        Ok(())
+    }
+
+    #[test]
+    fn test_replay_after_interrupted_rewrite() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let log_path = dir.path().join("database.log");
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let comment_ids = (0..3)
+            .map(|_| CommentID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+        {
+            let db = prepare_database(dir.path())?;
+            db.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            for id in &comment_ids {
+                db.save_comment(CommentEntity {
+                    id: id.clone(),
+                    channel_id: channel_id.clone(),
+                    name: "name".into(),
+                    message: "message".into(),
+                    sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })?;
+            }
+            let mut log = std::fs::read(&log_path)?;
+            db.take_comments_until(&channel_id, 1)?;
+            db.remove_comment(&channel_id, &comment_ids[2])?;
+
+            // crashed before the log is truncated.
+            for ids in &[vec![comment_ids[0].clone()], vec![comment_ids[2].clone()]] {
+                log.extend(serde_json::to_vec(&LogRecord::CommentRemoval(ids.clone()))?);
+                log.push(b'\n');
+            }
+            std::fs::write(&log_path, log)?;
+        }
+
+        let snapshot = read_value_with_log(&dir.path().join("database.toml"), &log_path, None)?;
+        assert_eq!(snapshot["comments"].as_array().ok_or_err()?.len(), 1);
+
+        let db = prepare_database(dir.path())?;
+        let comments = db.retrieve_comments(&channel_id)?;
+        assert_eq!(
+            comments.iter().map(|data| &data.id).collect::<Vec<_>>(),
+            vec![&comment_ids[1]]
+        );
+        assert_eq!(db.find_channel(&channel_id)?.ok_or_err()?.last_sequence, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_log_into_snapshot() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let snapshot_path = dir.path().join("database.toml");
+        let log_path = dir.path().join("database.log");
+        {
+            let db = prepare_database(dir.path())?;
+            db.save_channel(ChannelEntity {
+                id: ChannelID(uuid::Uuid::new_v4()),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+        assert!(has_pending_records(&log_path)?);
+
+        merge_log_into_snapshot(&snapshot_path, &log_path, None)?;
+        assert!(!log_path.exists());
+        assert_eq!(
+            read_table(&snapshot_path, None)?.channels[0].name,
+            "General"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_records_torn() {
+        let (records, valid_len) = split_records("{\"a\":1}\n{\"b\":2}\n{\"c\"");
+        assert_eq!(records, vec!["{\"a\":1}", "{\"b\":2}"]);
+        assert_eq!(valid_len, 16);
+    }
+}
@@ -0,0 +1,201 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Mutex;
+
+use futures::sync::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use serde_derive::Serialize;
+
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, ChannelID, CommentEntity};
+use crate::model::presence::ChannelPresence;
+use crate::prelude::*;
+
+/// A change of the chat that is published after it is saved to the storage. Serialized as the
+/// entity of the change.
+#[derive(Clone, Serialize)]
+#[serde(untagged)]
+pub enum ChatEvent {
+    ChannelCreated(ChannelEntity),
+    ChannelRenamed(ChannelEntity),
+    CommentPosted(CommentEntity),
+    CommentEdited(CommentEntity),
+
+    /// A comment that is deleted or moved to the archive by the retention.
+    CommentDeleted(CommentEntity),
+
+    /// Typing or active users of a channel that are changed. Not saved to the storage.
+    PresenceChanged(ChannelPresence),
+}
+
+impl ChatEvent {
+    /// Returns the channel that the event belongs to.
+    pub fn channel_id(&self) -> &ChannelID {
+        match self {
+            ChatEvent::ChannelCreated(data) | ChatEvent::ChannelRenamed(data) => &data.id,
+            ChatEvent::CommentPosted(data)
+            | ChatEvent::CommentEdited(data)
+            | ChatEvent::CommentDeleted(data) => &data.channel_id,
+            ChatEvent::PresenceChanged(data) => &data.channel_id,
+        }
+    }
+
+    /// Returns the name of the event for the webhooks and the metrics.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChatEvent::ChannelCreated(_) => "channel-created",
+            ChatEvent::ChannelRenamed(_) => "channel-renamed",
+            ChatEvent::CommentPosted(_) => "comment-posted",
+            ChatEvent::CommentEdited(_) => "comment-edited",
+            ChatEvent::CommentDeleted(_) => "comment-deleted",
+            ChatEvent::PresenceChanged(_) => "presence-changed",
+        }
+    }
+}
+
+/// Events that a subscriber of the `EventBus` receives.
+#[derive(Clone)]
+pub enum EventFilter {
+    All,
+    Channel(ChannelID),
+}
+
+impl EventFilter {
+    fn matches(&self, event: &ChatEvent) -> bool {
+        match self {
+            EventFilter::All => true,
+            EventFilter::Channel(data) => data == event.channel_id(),
+        }
+    }
+}
+
+struct Subscriber {
+    filter: EventFilter,
+    sender: UnboundedSender<ChatEvent>,
+}
+
+/// Delivers the `ChatEvent`s to the long polling, the subscriptions, the webhooks and the metrics
+/// independently of the storage.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl EventBus {
+    /// Returns the stream of the events that match the `filter` until it is dropped.
+    pub fn subscribe(&self, filter: EventFilter) -> Fallible<UnboundedReceiver<ChatEvent>> {
+        let (sender, receiver) = unbounded();
+        match self.subscribers.lock() {
+            Ok(mut subscribers) => {
+                subscribers.retain(|data| !data.sender.is_closed());
+                subscribers.push(Subscriber { filter, sender });
+            }
+            Err(e) => failure::bail!("failed to subscribe: {:?}", e),
+        }
+
+        Ok(receiver)
+    }
+
+    /// Sends the `event` to the subscribers of it, and removes the ones whose stream is dropped.
+    pub fn publish(&self, event: ChatEvent) -> Fallible<()> {
+        match self.subscribers.lock() {
+            Ok(mut subscribers) => {
+                subscribers.retain(|data| {
+                    if data.filter.matches(&event) {
+                        data.sender.unbounded_send(event.clone()).is_ok()
+                    } else {
+                        !data.sender.is_closed()
+                    }
+                });
+                Ok(())
+            }
+            Err(e) => failure::bail!("failed to publish event: {:?}", e),
+        }
+    }
+
+    /// Removes the subscribers whose stream is dropped by the timeout or the disconnection.
+    pub fn prune(&self) -> Fallible<()> {
+        match self.subscribers.lock() {
+            Ok(mut subscribers) => {
+                subscribers.retain(|data| !data.sender.is_closed());
+                Ok(())
+            }
+            Err(e) => failure::bail!("failed to prune subscribers: {:?}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use futures::Stream;
+
+    use crate::data::db::entity::dev_flex_chat_entity::CommentID;
+
+    use super::*;
+
+    fn comment(channel_id: &ChannelID) -> ChatEvent {
+        ChatEvent::CommentPosted(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "message".into(),
+            sequence: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })
+    }
+
+    #[test]
+    fn test_publish() -> Fallible<()> {
+        let bus = EventBus::default();
+        let channel_ids = (0..2)
+            .map(|_| ChannelID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+
+        let all = bus.subscribe(EventFilter::All)?;
+        let channel = bus.subscribe(EventFilter::Channel(channel_ids[0].clone()))?;
+        bus.publish(comment(&channel_ids[0]))?;
+        bus.publish(comment(&channel_ids[1]))?;
+        drop(bus);
+
+        let channel_ids_of = |events: UnboundedReceiver<ChatEvent>| {
+            events
+                .wait()
+                .map(|data| data.map(|data| data.channel_id().clone()))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| failure::format_err!("failed to receive events"))
+        };
+        assert_eq!(channel_ids_of(all)?, channel_ids);
+        assert_eq!(channel_ids_of(channel)?, vec![channel_ids[0].clone()]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prune() -> Fallible<()> {
+        let bus = EventBus::default();
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+
+        let _all = bus.subscribe(EventFilter::All)?;
+        drop(bus.subscribe(EventFilter::All)?);
+        drop(bus.subscribe(EventFilter::Channel(channel_id))?);
+        // the subscriber that is dropped before the last subscription is removed by it.
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 2);
+
+        bus.prune()?;
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        Ok(())
+    }
+}
@@ -14,32 +14,185 @@
  * limitations under the License.
  */
 
+use std::collections::hash_map::{Entry, HashMap};
 use std::path::PathBuf;
 
-use crate::data::db::dev_flex_chat_database::DevFlexChatDatabase;
+use chrono::Utc;
+use futures::sync::mpsc::UnboundedReceiver;
+
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::comment_archive::CommentArchive;
+use crate::data::db::database_cipher::DatabaseCipher;
+use crate::data::db::dev_flex_chat_database::{self, DevFlexChatDatabase, DevFlexChatTable};
 // TODO: use model.
 use crate::data::db::entity::dev_flex_chat_entity::{
     ChannelEntity, ChannelID, CommentEntity, CommentID,
 };
+use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+use crate::data::db::sqlite_chat_database::{self, SqliteChatDatabase};
+use crate::data::db::wal_chat_database::{self, WalChatDatabase};
+use crate::data::event_bus::{ChatEvent, EventBus, EventFilter};
 use crate::model::juniper_object::OrderDirection;
+use crate::model::presence::ChannelPresence;
+use crate::model::storage_type::StorageType;
 use crate::model::version::Version;
 use crate::prelude::*;
 
 pub struct DevFlexChatRepository {
-    database: DevFlexChatDatabase,
+    database: Box<dyn ChatStore>,
+    archive: Option<CommentArchive>,
+    events: EventBus,
 }
 
 impl DevFlexChatRepository {
-    pub fn prepare<T: Into<PathBuf>>(database_path: T) -> Fallible<Self> {
-        let database_path = database_path.into();
+    pub fn new(database: Box<dyn ChatStore>) -> Self {
+        Self {
+            database,
+            archive: None,
+            events: Default::default(),
+        }
+    }
+
+    pub fn with_archive(mut self, archive: CommentArchive) -> Self {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// Opens the storage. The files are encrypted with the `cipher` if it is given.
+    pub fn prepare(
+        storage: &StorageType,
+        database_dir: Option<PathBuf>,
+        cipher: Option<DatabaseCipher>,
+    ) -> Fallible<Self> {
+        let repo = Self::new(open_chat_store(
+            storage,
+            database_dir.clone(),
+            cipher.clone(),
+        )?);
+        match storage {
+            StorageType::Memory => Ok(repo),
+            _ => Ok(repo.with_archive(CommentArchive::new(
+                crate::util::get_archive_dir_path(database_dir),
+                cipher,
+            ))),
+        }
+    }
+
+    /// Reads all entities of the storage without opening it for writing, so that the storage
+    /// of the running server can be read.
+    pub fn read_snapshot(
+        storage: &StorageType,
+        database_dir: Option<PathBuf>,
+        cipher: Option<&DatabaseCipher>,
+    ) -> Fallible<DevFlexChatTable> {
+        ensure_cipher_supported(storage, cipher)?;
+
+        match storage {
+            StorageType::Toml | StorageType::Wal => wal_chat_database::read_table_with_log(
+                &crate::util::get_database_file_path(database_dir.clone()),
+                &crate::util::get_database_log_file_path(database_dir),
+                cipher,
+            ),
+            StorageType::Sqlite => sqlite_chat_database::read_table(
+                &crate::util::get_database_sqlite_file_path(database_dir),
+            ),
+            StorageType::Memory => failure::bail!("memory storage has no snapshot"),
+        }
+    }
+
+    /// Replaces all entities of the storage with the `table`.
+    pub fn write_snapshot(
+        storage: &StorageType,
+        database_dir: Option<PathBuf>,
+        table: &DevFlexChatTable,
+        cipher: Option<&DatabaseCipher>,
+    ) -> Fallible<()> {
+        ensure_cipher_supported(storage, cipher)?;
+
+        match storage {
+            StorageType::Toml | StorageType::Wal => {
+                // remove the log first so that the records of the previous database are not
+                // replayed onto the new snapshot.
+                let log_path = crate::util::get_database_log_file_path(database_dir.clone());
+                if log_path.exists() {
+                    std::fs::remove_file(&log_path)?;
+                }
+
+                let database_path = crate::util::get_database_file_path(database_dir);
+                if let Some(parent) = database_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                dev_flex_chat_database::write_table(&database_path, table, cipher)
+            }
+            StorageType::Sqlite => sqlite_chat_database::write_table(
+                &crate::util::get_database_sqlite_file_path(database_dir),
+                table,
+            ),
+            StorageType::Memory => failure::bail!("memory storage has no snapshot"),
+        }
+    }
+
+    /// Moves the comments of the channel whose `sequence` is less than or equal to the `sequence`
+    /// to the archive, and returns the number of them.
+    ///
+    /// The comments are written to the archive before the removal so that they are not lost by an
+    /// interruption. The removed comments are written to the archive again if some of them are
+    /// edited or saved in the meantime.
+    pub fn archive_comments_until(&self, channel_id: &ChannelID, sequence: u64) -> Fallible<usize> {
+        let archive = match self.archive {
+            Some(ref data) => data,
+            None => failure::bail!("archive is not available"),
+        };
+
+        let comments = self
+            .database
+            .retrieve_comments(channel_id)?
+            .into_iter()
+            .take_while(|data| data.sequence <= sequence)
+            .collect::<Vec<_>>();
+        if comments.is_empty() {
+            return Ok(0);
+        }
+
+        archive.append(channel_id, &comments)?;
+        let removed_comments = self.database.take_comments_until(channel_id, sequence)?;
+        let is_changed = removed_comments.iter().any(|data| {
+            !comments
+                .iter()
+                .any(|comment| comment.id == data.id && comment.updated_at == data.updated_at)
+        });
+        if is_changed {
+            archive.append(channel_id, &removed_comments)?;
+        }
 
-        Ok(Self {
-            database: DevFlexChatDatabase::create(database_path)?,
-        })
+        let count = removed_comments.len();
+        for comment in removed_comments {
+            self.events.publish(ChatEvent::CommentDeleted(comment))?;
+        }
+        Ok(count)
     }
 
-    pub fn channel_long_polling(&self) -> Fallible<ChannelEntity> {
-        self.database.channel_long_polling()
+    /// Returns at most `count` archived comments whose `sequence` is greater than the `sequence`.
+    pub fn archived_comments(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
+    ) -> Fallible<Vec<CommentEntity>> {
+        let archive = match self.archive {
+            Some(ref data) => data,
+            None => return Ok(vec![]),
+        };
+
+        let mut comments = archive
+            .retrieve(channel_id)?
+            .into_iter()
+            .filter(|data| sequence < data.sequence)
+            .collect::<Vec<_>>();
+        if let Some(count) = count {
+            comments.truncate(count as usize);
+        }
+        Ok(comments)
     }
 
     pub fn channels(&self) -> Fallible<Vec<ChannelEntity>> {
@@ -51,16 +204,12 @@ impl DevFlexChatRepository {
     }
 
     pub fn find_channel<T: AsRef<ChannelID>>(&self, id: T) -> Fallible<Option<ChannelEntity>> {
-        self.database.find_channel(id)
+        self.database.find_channel(id.as_ref())
     }
 
-    pub fn retrieve_channel_after_long_polling<T: AsRef<ChannelID>>(
-        &self,
-        id: T,
-        order_direction: &OrderDirection,
-    ) -> Fallible<Vec<ChannelEntity>> {
-        self.database
-            .channels_after_long_polling(id.as_ref(), order_direction)
+    /// Returns the channels that are created after the channel of the `id`.
+    pub fn channels_after<T: AsRef<ChannelID>>(&self, id: T) -> Fallible<Vec<ChannelEntity>> {
+        self.database.channels_after_created_asc(id.as_ref())
     }
 
     pub fn retrieve_first(
@@ -79,25 +228,197 @@ impl DevFlexChatRepository {
         }
     }
 
-    pub fn retrieve_after_long_polling(
+    pub fn comments(&self, channel_id: &ChannelID) -> Fallible<Vec<CommentEntity>> {
+        self.database.retrieve_comments(channel_id)
+    }
+
+    pub fn find_comment(
         &self,
         channel_id: &ChannelID,
         id: &CommentID,
+    ) -> Fallible<Option<CommentEntity>> {
+        self.database.find_comment(channel_id, id)
+    }
+
+    pub fn retrieve_after(
+        &self,
+        channel_id: &ChannelID,
+        sequence: u64,
+        count: Option<u32>,
         order_direction: &OrderDirection,
     ) -> Fallible<Vec<CommentEntity>> {
-        self.database
-            .retrieve_after_long_polling(channel_id, id, order_direction)
+        match order_direction {
+            OrderDirection::ASC => self
+                .database
+                .retrieve_after_sequence_asc(channel_id, sequence, count),
+            OrderDirection::DESC => self
+                .database
+                .retrieve_after_sequence_desc(channel_id, sequence, count),
+        }
     }
 
-    pub fn long_polling(&self, channel_id: &ChannelID) -> Fallible<CommentEntity> {
-        self.database.long_polling(channel_id)
+    /// Removes the subscribers whose stream is dropped.
+    pub fn prune_subscribers(&self) -> Fallible<()> {
+        self.events.prune()
     }
 
     pub fn save_channel<T: Into<ChannelEntity>>(&self, entity: T) -> Fallible<()> {
-        self.database.save_channel(entity)
+        let entity = entity.into();
+        self.database.save_channel(entity.clone())?;
+        self.events.publish(ChatEvent::ChannelCreated(entity))
+    }
+
+    pub fn save_channels(&self, entities: Vec<ChannelEntity>) -> Fallible<()> {
+        self.database.save_channels(entities.clone())?;
+        for entity in entities {
+            self.events.publish(ChatEvent::ChannelCreated(entity))?;
+        }
+        Ok(())
     }
 
-    pub fn save_comment<T: Into<CommentEntity>>(&self, comment: T) -> Fallible<()> {
-        self.database.save_comment(comment)
+    /// Saves the `comments` at once with the next `sequence` of their channels in the order of the
+    /// `comments`, and returns the saved ones.
+    pub fn save_comments(&self, comments: Vec<CommentEntity>) -> Fallible<Vec<CommentEntity>> {
+        let mut last_sequences = HashMap::new();
+        let mut entities = Vec::with_capacity(comments.len());
+        for mut entity in comments {
+            let last_sequence = match last_sequences.entry(entity.channel_id.clone()) {
+                Entry::Occupied(data) => data.into_mut(),
+                Entry::Vacant(data) => match self.database.find_channel(&entity.channel_id)? {
+                    Some(channel) => data.insert(channel.last_sequence),
+                    None => failure::bail!("channel not found: {:?}", entity.channel_id),
+                },
+            };
+            *last_sequence += 1;
+            entity.sequence = *last_sequence;
+            entities.push(entity);
+        }
+
+        self.database.insert_comments(entities.clone())?;
+        for entity in &entities {
+            self.events
+                .publish(ChatEvent::CommentPosted(entity.clone()))?;
+        }
+        Ok(entities)
+    }
+
+    pub fn save_comment<T: Into<CommentEntity>>(&self, comment: T) -> Fallible<CommentEntity> {
+        let entity = self.database.save_comment(comment.into())?;
+        self.events
+            .publish(ChatEvent::CommentPosted(entity.clone()))?;
+        Ok(entity)
+    }
+
+    /// Replaces the name of the channel of the `channel_id`, and returns the renamed one.
+    pub fn rename_channel(&self, channel_id: &ChannelID, name: String) -> Fallible<ChannelEntity> {
+        let entity = match self.database.find_channel(channel_id)? {
+            Some(data) => data,
+            None => failure::bail!("channel not found"),
+        };
+        let entity = self.database.update_channel(ChannelEntity {
+            name,
+            updated_at: Utc::now(),
+            ..entity
+        })?;
+        self.events
+            .publish(ChatEvent::ChannelRenamed(entity.clone()))?;
+        Ok(entity)
+    }
+
+    /// Replaces the message of the comment of the `id`, and returns the edited one.
+    pub fn edit_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+        message: String,
+    ) -> Fallible<CommentEntity> {
+        let entity = match self.database.find_comment(channel_id, id)? {
+            Some(data) => data,
+            None => failure::bail!("comment not found"),
+        };
+        let entity = self.database.update_comment(CommentEntity {
+            message,
+            updated_at: Utc::now(),
+            ..entity
+        })?;
+        self.events
+            .publish(ChatEvent::CommentEdited(entity.clone()))?;
+        Ok(entity)
+    }
+
+    /// Removes the comment of the `id`, and returns the removed one.
+    pub fn delete_comment(
+        &self,
+        channel_id: &ChannelID,
+        id: &CommentID,
+    ) -> Fallible<CommentEntity> {
+        let entity = self.database.remove_comment(channel_id, id)?;
+        self.events
+            .publish(ChatEvent::CommentDeleted(entity.clone()))?;
+        Ok(entity)
+    }
+
+    /// Publishes the `presence` that is not saved to the storage.
+    pub fn publish_presence(&self, presence: ChannelPresence) -> Fallible<()> {
+        self.events.publish(ChatEvent::PresenceChanged(presence))
+    }
+
+    /// Returns the stream of the events that match the `filter` and are published after this
+    /// call.
+    pub fn subscribe(&self, filter: EventFilter) -> Fallible<UnboundedReceiver<ChatEvent>> {
+        self.events.subscribe(filter)
+    }
+}
+
+/// Opens the `ChatStore` of the storage without the archive. The files are encrypted with the
+/// `cipher` if it is given.
+pub fn open_chat_store(
+    storage: &StorageType,
+    database_dir: Option<PathBuf>,
+    cipher: Option<DatabaseCipher>,
+) -> Fallible<Box<dyn ChatStore>> {
+    ensure_cipher_supported(storage, cipher.as_ref())?;
+
+    Ok(match storage {
+        StorageType::Toml => {
+            let log_path = crate::util::get_database_log_file_path(database_dir.clone());
+            if wal_chat_database::has_pending_records(&log_path)? {
+                failure::bail!(
+                    "{:?} has records that are not in the snapshot. use the wal storage or \
+                     run the migration to merge them",
+                    log_path
+                );
+            }
+
+            Box::new(DevFlexChatDatabase::create(
+                crate::util::get_database_file_path(database_dir),
+                cipher,
+            )?)
+        }
+        StorageType::Wal => Box::new(WalChatDatabase::create(
+            crate::util::get_database_file_path(database_dir.clone()),
+            crate::util::get_database_log_file_path(database_dir),
+            cipher,
+        )?),
+        StorageType::Sqlite => Box::new(SqliteChatDatabase::create(
+            crate::util::get_database_sqlite_file_path(database_dir),
+        )?),
+        StorageType::Memory => Box::new(InMemoryChatDatabase::new()),
+    })
+}
+
+/// Fails if the `storage` does not support the encryption and the `cipher` is given. The SQLite
+/// storage is rejected because the `rusqlite` writes the database file as plaintext and the
+/// `DatabaseCipher` has no way to encrypt it. The memory storage has no file to encrypt, so that
+/// the `cipher` is ignored.
+pub fn ensure_cipher_supported(
+    storage: &StorageType,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    match (storage, cipher) {
+        (StorageType::Sqlite, Some(_)) => {
+            failure::bail!("encryption is supported for the toml and wal storage")
+        }
+        _ => Ok(()),
     }
 }
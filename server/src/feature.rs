@@ -14,5 +14,13 @@
  * limitations under the License.
  */
 
+pub mod backup;
+pub mod check;
 pub(crate) mod dev_flex_chat;
+pub mod export;
+pub(crate) mod metrics;
+pub mod migrate_storage;
 pub mod migration;
+pub mod rekey;
+pub mod retention;
+pub(crate) mod webhook;
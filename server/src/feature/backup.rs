@@ -0,0 +1,405 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+
+use crate::data::db::comment_archive::CommentArchive;
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::db::dev_flex_chat_database::{
+    convert_to_version, generate_latest_database_version, DevFlexChatTable,
+};
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelID, CommentEntity};
+use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::model::storage_type::StorageType;
+use crate::prelude::*;
+
+/// Contents of the archive of the `backup`.
+///
+/// The `table` is flattened so that the archive is a `database.toml` with the archived comments
+/// and the `retention.toml`, and the archives of the previous versions can be restored.
+#[derive(Deserialize, Serialize)]
+struct BackupArchive {
+    /// Contents of the `retention.toml` if it exists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<String>,
+
+    #[serde(flatten)]
+    table: DevFlexChatTable,
+
+    /// Comments that are moved to the `CommentArchive` by the retention.
+    #[serde(
+        default,
+        rename = "archived-comments",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    archived_comments: Vec<CommentEntity>,
+}
+
+/// Writes a snapshot of the database, the archived comments and the `retention.toml` to the
+/// `out`.
+///
+/// The archive is a `database.toml` that has the version-code of the database. This does not
+/// take the `DatabaseLock` so that the running server can be backed up. The archive is encrypted
+/// with the `cipher` of the database.
+///
+/// The retention of the running server writes the comments to the `CommentArchive` before it
+/// removes them from the database, so that a comment that is moved while reading is in both the
+/// snapshot and the archive. Such comments are taken from the snapshot so that the backup is the
+/// database at the time of reading the snapshot.
+pub fn backup(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    out: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let table = DevFlexChatRepository::read_snapshot(&storage, database_dir.clone(), cipher)?;
+
+    let database_version = convert_to_version(table.version_code);
+    let latest_version = generate_latest_database_version()?;
+    if database_version != latest_version {
+        failure::bail!(
+            "need to update database. current: {:?}, expect: {:?}",
+            database_version,
+            latest_version
+        );
+    }
+
+    let comment_archive = CommentArchive::new(
+        crate::util::get_archive_dir_path(database_dir.clone()),
+        cipher.cloned(),
+    );
+    let comment_ids = table
+        .comments
+        .iter()
+        .map(|data| &data.id)
+        .collect::<HashSet<_>>();
+    let mut archived_comments = vec![];
+    for channel in &table.channels {
+        archived_comments.extend(
+            comment_archive
+                .retrieve(&channel.id)?
+                .into_iter()
+                .filter(|data| !comment_ids.contains(&data.id)),
+        );
+    }
+
+    let retention_path = crate::util::get_retention_file_path(database_dir);
+    let retention = if retention_path.exists() {
+        Some(std::fs::read_to_string(&retention_path)?)
+    } else {
+        None
+    };
+
+    info!(
+        "write {} channels, {} comments and {} archived comments",
+        table.channels.len(),
+        table.comments.len(),
+        archived_comments.len()
+    );
+    let archive = BackupArchive {
+        retention,
+        table,
+        archived_comments,
+    };
+    database_cipher::write_file_atomically(out, &toml::to_vec(&archive)?, cipher)?;
+
+    info!("succeeded to back up to {:?}", out);
+    Ok(())
+}
+
+/// Replaces the database and the archived comments with the `archive` that is created by the
+/// `backup`, and writes the `retention.toml` if the archive has it. The `cipher` is used for the
+/// archive, the database and the archived comments.
+///
+/// The archived comments are written to a temporary directory first and replace the previous
+/// ones by renaming, so that a failure does not leave a part of them.
+pub fn restore(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    archive: &Path,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let BackupArchive {
+        retention,
+        table,
+        archived_comments,
+    } = read_archive(archive, cipher)?;
+
+    let archive_version = convert_to_version(table.version_code);
+    let latest_version = generate_latest_database_version()?;
+    if archive_version != latest_version {
+        failure::bail!(
+            "unsupported version of the archive. archive: {:?}, expect: {:?}",
+            archive_version,
+            latest_version
+        );
+    }
+
+    let _database_lock = DatabaseLock::try_acquire(database_dir.clone())?;
+
+    info!(
+        "restore {} channels, {} comments and {} archived comments",
+        table.channels.len(),
+        table.comments.len(),
+        archived_comments.len()
+    );
+    let archive_dir = crate::util::get_archive_dir_path(database_dir.clone());
+    let staging_dir = crate::util::get_temporary_file_path(&archive_dir)?;
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+    std::fs::create_dir_all(&staging_dir)?;
+    let mut channel_comments = HashMap::<ChannelID, Vec<CommentEntity>>::new();
+    for comment in archived_comments {
+        channel_comments
+            .entry(comment.channel_id.clone())
+            .or_default()
+            .push(comment);
+    }
+    let comment_archive = CommentArchive::new(&staging_dir, cipher.cloned());
+    for (channel_id, comments) in channel_comments {
+        comment_archive.append(&channel_id, &comments)?;
+    }
+
+    DevFlexChatRepository::write_snapshot(&storage, database_dir.clone(), &table, cipher)?;
+
+    // the archives of the previous database do not belong to the restored channels.
+    let previous_dir = crate::util::get_backup_file_path(&archive_dir)?;
+    if previous_dir.exists() {
+        std::fs::remove_dir_all(&previous_dir)?;
+    }
+    if archive_dir.exists() {
+        std::fs::rename(&archive_dir, &previous_dir)?;
+    }
+    std::fs::rename(&staging_dir, &archive_dir)?;
+    if previous_dir.exists() {
+        std::fs::remove_dir_all(&previous_dir)?;
+    }
+
+    if let Some(retention) = retention {
+        crate::util::write_file_atomically(
+            crate::util::get_retention_file_path(database_dir),
+            retention.as_bytes(),
+        )?;
+    }
+
+    info!("succeeded to restore from {:?}", archive);
+    Ok(())
+}
+
+fn read_archive(archive: &Path, cipher: Option<&DatabaseCipher>) -> Fallible<BackupArchive> {
+    let data = database_cipher::read_file_to_string(archive, cipher)?;
+    Ok(toml::from_str(&data)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::data::db::chat_store::ChatStore;
+    use crate::data::db::entity::dev_flex_chat_entity::{
+        ChannelEntity, ChannelID, CommentEntity, CommentID,
+    };
+    use crate::data::db::sqlite_chat_database::SqliteChatDatabase;
+    use crate::data::db::wal_chat_database::WalChatDatabase;
+
+    use super::*;
+
+    #[test]
+    fn test_backup_and_restore() -> Fallible<()> {
+        let source_dir = tempfile::tempdir()?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let db = WalChatDatabase::create(
+            source_dir.path().join("database.toml"),
+            source_dir.path().join("database.log"),
+            None,
+        )?;
+        db.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        db.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "message".into(),
+            sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        // the database is still open.
+        let archive = source_dir.path().join("backup.toml");
+        backup(
+            Some(source_dir.path().to_owned()),
+            StorageType::Wal,
+            &archive,
+            None,
+        )?;
+
+        let target_dir = tempfile::tempdir()?;
+        restore(
+            Some(target_dir.path().to_owned()),
+            StorageType::Sqlite,
+            &archive,
+            None,
+        )?;
+
+        let db = SqliteChatDatabase::create(target_dir.path().join("database.sqlite"))?;
+        let comments = db.retrieve_comments(&channel_id)?;
+        assert_eq!(comments.len(), 1);
+        assert_eq!(comments[0].sequence, 1);
+        assert_eq!(db.find_channel(&channel_id)?.ok_or_err()?.last_sequence, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_and_restore_archived_comments() -> Fallible<()> {
+        let source_dir = tempfile::tempdir()?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let repo = DevFlexChatRepository::prepare(
+            &StorageType::Wal,
+            Some(source_dir.path().into()),
+            None,
+        )?;
+        repo.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        for _ in 0..3 {
+            repo.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: "message".into(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+        assert_eq!(repo.archive_comments_until(&channel_id, 2)?, 2);
+        let retention = "[default]\nmax-count = 1\n";
+        std::fs::write(source_dir.path().join("retention.toml"), retention)?;
+
+        let archive = source_dir.path().join("backup.toml");
+        backup(
+            Some(source_dir.path().to_owned()),
+            StorageType::Wal,
+            &archive,
+            None,
+        )?;
+
+        let target_dir = tempfile::tempdir()?;
+        restore(
+            Some(target_dir.path().to_owned()),
+            StorageType::Sqlite,
+            &archive,
+            None,
+        )?;
+
+        let repo = DevFlexChatRepository::prepare(
+            &StorageType::Sqlite,
+            Some(target_dir.path().into()),
+            None,
+        )?;
+        let sequences = |comments: Vec<CommentEntity>| {
+            comments
+                .iter()
+                .map(|data| data.sequence)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            sequences(repo.archived_comments(&channel_id, 0, None)?),
+            vec![1, 2]
+        );
+        assert_eq!(sequences(repo.comments(&channel_id)?), vec![3]);
+        assert_eq!(
+            std::fs::read_to_string(target_dir.path().join("retention.toml"))?,
+            retention
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_backup_interrupted_archival() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let repo =
+            DevFlexChatRepository::prepare(&StorageType::Wal, Some(dir.path().into()), None)?;
+        repo.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        let comment = repo.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "message".into(),
+            sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        // archived but not removed yet.
+        CommentArchive::new(dir.path().join("archives"), None).append(&channel_id, &[comment])?;
+
+        let archive = dir.path().join("backup.toml");
+        backup(
+            Some(dir.path().to_owned()),
+            StorageType::Wal,
+            &archive,
+            None,
+        )?;
+
+        let archive = read_archive(&archive, None)?;
+        assert_eq!(archive.table.comments.len(), 1);
+        assert!(archive.archived_comments.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_unsupported_version() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let archive = dir.path().join("backup.toml");
+        let mut table = DevFlexChatTable::new()?;
+        table.version_code -= 1;
+        std::fs::write(&archive, toml::to_vec(&table)?)?;
+
+        assert!(restore(
+            Some(dir.path().to_owned()),
+            StorageType::Toml,
+            &archive,
+            None
+        )
+        .is_err());
+        assert!(!dir.path().join("database.toml").exists());
+        Ok(())
+    }
+}
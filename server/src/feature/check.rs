@@ -0,0 +1,434 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_derive::Serialize;
+
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::db::dev_flex_chat_database::{
+    convert_to_version, generate_latest_database_version,
+};
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, CommentEntity};
+use crate::data::db::{sqlite_chat_database, wal_chat_database};
+use crate::data::repository::dev_flex_chat_repository::ensure_cipher_supported;
+use crate::model::storage_type::StorageType;
+use crate::prelude::*;
+
+/// Entry of the `database.toml` that has a problem.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Target {
+    /// The whole database. Cannot be repaired.
+    Database,
+
+    /// Index of the `channels`.
+    Channel(usize),
+
+    /// Index of the `comments`.
+    Comment(usize),
+}
+
+struct Problem {
+    target: Target,
+    reason: String,
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.target {
+            Target::Database => write!(f, "database: {}", self.reason),
+            Target::Channel(index) => write!(f, "channels[{}]: {}", index, self.reason),
+            Target::Comment(index) => write!(f, "comments[{}]: {}", index, self.reason),
+        }
+    }
+}
+
+/// A line of the `database.quarantine`.
+#[derive(Serialize)]
+struct QuarantineRecord<'a> {
+    #[serde(rename = "quarantined-at")]
+    quarantined_at: DateTime<Utc>,
+    kind: &'static str,
+    reasons: &'a [String],
+    entry: &'a toml::Value,
+}
+
+/// Validates the database and prints the problems.
+///
+/// Fails if a problem is found. With the `repair`, the entries that have a problem are moved to
+/// the `database.quarantine` instead, and only the problems of the whole database such as an
+/// unexpected version-code fail. The quarantined entries are encrypted with the `cipher` as well
+/// as the database.
+pub fn check(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    repair: bool,
+    cipher: Option<DatabaseCipher>,
+) -> Fallible<()> {
+    ensure_cipher_supported(&storage, cipher.as_ref())?;
+
+    match storage {
+        StorageType::Toml | StorageType::Wal => check_toml(database_dir, repair, cipher.as_ref()),
+        StorageType::Sqlite => {
+            if repair {
+                failure::bail!("repair is not supported for the sqlite storage");
+            }
+
+            let problems = sqlite_chat_database::check(
+                &crate::util::get_database_sqlite_file_path(database_dir),
+            )?;
+            for problem in &problems {
+                println!("{}", problem);
+            }
+            if !problems.is_empty() {
+                failure::bail!("found {} problems", problems.len());
+            }
+
+            println!("no problems found");
+            Ok(())
+        }
+        StorageType::Memory => failure::bail!("memory storage has nothing to check"),
+    }
+}
+
+fn check_toml(
+    database_dir: Option<PathBuf>,
+    repair: bool,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    // the server must not write the database during the repair.
+    let _database_lock = if repair {
+        Some(DatabaseLock::try_acquire(database_dir.clone())?)
+    } else {
+        None
+    };
+
+    let database_path = crate::util::get_database_file_path(database_dir.clone());
+    let log_path = crate::util::get_database_log_file_path(database_dir.clone());
+    let mut value = match wal_chat_database::read_value_with_log(&database_path, &log_path, cipher)
+    {
+        Ok(data) => data,
+        Err(e) => failure::bail!("failed to read {:?}: {}", database_path, e),
+    };
+
+    let problems = find_problems(&value)?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    if problems.is_empty() {
+        println!("no problems found");
+        return Ok(());
+    }
+
+    if !repair {
+        failure::bail!(
+            "found {} problems. the entries can be quarantined by the repair",
+            problems.len()
+        );
+    }
+
+    if problems.iter().any(|data| data.target == Target::Database) {
+        failure::bail!("cannot repair the problems of the database");
+    }
+
+    let mut reasons = BTreeMap::<Target, Vec<String>>::new();
+    for problem in problems {
+        reasons
+            .entry(problem.target)
+            .or_default()
+            .push(problem.reason);
+    }
+
+    info!("quarantine {} entries", reasons.len());
+    let quarantine_path = crate::util::get_database_quarantine_file_path(database_dir);
+    let mut quarantine = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&quarantine_path)?;
+    let quarantined_at = Utc::now();
+    for (target, reasons) in &reasons {
+        let (kind, key, index) = match target {
+            Target::Channel(index) => ("channel", "channels", *index),
+            Target::Comment(index) => ("comment", "comments", *index),
+            Target::Database => unreachable!(),
+        };
+        let record = serde_json::to_vec(&QuarantineRecord {
+            quarantined_at,
+            kind,
+            reasons,
+            entry: &value[key][index],
+        })?;
+        let mut line = database_cipher::encode_line(&record, cipher)?;
+        line.push(b'\n');
+        quarantine.write_all(&line)?;
+    }
+    // the entries must not be lost if the following write is interrupted.
+    quarantine.sync_data()?;
+
+    for (key, kind) in &[("channels", "channel"), ("comments", "comment")] {
+        let indexes = reasons
+            .keys()
+            .filter_map(|target| match target {
+                Target::Channel(index) if *kind == "channel" => Some(*index),
+                Target::Comment(index) if *kind == "comment" => Some(*index),
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+        let mut index = 0;
+        value[*key].as_array_mut().ok_or_err()?.retain(|_| {
+            let quarantined = indexes.contains(&index);
+            index += 1;
+            !quarantined
+        });
+    }
+
+    database_cipher::write_file_atomically(
+        &database_path,
+        toml::to_string(&value)?.as_bytes(),
+        cipher,
+    )?;
+    if log_path.exists() {
+        std::fs::remove_file(&log_path)?;
+    }
+
+    println!(
+        "quarantined {} entries to {:?}",
+        reasons.len(),
+        quarantine_path
+    );
+    Ok(())
+}
+
+fn find_problems(value: &toml::Value) -> Fallible<Vec<Problem>> {
+    let mut problems = vec![];
+
+    let latest_version = generate_latest_database_version()?;
+    match value.get("version-code").and_then(|data| data.as_integer()) {
+        Some(version_code) => {
+            let version = convert_to_version(version_code as u64);
+            if version != latest_version {
+                problems.push(Problem {
+                    target: Target::Database,
+                    reason: format!(
+                        "need to update database. current: {:?}, expect: {:?}",
+                        version, latest_version
+                    ),
+                });
+            }
+        }
+        None => problems.push(Problem {
+            target: Target::Database,
+            reason: "version-code not found".into(),
+        }),
+    }
+
+    let channels = value.get("channels").and_then(|data| data.as_array());
+    let comments = value.get("comments").and_then(|data| data.as_array());
+    let (channels, comments) = match (channels, comments) {
+        (Some(channels), Some(comments)) if problems.is_empty() => (channels, comments),
+        (channels, comments) => {
+            if channels.is_none() {
+                problems.push(Problem {
+                    target: Target::Database,
+                    reason: "channels not found".into(),
+                });
+            }
+            if comments.is_none() {
+                problems.push(Problem {
+                    target: Target::Database,
+                    reason: "comments not found".into(),
+                });
+            }
+            return Ok(problems);
+        }
+    };
+
+    let mut channel_ids = HashSet::new();
+    for (index, entry) in channels.iter().enumerate() {
+        match entry.clone().try_into::<ChannelEntity>() {
+            Ok(entity) => {
+                if !channel_ids.insert(entity.id.clone()) {
+                    problems.push(Problem {
+                        target: Target::Channel(index),
+                        reason: format!("duplicate id: {}", entity.id.0),
+                    });
+                }
+            }
+            Err(e) => problems.push(Problem {
+                target: Target::Channel(index),
+                reason: format!("malformed: {}", e),
+            }),
+        }
+    }
+
+    let mut comment_ids = HashSet::new();
+    let mut last_sequences = HashMap::new();
+    for (index, entry) in comments.iter().enumerate() {
+        let entity = match entry.clone().try_into::<CommentEntity>() {
+            Ok(data) => data,
+            Err(e) => {
+                problems.push(Problem {
+                    target: Target::Comment(index),
+                    reason: format!("malformed: {}", e),
+                });
+                continue;
+            }
+        };
+
+        if !comment_ids.insert(entity.id.clone()) {
+            problems.push(Problem {
+                target: Target::Comment(index),
+                reason: format!("duplicate id: {}", entity.id.0),
+            });
+            continue;
+        }
+
+        if !channel_ids.contains(&entity.channel_id) {
+            problems.push(Problem {
+                target: Target::Comment(index),
+                reason: format!("channel not found: {}", entity.channel_id.0),
+            });
+            continue;
+        }
+
+        let last_sequence = last_sequences.entry(entity.channel_id).or_insert(0);
+        if entity.sequence <= *last_sequence {
+            problems.push(Problem {
+                target: Target::Comment(index),
+                reason: format!(
+                    "sequence must be greater than {}: {}",
+                    last_sequence, entity.sequence
+                ),
+            });
+            continue;
+        }
+        *last_sequence = entity.sequence;
+    }
+
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+
+    use super::*;
+
+    fn prepare_database(dir: &Path) -> Fallible<()> {
+        let (version, flush_code) = generate_latest_database_version()?;
+        let version_code =
+            crate::data::db::dev_flex_chat_database::convert_to_version_code(&version, flush_code);
+        let channel_id = uuid::Uuid::new_v4();
+        let comment = |id: uuid::Uuid, channel_id: uuid::Uuid, sequence: u64| {
+            format!(
+                r#"
+[[comments]]
+id = "{}"
+channel-id = "{}"
+name = "name"
+message = "message"
+sequence = {}
+created-at = "2020-04-01T00:00:00Z"
+updated-at = "2020-04-01T00:00:00Z"
+"#,
+                id, channel_id, sequence
+            )
+        };
+        let duplicate_comment_id = uuid::Uuid::new_v4();
+        let data = format!(
+            r#"version-code = {}
+{}{}{}{}
+[[comments]]
+id = "{}"
+channel-id = "{}"
+message = "malformed"
+
+[[channels]]
+id = "{}"
+name = "General"
+last-sequence = 2
+created-at = "2020-04-01T00:00:00Z"
+updated-at = "2020-04-01T00:00:00Z"
+
+[[channels]]
+id = "{}"
+name = "Duplicate"
+last-sequence = 0
+created-at = "2020-04-01T00:00:00Z"
+updated-at = "2020-04-01T00:00:00Z"
+"#,
+            version_code,
+            comment(duplicate_comment_id, channel_id, 1),
+            comment(duplicate_comment_id, channel_id, 2),
+            comment(uuid::Uuid::new_v4(), channel_id, 2),
+            comment(uuid::Uuid::new_v4(), uuid::Uuid::new_v4(), 1),
+            uuid::Uuid::new_v4(),
+            channel_id,
+            channel_id,
+            channel_id,
+        );
+        std::fs::write(dir.join("database.toml"), data)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_repair() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        prepare_database(dir.path())?;
+        let database_dir = Some(dir.path().to_owned());
+
+        let value = wal_chat_database::read_value_with_log(
+            &dir.path().join("database.toml"),
+            &dir.path().join("database.log"),
+            None,
+        )?;
+        let targets = find_problems(&value)?
+            .into_iter()
+            .map(|data| data.target)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            targets,
+            vec![
+                Target::Channel(1),
+                Target::Comment(1),
+                Target::Comment(3),
+                Target::Comment(4)
+            ]
+        );
+
+        assert!(check(database_dir.clone(), StorageType::Toml, false, None).is_err());
+        check(database_dir.clone(), StorageType::Toml, true, None)?;
+        check(database_dir.clone(), StorageType::Toml, false, None)?;
+
+        let quarantine = std::fs::read_to_string(dir.path().join("database.quarantine"))?;
+        assert_eq!(quarantine.lines().count(), 4);
+
+        let repo = DevFlexChatRepository::prepare(&StorageType::Toml, database_dir, None)?;
+        let channels = repo.channels()?;
+        assert_eq!(channels.len(), 1);
+        assert_eq!(repo.comments(&channels[0].id)?.len(), 2);
+        Ok(())
+    }
+}
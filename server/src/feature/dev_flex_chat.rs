@@ -14,8 +14,12 @@
  * limitations under the License.
  */
 
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
+use futures::{Future, Stream};
 use juniper::{graphql_value, FieldError, FieldResult, GraphQLInputObject, GraphQLObject, ID};
 use log::warn;
 use uuid::Uuid;
@@ -23,12 +27,27 @@ use uuid::Uuid;
 use crate::data::db::entity::dev_flex_chat_entity::{
     ChannelEntity, ChannelID, CommentEntity, CommentID,
 };
+use crate::data::event_bus::{ChatEvent, EventFilter};
 use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
 use crate::model::juniper_object::{Context, OrderDirection};
+use crate::prelude::*;
 
 pub struct Channel {
     pub id: ChannelID,
     pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl From<ChannelEntity> for Channel {
+    fn from(entity: ChannelEntity) -> Self {
+        Self {
+            id: entity.id,
+            name: entity.name,
+            created_at: entity.created_at,
+            updated_at: entity.updated_at,
+        }
+    }
 }
 
 #[derive(Debug, juniper::GraphQLInputObject)]
@@ -40,6 +59,8 @@ pub struct ChannelInput {
 pub struct ChannelResponse {
     pub id: ID,
     pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
 }
 
 #[derive(Debug, juniper::GraphQLInputObject)]
@@ -57,6 +78,25 @@ pub struct Comment {
     pub id: ID,
     pub name: String,
     pub message: String,
+    /// Monotonically increasing number in the channel, to use as the cursor.
+    pub sequence: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl TryFrom<CommentEntity> for Comment {
+    type Error = FieldError;
+
+    fn try_from(entity: CommentEntity) -> FieldResult<Self> {
+        Ok(Self {
+            id: entity.id.0.to_string().into(),
+            name: entity.name,
+            message: entity.message,
+            sequence: convert_from_sequence(entity.sequence)?,
+            created_at: entity.created_at,
+            updated_at: entity.updated_at,
+        })
+    }
 }
 
 #[derive(GraphQLInputObject)]
@@ -71,6 +111,15 @@ pub struct CommentResponse {
     pub id: ID,
     pub name: String,
     pub message: String,
+    pub sequence: i32,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(GraphQLObject)]
+pub struct PresenceResponse {
+    pub typing_users: Vec<String>,
+    pub active_users: Vec<String>,
 }
 
 #[juniper::object(Context = Context)]
@@ -83,10 +132,41 @@ impl Channel {
         self.name.to_owned()
     }
 
+    fn created_at(&self) -> DateTime<Utc> {
+        self.created_at
+    }
+
+    fn updated_at(&self) -> DateTime<Utc> {
+        self.updated_at
+    }
+
+    /// Returns the names of the users who are typing in the channel.
+    fn typing_users(&self, context: &Context) -> FieldResult<Vec<String>> {
+        context.presence.typing_users(&self.id).map_err(|e| {
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to retrieve typing users"}),
+            )
+        })
+    }
+
+    /// Returns the names of the users who sent the heartbeat or typed in the channel recently.
+    fn active_users(&self, context: &Context) -> FieldResult<Vec<String>> {
+        context.presence.active_users(&self.id).map_err(|e| {
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to retrieve active users"}),
+            )
+        })
+    }
+
+    /// Returns the last `first` comments, or the `first` comments that follow the `after`
+    /// sequence.
     fn comments(
         &self,
         context: &Context,
         first: i32,
+        after: Option<i32>,
         order_by: CommentOrder,
     ) -> FieldResult<Vec<Comment>> {
         let first = first.try_into().map_err(|e| {
@@ -95,110 +175,248 @@ impl Channel {
                 graphql_value!({"internal_error": "failed to convert number"}),
             )
         })?;
-        Ok(context
+        let ret = match after {
+            Some(after) => context.chat_repo.retrieve_after(
+                &self.id,
+                convert_to_sequence(after)?,
+                Some(first),
+                &order_by.direction,
+            ),
+            None => context
+                .chat_repo
+                .retrieve_first(&self.id, first, &order_by.direction),
+        };
+        ret.map_err(|e| {
+            warn!("failed repo.retrieve_first: {:?}", e);
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to retrieve data"}),
+            )
+        })?
+        .into_iter()
+        .map(Comment::try_from)
+        .collect::<FieldResult<Vec<_>>>()
+    }
+
+    /// Returns the `first` comments that are moved to the archive by the retention, following the
+    /// `after` sequence.
+    fn archived_comments(
+        &self,
+        context: &Context,
+        first: i32,
+        after: Option<i32>,
+    ) -> FieldResult<Vec<Comment>> {
+        let first = first.try_into().map_err(|e| {
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to convert number"}),
+            )
+        })?;
+        let after = match after {
+            Some(data) => convert_to_sequence(data)?,
+            None => 0,
+        };
+        context
             .chat_repo
-            .retrieve_first(&self.id, first, &order_by.direction)
+            .archived_comments(&self.id, after, Some(first))
             .map_err(|e| {
-                warn!("failed repo.retrieve_first: {:?}", e);
+                warn!("failed repo.archived_comments: {:?}", e);
                 FieldError::new(
                     e,
                     graphql_value!({"internal_error": "failed to retrieve data"}),
                 )
             })?
             .into_iter()
-            .map(|entity| Comment {
-                id: entity.id.0.to_string().into(),
-                name: entity.name,
-                message: entity.message,
-            })
-            .collect::<Vec<_>>())
+            .map(Comment::try_from)
+            .collect::<FieldResult<Vec<_>>>()
     }
 
+    /// Returns the comments that follow the `after` sequence, or the comment of the `id`. Returns
+    /// the comment that is saved while waiting if both are `None`. Waits for a new comment if there
+    /// is nothing to return, and returns an empty list after the `timeout_seconds`.
     fn comments_long_polling(
         &self,
         context: &Context,
         id: Option<ID>,
+        after: Option<i32>,
         order_by: CommentOrder,
+        timeout_seconds: Option<i32>,
     ) -> FieldResult<Vec<Comment>> {
-        match id {
-            Some(id) => {
-                let id = convert_id_to_uuid(&id)?;
-                Ok(context
-                    .chat_repo
-                    .retrieve_after_long_polling(&self.id, &CommentID(id), &order_by.direction)
-                    .map_err(|e| {
-                        FieldError::new(
-                            e,
-                            graphql_value!({"internal_error": "failed to long polling"}),
-                        )
-                    })?
-                    .into_iter()
-                    .map(|entity| Comment {
-                        id: entity.id.0.to_string().into(),
-                        name: entity.name,
-                        message: entity.message,
-                    })
-                    .collect::<Vec<_>>())
-                .map_err(|e| {
-                    warn!("failed to long polling comment: {:?}", e);
-                    e
-                })
-            }
-            None => Ok(vec![context
-                .chat_repo
-                .long_polling(&self.id)
-                .map_err(|e| {
+        let timeout = long_polling_timeout(context, timeout_seconds)?;
+        let after = match (after, id) {
+            (Some(after), _) => Some(convert_to_sequence(after)?),
+            (None, Some(id)) => {
+                let id = CommentID(convert_id_to_uuid(&id)?);
+                match context.chat_repo.find_comment(&self.id, &id).map_err(|e| {
                     FieldError::new(
                         e,
-                        graphql_value!({"internal_error": "failed to long polling"}),
+                        graphql_value!({"internal_error": "failed to find comment"}),
                     )
+                })? {
+                    Some(data) => Some(data.sequence),
+                    None => {
+                        return Err(FieldError::new(
+                            "id not found",
+                            graphql_value!({"internal_error": "id not found"}),
+                        ))
+                    }
+                }
+            }
+            (None, None) => None,
+        };
+
+        let long_polling_error = |e: failure::Error| {
+            warn!("failed to long polling comment: {:?}", e);
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to long polling"}),
+            )
+        };
+
+        // subscribe before the retrieval so that the comment between them is received.
+        let events = context
+            .chat_repo
+            .subscribe(EventFilter::Channel(self.id.clone()))
+            .map_err(long_polling_error)?;
+        let comments = match after {
+            Some(after) => context
+                .chat_repo
+                .retrieve_after(&self.id, after, None, &order_by.direction)
+                .map_err(long_polling_error)?,
+            None => context
+                .received_events()
+                .iter()
+                .filter_map(|event| match event {
+                    ChatEvent::CommentPosted(data) if data.channel_id == self.id => {
+                        Some(data.clone())
+                    }
+                    _ => None,
                 })
-                .map(|entity| Comment {
-                    id: entity.id.0.to_string().into(),
-                    name: entity.name,
-                    message: entity.message,
-                })?]),
+                .take(1)
+                .collect(),
+        };
+
+        if comments.is_empty() && !context.timed_out() {
+            context
+                .wait_for(
+                    next_event(events, |event| match event {
+                        ChatEvent::CommentPosted(_) => Some(event),
+                        _ => None,
+                    }),
+                    timeout,
+                )
+                .map_err(long_polling_error)?;
+        }
+
+        comments
+            .into_iter()
+            .map(Comment::try_from)
+            .collect::<FieldResult<Vec<_>>>()
+    }
+
+    /// Returns the typing and active users of the channel after they change. Waits for a change,
+    /// and returns the current users after the `timeout_seconds`.
+    fn presence_long_polling(
+        &self,
+        context: &Context,
+        timeout_seconds: Option<i32>,
+    ) -> FieldResult<PresenceResponse> {
+        let timeout = long_polling_timeout(context, timeout_seconds)?;
+
+        let long_polling_error = |e: failure::Error| {
+            warn!("failed to long polling presence: {:?}", e);
+            FieldError::new(
+                e,
+                graphql_value!({"internal_error": "failed to long polling"}),
+            )
+        };
+
+        let changed = context.received_events().iter().any(|event| match event {
+            ChatEvent::PresenceChanged(data) => data.channel_id == self.id,
+            _ => false,
+        });
+        if !changed && !context.timed_out() {
+            let events = context
+                .chat_repo
+                .subscribe(EventFilter::Channel(self.id.clone()))
+                .map_err(long_polling_error)?;
+            context
+                .wait_for(
+                    next_event(events, |event| match event {
+                        ChatEvent::PresenceChanged(_) => Some(event),
+                        _ => None,
+                    }),
+                    timeout,
+                )
+                .map_err(long_polling_error)?;
         }
+
+        Ok(PresenceResponse {
+            typing_users: context
+                .presence
+                .typing_users(&self.id)
+                .map_err(long_polling_error)?,
+            active_users: context
+                .presence
+                .active_users(&self.id)
+                .map_err(long_polling_error)?,
+        })
     }
 }
 
+/// Returns the channels that are created after the channel of the `id`, or the channel that is
+/// created while waiting if the `id` is `None`. Waits for a new channel if there is nothing to
+/// return, and returns an empty list after the `timeout_seconds`.
 pub fn channel_long_polling(
-    repo: &DevFlexChatRepository,
+    context: &Context,
     id: Option<ID>,
-    order_by: ChannelOrder,
+    _order_by: ChannelOrder,
+    timeout_seconds: Option<i32>,
 ) -> FieldResult<Vec<Channel>> {
-    match id {
-        Some(id) => Ok(repo
-            .retrieve_channel_after_long_polling(
-                ChannelID::from(convert_id_to_uuid(&id)?),
-                &order_by.direction,
-            )
-            .map_err(|e| {
-                FieldError::new(
-                    e,
-                    graphql_value!({"internal_error": "failed channel long polling"}),
-                )
-            })?
-            .into_iter()
-            .map(|entity| Channel {
-                id: entity.id,
-                name: entity.name,
-            })
-            .collect::<Vec<_>>()),
-        None => Ok(vec![repo
-            .channel_long_polling()
-            .map_err(|e| {
-                FieldError::new(
-                    e,
-                    graphql_value!({
-                "internal_error": "failed long polling for channel"}),
-                )
+    // TODO: use order.
+    let timeout = long_polling_timeout(context, timeout_seconds)?;
+
+    let long_polling_error = |e: failure::Error| {
+        FieldError::new(
+            e,
+            graphql_value!({"internal_error": "failed channel long polling"}),
+        )
+    };
+
+    // subscribe before the retrieval so that the channel between them is received.
+    let events = context
+        .chat_repo
+        .subscribe(EventFilter::All)
+        .map_err(long_polling_error)?;
+    let channels = match id {
+        Some(id) => context
+            .chat_repo
+            .channels_after(ChannelID::from(convert_id_to_uuid(&id)?))
+            .map_err(long_polling_error)?,
+        None => context
+            .received_events()
+            .iter()
+            .filter_map(|event| match event {
+                ChatEvent::ChannelCreated(data) => Some(data.clone()),
+                _ => None,
             })
-            .map(|entity| Channel {
-                id: entity.id,
-                name: entity.name,
-            })?]),
+            .take(1)
+            .collect(),
+    };
+
+    if channels.is_empty() && !context.timed_out() {
+        context
+            .wait_for(
+                next_event(events, |event| match event {
+                    ChatEvent::ChannelCreated(_) => Some(event),
+                    _ => None,
+                }),
+                timeout,
+            )
+            .map_err(long_polling_error)?;
     }
+
+    Ok(channels.into_iter().map(Channel::from).collect::<Vec<_>>())
 }
 
 pub fn channel(repo: &DevFlexChatRepository, id: ID) -> FieldResult<Option<Channel>> {
@@ -217,10 +435,7 @@ pub fn channel(repo: &DevFlexChatRepository, id: ID) -> FieldResult<Option<Chann
                 graphql_value!({"internal_error": "failed to find channel"}),
             )
         })?
-        .map(|data| Channel {
-            id,
-            name: data.name,
-        }))
+        .map(Channel::from))
 }
 
 pub fn channels(repo: &DevFlexChatRepository) -> FieldResult<Vec<Channel>> {
@@ -234,10 +449,7 @@ pub fn channels(repo: &DevFlexChatRepository) -> FieldResult<Vec<Channel>> {
             )
         })?
         .into_iter()
-        .map(|entity| Channel {
-            id: entity.id,
-            name: entity.name,
-        })
+        .map(Channel::from)
         .collect::<Vec<_>>())
 }
 
@@ -245,16 +457,26 @@ pub fn add_channel(
     repo: &DevFlexChatRepository,
     channel: ChannelInput,
 ) -> FieldResult<ChannelResponse> {
-    let id = uuid::Uuid::new_v4();
-    // TODO: check conflict of a uuid.
+    let id = loop {
+        let id = uuid::Uuid::new_v4();
+        if repo.find_channel(ChannelID(id))?.is_none() {
+            break id;
+        }
+    };
+    let now = Utc::now();
     // TODO: check conflict of a name.
     repo.save_channel(ChannelEntity {
         id: ChannelID(id),
         name: channel.name.to_owned(),
+        last_sequence: 0,
+        created_at: now,
+        updated_at: now,
     })?;
     Ok(ChannelResponse {
         id: id.to_string().into(),
         name: channel.name,
+        created_at: now,
+        updated_at: now,
     })
 }
 
@@ -262,18 +484,236 @@ pub fn add_comment(
     repo: &DevFlexChatRepository,
     comment: CommentInput,
 ) -> FieldResult<CommentResponse> {
-    let id = uuid::Uuid::new_v4();
-    // TODO: check conflict of a uuid.
-    repo.save_comment(CommentEntity {
+    let channel_id = ChannelID(comment.channel_id.parse()?);
+    let id = loop {
+        let id = uuid::Uuid::new_v4();
+        if repo.find_comment(&channel_id, &CommentID(id))?.is_none() {
+            break id;
+        }
+    };
+    let now = Utc::now();
+    let entity = repo.save_comment(CommentEntity {
         id: CommentID(id),
-        channel_id: ChannelID(comment.channel_id.parse()?),
+        channel_id,
         name: comment.name.to_owned(),
         message: comment.message.to_owned(),
+        sequence: 0,
+        created_at: now,
+        updated_at: now,
     })?;
     Ok(CommentResponse {
         id: id.to_string().into(),
         name: comment.name,
         message: comment.message,
+        sequence: convert_from_sequence(entity.sequence)?,
+        created_at: now,
+        updated_at: now,
+    })
+}
+
+pub fn rename_channel(
+    repo: &DevFlexChatRepository,
+    channel_id: ID,
+    name: String,
+) -> FieldResult<ChannelResponse> {
+    let entity = repo.rename_channel(&ChannelID(convert_id_to_uuid(&channel_id)?), name)?;
+    Ok(ChannelResponse {
+        id: entity.id.0.to_string().into(),
+        name: entity.name,
+        created_at: entity.created_at,
+        updated_at: entity.updated_at,
+    })
+}
+
+pub fn edit_comment(
+    repo: &DevFlexChatRepository,
+    channel_id: ID,
+    id: ID,
+    message: String,
+) -> FieldResult<CommentResponse> {
+    let entity = repo.edit_comment(
+        &ChannelID(convert_id_to_uuid(&channel_id)?),
+        &CommentID(convert_id_to_uuid(&id)?),
+        message,
+    )?;
+    Ok(CommentResponse {
+        id: entity.id.0.to_string().into(),
+        name: entity.name,
+        message: entity.message,
+        sequence: convert_from_sequence(entity.sequence)?,
+        created_at: entity.created_at,
+        updated_at: entity.updated_at,
+    })
+}
+
+pub fn delete_comment(repo: &DevFlexChatRepository, channel_id: ID, id: ID) -> FieldResult<bool> {
+    repo.delete_comment(
+        &ChannelID(convert_id_to_uuid(&channel_id)?),
+        &CommentID(convert_id_to_uuid(&id)?),
+    )?;
+    Ok(true)
+}
+
+/// Marks the user of the `name` as typing in the channel for a few seconds.
+pub fn set_typing(context: &Context, channel_id: ID, name: String) -> FieldResult<bool> {
+    let channel_id = find_channel_id(&context.chat_repo, &channel_id)?;
+    publish_presence(context, context.presence.set_typing(&channel_id, name)?)?;
+    Ok(true)
+}
+
+/// Marks the user of the `name` as active in the channel. The clients send it periodically while
+/// the channel is open.
+pub fn heartbeat(context: &Context, channel_id: ID, name: String) -> FieldResult<bool> {
+    let channel_id = find_channel_id(&context.chat_repo, &channel_id)?;
+    publish_presence(context, context.presence.heartbeat(&channel_id, name)?)?;
+    Ok(true)
+}
+
+/// Starts a thread that removes the expired users of the presence every `interval`, and publishes
+/// the presence of the channels whose users are expired.
+pub fn spawn_presence_sweeper(
+    context: Arc<Context>,
+    interval: Duration,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+
+        let ret = context
+            .presence
+            .sweep()
+            .and_then(|channel_ids| publish_presence(&context, channel_ids));
+        if let Err(e) = ret {
+            warn!("failed to sweep presence: {:?}", e);
+        }
+    })
+}
+
+fn publish_presence(context: &Context, channel_ids: Vec<ChannelID>) -> Fallible<()> {
+    for channel_id in channel_ids {
+        context
+            .chat_repo
+            .publish_presence(context.presence.channel_presence(&channel_id)?)?;
+    }
+    Ok(())
+}
+
+fn find_channel_id(repo: &DevFlexChatRepository, id: &ID) -> FieldResult<ChannelID> {
+    let channel_id = ChannelID(convert_id_to_uuid(id)?);
+    match repo.find_channel(&channel_id)? {
+        Some(_) => Ok(channel_id),
+        None => Err(FieldError::new(
+            "channel not found",
+            graphql_value!({"internal_error": "channel not found"}),
+        )),
+    }
+}
+
+/// Returns the comment of the `event` if it is added to the channel of the `channel_id`.
+pub fn comment_added(event: &ChatEvent, channel_id: ID) -> FieldResult<Option<Comment>> {
+    let channel_id = ChannelID(convert_id_to_uuid(&channel_id)?);
+    match event {
+        ChatEvent::CommentPosted(data) if data.channel_id == channel_id => {
+            Ok(Some(Comment::try_from(data.clone())?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the comment of the `event` if it is edited in the channel of the `channel_id`.
+pub fn comment_edited(event: &ChatEvent, channel_id: ID) -> FieldResult<Option<Comment>> {
+    let channel_id = ChannelID(convert_id_to_uuid(&channel_id)?);
+    match event {
+        ChatEvent::CommentEdited(data) if data.channel_id == channel_id => {
+            Ok(Some(Comment::try_from(data.clone())?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the comment of the `event` if it is deleted from the channel of the `channel_id`.
+pub fn comment_deleted(event: &ChatEvent, channel_id: ID) -> FieldResult<Option<Comment>> {
+    let channel_id = ChannelID(convert_id_to_uuid(&channel_id)?);
+    match event {
+        ChatEvent::CommentDeleted(data) if data.channel_id == channel_id => {
+            Ok(Some(Comment::try_from(data.clone())?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the users of the `event` if they are changed in the channel of the `channel_id`.
+pub fn presence_changed(
+    event: &ChatEvent,
+    channel_id: ID,
+) -> FieldResult<Option<PresenceResponse>> {
+    let channel_id = ChannelID(convert_id_to_uuid(&channel_id)?);
+    match event {
+        ChatEvent::PresenceChanged(data) if data.channel_id == channel_id => {
+            Ok(Some(PresenceResponse {
+                typing_users: data.typing_users.clone(),
+                active_users: data.active_users.clone(),
+            }))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Returns the channel of the `event` if it is added.
+pub fn channel_added(event: &ChatEvent) -> Option<Channel> {
+    match event {
+        ChatEvent::ChannelCreated(data) => Some(Channel::from(data.clone())),
+        _ => None,
+    }
+}
+
+/// Returns the channel of the `event` if it is renamed.
+pub fn channel_renamed(event: &ChatEvent) -> Option<Channel> {
+    match event {
+        ChatEvent::ChannelRenamed(data) => Some(Channel::from(data.clone())),
+        _ => None,
+    }
+}
+
+/// Returns the first event of the `events` that the `f` returns.
+fn next_event<S, F>(events: S, f: F) -> impl Future<Item = ChatEvent, Error = ()>
+where
+    S: Stream<Item = ChatEvent, Error = ()>,
+    F: FnMut(ChatEvent) -> Option<ChatEvent>,
+{
+    events
+        .filter_map(f)
+        .into_future()
+        .map_err(|_| ())
+        .and_then(|(event, _)| event.ok_or(()))
+}
+
+/// Returns the timeout of the `timeout_seconds` argument, or the default of the server.
+fn long_polling_timeout(context: &Context, timeout_seconds: Option<i32>) -> FieldResult<Duration> {
+    match timeout_seconds {
+        Some(data) if 0 < data => Ok(Duration::from_secs(data as u64)),
+        Some(_) => Err(FieldError::new(
+            "timeoutSeconds must be positive",
+            graphql_value!({"internal_error": "timeoutSeconds must be positive"}),
+        )),
+        None => Ok(context.long_polling_timeout),
+    }
+}
+
+fn convert_to_sequence(sequence: i32) -> FieldResult<u64> {
+    sequence.try_into().map_err(|e| {
+        FieldError::new(
+            e,
+            graphql_value!({"internal_error": "failed to convert number"}),
+        )
+    })
+}
+
+fn convert_from_sequence(sequence: u64) -> FieldResult<i32> {
+    i32::try_from(sequence).map_err(|e| {
+        FieldError::new(
+            e,
+            graphql_value!({"internal_error": "sequence is out of range"}),
+        )
     })
 }
 
@@ -285,3 +725,150 @@ fn convert_id_to_uuid(id: &ID) -> FieldResult<Uuid> {
         )
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+
+    use super::*;
+
+    fn prepare_repo() -> DevFlexChatRepository {
+        DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new()))
+    }
+
+    #[test]
+    fn test_add_channel() -> FieldResult<()> {
+        let repo = prepare_repo();
+        let response = add_channel(
+            &repo,
+            ChannelInput {
+                name: "General".into(),
+            },
+        )?;
+
+        let actual = channels(&repo)?;
+        assert_eq!(actual.len(), 1);
+        assert_eq!(actual[0].id.0.to_string(), response.id.to_string());
+        assert_eq!(actual[0].name, "General");
+
+        let actual = channel(&repo, response.id)?.unwrap();
+        assert_eq!(actual.name, "General");
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_comment() -> FieldResult<()> {
+        let repo = prepare_repo();
+        let channel_response = add_channel(
+            &repo,
+            ChannelInput {
+                name: "General".into(),
+            },
+        )?;
+
+        for (index, message) in ["one", "two", "three"].iter().enumerate() {
+            let response = add_comment(
+                &repo,
+                CommentInput {
+                    channel_id: channel_response.id.clone(),
+                    name: "name".into(),
+                    message: message.to_string(),
+                },
+            )?;
+            assert_eq!(response.sequence, index as i32 + 1);
+        }
+
+        let channel_id = ChannelID(convert_id_to_uuid(&channel_response.id)?);
+        let actual = repo
+            .retrieve_first(&channel_id, 2, &OrderDirection::DESC)
+            .unwrap()
+            .into_iter()
+            .map(|data| data.message)
+            .collect::<Vec<_>>();
+        assert_eq!(actual, vec!["three", "two"]);
+
+        let actual = repo
+            .retrieve_after(&channel_id, 1, Some(1), &OrderDirection::ASC)
+            .unwrap()
+            .into_iter()
+            .map(|data| data.message)
+            .collect::<Vec<_>>();
+        assert_eq!(actual, vec!["two"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_comment_to_unknown_channel() {
+        let repo = prepare_repo();
+        let ret = add_comment(
+            &repo,
+            CommentInput {
+                channel_id: uuid::Uuid::new_v4().to_string().into(),
+                name: "name".into(),
+                message: "message".into(),
+            },
+        );
+        assert!(ret.is_err());
+    }
+
+    #[test]
+    fn test_edit_and_delete_comment() -> FieldResult<()> {
+        let repo = prepare_repo();
+        let channel_response = add_channel(
+            &repo,
+            ChannelInput {
+                name: "General".into(),
+            },
+        )?;
+        let comment_response = add_comment(
+            &repo,
+            CommentInput {
+                channel_id: channel_response.id.clone(),
+                name: "name".into(),
+                message: "message".into(),
+            },
+        )?;
+        let channel_id = ChannelID(convert_id_to_uuid(&channel_response.id)?);
+        let events = repo.subscribe(EventFilter::Channel(channel_id.clone()))?;
+
+        let response = rename_channel(&repo, channel_response.id.clone(), "Random".into())?;
+        assert_eq!(response.name, "Random");
+        let response = edit_comment(
+            &repo,
+            channel_response.id.clone(),
+            comment_response.id.clone(),
+            "edited".into(),
+        )?;
+        assert_eq!(response.message, "edited");
+        assert_eq!(response.sequence, 1);
+        assert!(delete_comment(
+            &repo,
+            channel_response.id.clone(),
+            comment_response.id.clone()
+        )?);
+        assert!(delete_comment(&repo, channel_response.id, comment_response.id).is_err());
+
+        assert_eq!(channels(&repo)?[0].name, "Random");
+        assert!(repo.comments(&channel_id)?.is_empty());
+        let actual = events
+            .take(3)
+            .wait()
+            .map(|data| data.map(|data| data.name()))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            actual,
+            vec!["channel-renamed", "comment-edited", "comment-deleted"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_convert_from_sequence() {
+        assert_eq!(
+            convert_from_sequence((1 << 31) - 1).ok(),
+            Some(2_147_483_647)
+        );
+        assert!(convert_from_sequence(1 << 31).is_err());
+    }
+}
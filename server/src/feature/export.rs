@@ -0,0 +1,446 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use serde_derive::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::data::db::database_cipher::DatabaseCipher;
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::db::entity::dev_flex_chat_entity::{
+    ChannelEntity, ChannelID, CommentEntity, CommentID,
+};
+use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::model::export_format::ExportFormat;
+use crate::model::storage_type::StorageType;
+use crate::prelude::*;
+
+/// A channel or a comment. The same columns are used for both so that the CSV has a header.
+///
+/// The `sequence` is exported for the other tools and is reassigned at the import.
+#[derive(Debug, Deserialize, Serialize)]
+struct ExportRecord {
+    kind: RecordKind,
+    id: Uuid,
+    #[serde(rename = "channel-id")]
+    channel_id: Option<Uuid>,
+    name: String,
+    message: Option<String>,
+    sequence: Option<u64>,
+    #[serde(rename = "created-at")]
+    created_at: DateTime<Utc>,
+    #[serde(rename = "updated-at")]
+    updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum RecordKind {
+    Channel,
+    Comment,
+}
+
+impl From<ChannelEntity> for ExportRecord {
+    fn from(entity: ChannelEntity) -> Self {
+        Self {
+            kind: RecordKind::Channel,
+            id: entity.id.0,
+            channel_id: None,
+            name: entity.name,
+            message: None,
+            sequence: None,
+            created_at: entity.created_at,
+            updated_at: entity.updated_at,
+        }
+    }
+}
+
+impl From<CommentEntity> for ExportRecord {
+    fn from(entity: CommentEntity) -> Self {
+        Self {
+            kind: RecordKind::Comment,
+            id: entity.id.0,
+            channel_id: Some(entity.channel_id.0),
+            name: entity.name,
+            message: Some(entity.message),
+            sequence: Some(entity.sequence),
+            created_at: entity.created_at,
+            updated_at: entity.updated_at,
+        }
+    }
+}
+
+/// Writes the channels and the comments to the `out`, or the stdout if it is `None`.
+///
+/// The `channel_ids` selects the channels if it is not empty, and the `since` (inclusive) and the
+/// `until` (exclusive) select the comments by the created time. This does not take the
+/// `DatabaseLock` so that the running server can be exported. The `out` is not encrypted even if
+/// the database is encrypted with the `cipher`.
+#[allow(clippy::too_many_arguments)]
+pub fn export(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    format: ExportFormat,
+    channel_ids: &[Uuid],
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    out: Option<&Path>,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let table = DevFlexChatRepository::read_snapshot(&storage, database_dir, cipher)?;
+
+    let channels = table
+        .channels
+        .into_iter()
+        .filter(|data| channel_ids.is_empty() || channel_ids.contains(&data.id.0))
+        .collect::<Vec<_>>();
+    for channel_id in channel_ids {
+        if channels.iter().all(|data| data.id.0 != *channel_id) {
+            failure::bail!("channel not found: {}", channel_id);
+        }
+    }
+
+    let exported_channel_ids = channels
+        .iter()
+        .map(|data| data.id.clone())
+        .collect::<HashSet<_>>();
+    let comments = table
+        .comments
+        .into_iter()
+        .filter(|data| exported_channel_ids.contains(&data.channel_id))
+        .filter(|data| match since {
+            Some(since) => since <= data.created_at,
+            None => true,
+        })
+        .filter(|data| match until {
+            Some(until) => data.created_at < until,
+            None => true,
+        })
+        .collect::<Vec<_>>();
+
+    info!(
+        "export {} channels and {} comments",
+        channels.len(),
+        comments.len()
+    );
+
+    let records = channels
+        .into_iter()
+        .map(ExportRecord::from)
+        .chain(comments.into_iter().map(ExportRecord::from));
+
+    let mut data = vec![];
+    write_records(&format, records, &mut data)?;
+    match out {
+        Some(out) => crate::util::write_file_atomically(out, &data)?,
+        None => std::io::stdout().write_all(&data)?,
+    }
+
+    Ok(())
+}
+
+/// Saves the channels and the comments of the `input` to the database.
+///
+/// The `input` is rejected before saving if an ID conflicts with the database, the archived
+/// comments or the other records, or if a comment belongs to an unknown channel. The channels and
+/// the comments are saved by two writes, so that the channels remain if saving the comments
+/// fails. The `sequence` of the comments is reassigned in the order of the `input`.
+pub fn import(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    format: ExportFormat,
+    input: &Path,
+    cipher: Option<DatabaseCipher>,
+) -> Fallible<()> {
+    if storage == StorageType::Memory {
+        failure::bail!("memory storage cannot be imported");
+    }
+
+    let records = read_records(&format, BufReader::new(std::fs::File::open(input)?))?;
+
+    let _database_lock = DatabaseLock::try_acquire(database_dir.clone())?;
+    let repo = DevFlexChatRepository::prepare(&storage, database_dir, cipher)?;
+
+    let mut channel_ids = HashSet::new();
+    let mut comment_ids = HashSet::new();
+    for channel in repo.channels()? {
+        // the archived comments still own their IDs.
+        for comment in repo.archived_comments(&channel.id, 0, None)? {
+            comment_ids.insert(comment.id.0);
+        }
+        for comment in repo.comments(&channel.id)? {
+            comment_ids.insert(comment.id.0);
+        }
+        channel_ids.insert(channel.id.0);
+    }
+
+    let mut channels = vec![];
+    let mut comments = vec![];
+    let mut conflicts = vec![];
+    for record in records {
+        match record.kind {
+            RecordKind::Channel => {
+                if !channel_ids.insert(record.id) {
+                    conflicts.push(record.id);
+                    continue;
+                }
+                channels.push(ChannelEntity {
+                    id: ChannelID(record.id),
+                    name: record.name,
+                    last_sequence: 0,
+                    created_at: record.created_at,
+                    updated_at: record.updated_at,
+                });
+            }
+            RecordKind::Comment => {
+                let id = record.id;
+                if !comment_ids.insert(id) {
+                    conflicts.push(id);
+                    continue;
+                }
+                comments.push(CommentEntity {
+                    id: CommentID(id),
+                    channel_id: ChannelID(
+                        record
+                            .channel_id
+                            .ok_or_else(|| failure::format_err!("channel-id not found: {}", id))?,
+                    ),
+                    name: record.name,
+                    message: record
+                        .message
+                        .ok_or_else(|| failure::format_err!("message not found: {}", id))?,
+                    sequence: 0,
+                    created_at: record.created_at,
+                    updated_at: record.updated_at,
+                });
+            }
+        }
+    }
+
+    if !conflicts.is_empty() {
+        failure::bail!("conflicting ids: {:?}", conflicts);
+    }
+
+    for comment in &comments {
+        if !channel_ids.contains(&comment.channel_id.0) {
+            failure::bail!("channel not found: {:?}", comment.channel_id);
+        }
+    }
+
+    info!(
+        "import {} channels and {} comments",
+        channels.len(),
+        comments.len()
+    );
+
+    repo.save_channels(channels)?;
+    repo.save_comments(comments)?;
+
+    info!("succeeded to import {:?}", input);
+    Ok(())
+}
+
+fn write_records<I, W>(format: &ExportFormat, records: I, writer: W) -> Fallible<()>
+where
+    I: Iterator<Item = ExportRecord>,
+    W: Write,
+{
+    match format {
+        ExportFormat::JsonLines => {
+            let mut writer = writer;
+            for record in records {
+                serde_json::to_writer(&mut writer, &record)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(writer);
+            for record in records {
+                writer.serialize(record)?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn read_records<R: BufRead>(format: &ExportFormat, reader: R) -> Fallible<Vec<ExportRecord>> {
+    match format {
+        ExportFormat::JsonLines => {
+            let mut records = vec![];
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                records.push(serde_json::from_str(&line)?);
+            }
+            Ok(records)
+        }
+        ExportFormat::Csv => Ok(csv::Reader::from_reader(reader)
+            .deserialize()
+            .collect::<Result<Vec<_>, _>>()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn prepare_database(dir: &Path) -> Fallible<ChannelID> {
+        let repo = DevFlexChatRepository::prepare(&StorageType::Toml, Some(dir.to_owned()), None)?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        repo.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        for (day, message) in ["one", "two", "three"].iter().enumerate() {
+            let created_at = Utc.ymd(2020, 4, day as u32 + 1).and_hms(0, 0, 0);
+            repo.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: message.to_string(),
+                sequence: 0,
+                created_at,
+                updated_at: created_at,
+            })?;
+        }
+        Ok(channel_id)
+    }
+
+    #[test]
+    fn test_export_and_import() -> Fallible<()> {
+        for format in &[ExportFormat::JsonLines, ExportFormat::Csv] {
+            let source_dir = tempfile::tempdir()?;
+            let channel_id = prepare_database(source_dir.path())?;
+
+            let out = source_dir.path().join("export");
+            export(
+                Some(source_dir.path().to_owned()),
+                StorageType::Toml,
+                format.clone(),
+                &[channel_id.0],
+                Some(Utc.ymd(2020, 4, 2).and_hms(0, 0, 0)),
+                None,
+                Some(&out),
+                None,
+            )?;
+
+            let target_dir = tempfile::tempdir()?;
+            import(
+                Some(target_dir.path().to_owned()),
+                StorageType::Sqlite,
+                format.clone(),
+                &out,
+                None,
+            )?;
+
+            let repo = DevFlexChatRepository::prepare(
+                &StorageType::Sqlite,
+                Some(target_dir.path().to_owned()),
+                None,
+            )?;
+            let comments = repo.comments(&channel_id)?;
+            assert_eq!(
+                comments
+                    .iter()
+                    .map(|data| data.message.as_str())
+                    .collect::<Vec<_>>(),
+                vec!["two", "three"]
+            );
+            assert_eq!(comments[0].sequence, 1);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_conflict() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let channel_id = prepare_database(dir.path())?;
+        let out = dir.path().join("export.jsonl");
+        export(
+            Some(dir.path().to_owned()),
+            StorageType::Toml,
+            ExportFormat::JsonLines,
+            &[],
+            None,
+            None,
+            Some(&out),
+            None,
+        )?;
+
+        assert!(import(
+            Some(dir.path().to_owned()),
+            StorageType::Toml,
+            ExportFormat::JsonLines,
+            &out,
+            None,
+        )
+        .is_err());
+
+        let repo =
+            DevFlexChatRepository::prepare(&StorageType::Toml, Some(dir.path().to_owned()), None)?;
+        assert_eq!(repo.channels()?.len(), 1);
+        assert_eq!(repo.comments(&channel_id)?.len(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_archived_comment_conflict() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let channel_id = prepare_database(dir.path())?;
+        let repo =
+            DevFlexChatRepository::prepare(&StorageType::Toml, Some(dir.path().to_owned()), None)?;
+        assert_eq!(repo.archive_comments_until(&channel_id, 1)?, 1);
+
+        let out = dir.path().join("import.jsonl");
+        let mut data = vec![];
+        write_records(
+            &ExportFormat::JsonLines,
+            repo.archived_comments(&channel_id, 0, None)?
+                .into_iter()
+                .map(ExportRecord::from),
+            &mut data,
+        )?;
+        std::fs::write(&out, data)?;
+        drop(repo);
+
+        let err = import(
+            Some(dir.path().to_owned()),
+            StorageType::Toml,
+            ExportFormat::JsonLines,
+            &out,
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().starts_with("conflicting ids"));
+        Ok(())
+    }
+}
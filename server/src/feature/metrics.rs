@@ -0,0 +1,40 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use futures::Stream;
+use log::warn;
+
+use crate::data::event_bus::EventFilter;
+use crate::model::juniper_object::Context;
+use crate::prelude::*;
+
+/// Starts a thread that counts the events of the `chat_repo` into the `metrics` of the `context`.
+pub fn spawn_collector(context: Arc<Context>) -> Fallible<std::thread::JoinHandle<()>> {
+    let events = context.chat_repo.subscribe(EventFilter::All)?;
+    Ok(std::thread::spawn(move || {
+        for event in events.wait() {
+            let event = match event {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            if let Err(e) = context.metrics.record(&event) {
+                warn!("failed to record the metrics: {:?}", e);
+            }
+        }
+    }))
+}
@@ -0,0 +1,439 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use flate2::Crc;
+use log::{info, warn};
+
+use crate::data::db::chat_store::ChatStore;
+use crate::data::db::comment_archive;
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, CommentEntity};
+use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+use crate::data::db::sqlite_chat_database::SqliteChatDatabase;
+use crate::data::repository::dev_flex_chat_repository::{open_chat_store, DevFlexChatRepository};
+use crate::feature::migration;
+use crate::model::storage_location::StorageLocation;
+use crate::model::storage_type::StorageType;
+use crate::prelude::*;
+
+/// Number of the comments to read and write at once.
+const BATCH_SIZE: usize = 1000;
+
+/// Copies the channels and the comments from the `from` to the empty `to`.
+///
+/// The comments are copied for each channel in batches of the `BATCH_SIZE` through the
+/// `ChatStore` of the `to`. The IDs, the sequences and the order of the entities are preserved,
+/// and the `to` is created with the version-code of the app. The `to` is opened again and
+/// compared with the copied entities by the counts and the checksum. The archived comments are
+/// copied as well.
+///
+/// The `from` is read without the `DatabaseLock` so that the running server can be copied. The
+/// `from` is read again after the copy, and this fails if the entities are saved or removed in
+/// the meantime. The `cipher` is used for the toml and the wal storage.
+pub fn migrate_storage(
+    from: StorageLocation,
+    to: StorageLocation,
+    cipher: Option<DatabaseCipher>,
+) -> Fallible<()> {
+    if from == to {
+        failure::bail!("same storage: {}", from);
+    }
+    for location in &[&from, &to] {
+        if location.storage == StorageType::Memory {
+            failure::bail!("memory storage cannot be migrated");
+        }
+    }
+
+    let from_cipher = storage_cipher(&from.storage, cipher.as_ref());
+    let to_cipher = storage_cipher(&to.storage, cipher.as_ref());
+    if from_cipher.is_some() && to_cipher.is_none() {
+        warn!("{} is not encrypted", to);
+    }
+
+    let pending =
+        migration::pending_migrations(Some(from.database_dir.clone()), &from.storage, from_cipher)?;
+    if !pending.is_empty() {
+        failure::bail!("need to migrate {}: {}", from, pending.join(", "));
+    }
+
+    let _database_lock = DatabaseLock::try_acquire(Some(to.database_dir.clone()))?;
+    ensure_empty(&to, to_cipher)?;
+
+    info!("copy {} to {}", from, to);
+    let source = open_snapshot(&from, from_cipher)?;
+    let expected = {
+        let target = open_chat_store(
+            &to.storage,
+            Some(to.database_dir.clone()),
+            to_cipher.cloned(),
+        )?;
+        copy_entities(&*source, &*target)?
+    };
+
+    let archive_count = copy_archives(&from, &to, from_cipher, to_cipher)?;
+
+    // the archive files are consistent with the copied comments if the comments are not changed,
+    // because the retention removes the comments that it writes to the archive.
+    info!("verify {}", from);
+    if let Err(e) = verify(
+        &expected,
+        &calculate_summary(&*open_snapshot(&from, from_cipher)?)?,
+    ) {
+        failure::bail!(
+            "{} is changed while copying. stop the server and retry: {}",
+            from,
+            e
+        );
+    }
+
+    info!("verify {}", to);
+    verify(
+        &expected,
+        &calculate_summary(&*open_snapshot(&to, to_cipher)?)?,
+    )?;
+
+    println!(
+        "copied {} channels, {} comments and {} archive files. checksum: {:08x}",
+        expected.channel_count, expected.comment_count, archive_count, expected.checksum
+    );
+    Ok(())
+}
+
+/// Counts and CRC-32 of the entities in order.
+struct Summary {
+    channel_count: usize,
+    comment_count: usize,
+    checksum: u32,
+}
+
+struct SummaryBuilder {
+    summary: Summary,
+    crc: Crc,
+}
+
+impl SummaryBuilder {
+    fn new() -> Self {
+        Self {
+            summary: Summary {
+                channel_count: 0,
+                comment_count: 0,
+                checksum: 0,
+            },
+            crc: Crc::new(),
+        }
+    }
+
+    fn add_channel(&mut self, channel: &ChannelEntity) -> Fallible<()> {
+        self.summary.channel_count += 1;
+        self.crc.update(&serde_json::to_vec(channel)?);
+        Ok(())
+    }
+
+    fn add_comments(&mut self, comments: &[CommentEntity]) -> Fallible<()> {
+        self.summary.comment_count += comments.len();
+        for comment in comments {
+            self.crc.update(&serde_json::to_vec(comment)?);
+        }
+        Ok(())
+    }
+
+    fn build(mut self) -> Summary {
+        self.summary.checksum = self.crc.sum();
+        self.summary
+    }
+}
+
+/// Copies the channels and the comments of each channel, and returns the summary of them.
+fn copy_entities(source: &dyn ChatStore, target: &dyn ChatStore) -> Fallible<Summary> {
+    let mut summary = SummaryBuilder::new();
+    for channel in source.channels_created_asc()? {
+        summary.add_channel(&channel)?;
+        target.save_channel(channel.clone())?;
+
+        let mut sequence = 0;
+        loop {
+            let comments = source.retrieve_after_sequence_asc(
+                &channel.id,
+                sequence,
+                Some(BATCH_SIZE as u32),
+            )?;
+            let last_sequence = match comments.last() {
+                Some(data) => data.sequence,
+                None => break,
+            };
+            summary.add_comments(&comments)?;
+            target.insert_comments(comments)?;
+            sequence = last_sequence;
+        }
+        info!("copied {}: {:?}", channel.name, channel.id);
+    }
+    Ok(summary.build())
+}
+
+/// Returns the summary of the `store` in the same order as the `copy_entities`.
+fn calculate_summary(store: &dyn ChatStore) -> Fallible<Summary> {
+    let mut summary = SummaryBuilder::new();
+    for channel in store.channels_created_asc()? {
+        summary.add_channel(&channel)?;
+        summary.add_comments(&store.retrieve_comments(&channel.id)?)?;
+    }
+    Ok(summary.build())
+}
+
+/// Fails if the `actual` differs from the `expected`.
+fn verify(expected: &Summary, actual: &Summary) -> Fallible<()> {
+    if actual.channel_count != expected.channel_count
+        || actual.comment_count != expected.comment_count
+    {
+        failure::bail!(
+            "count mismatch. channels: {} -> {}, comments: {} -> {}",
+            expected.channel_count,
+            actual.channel_count,
+            expected.comment_count,
+            actual.comment_count
+        );
+    }
+    if actual.checksum != expected.checksum {
+        failure::bail!(
+            "checksum mismatch: {:08x} -> {:08x}",
+            expected.checksum,
+            actual.checksum
+        );
+    }
+    Ok(())
+}
+
+/// Opens the `ChatStore` of the `location` without writing it.
+///
+/// The toml and the wal storage are read into the memory because the snapshot is a file. The
+/// comments of the unknown channels are not read.
+fn open_snapshot(
+    location: &StorageLocation,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<Box<dyn ChatStore>> {
+    let database_dir = Some(location.database_dir.clone());
+    match location.storage {
+        StorageType::Sqlite => {
+            let database_path = crate::util::get_database_sqlite_file_path(database_dir);
+            if !database_path.exists() {
+                failure::bail!("{:?} not found", database_path);
+            }
+            Ok(Box::new(SqliteChatDatabase::create(database_path)?))
+        }
+        _ => Ok(Box::new(InMemoryChatDatabase::with_table(
+            DevFlexChatRepository::read_snapshot(&location.storage, database_dir, cipher)?,
+        ))),
+    }
+}
+
+fn storage_cipher<'a>(
+    storage: &StorageType,
+    cipher: Option<&'a DatabaseCipher>,
+) -> Option<&'a DatabaseCipher> {
+    match storage {
+        StorageType::Toml | StorageType::Wal => cipher,
+        StorageType::Sqlite | StorageType::Memory => None,
+    }
+}
+
+fn ensure_empty(location: &StorageLocation, cipher: Option<&DatabaseCipher>) -> Fallible<()> {
+    let database_dir = Some(location.database_dir.clone());
+    let database_path = match location.storage {
+        StorageType::Sqlite => crate::util::get_database_sqlite_file_path(database_dir.clone()),
+        _ => crate::util::get_database_file_path(database_dir.clone()),
+    };
+    if !database_path.exists() {
+        return Ok(());
+    }
+
+    let table = DevFlexChatRepository::read_snapshot(&location.storage, database_dir, cipher)?;
+    if !table.channels.is_empty() || !table.comments.is_empty() {
+        failure::bail!("{} is not empty", location);
+    }
+    Ok(())
+}
+
+/// Copies the archive files, and returns the number of them.
+fn copy_archives(
+    from: &StorageLocation,
+    to: &StorageLocation,
+    from_cipher: Option<&DatabaseCipher>,
+    to_cipher: Option<&DatabaseCipher>,
+) -> Fallible<usize> {
+    let from_archive_dir = crate::util::get_archive_dir_path(Some(from.database_dir.clone()));
+    let to_archive_dir = crate::util::get_archive_dir_path(Some(to.database_dir.clone()));
+    if from_archive_dir == to_archive_dir && from_cipher.is_some() == to_cipher.is_some() {
+        return Ok(0);
+    }
+
+    let files = comment_archive::list_all_files(&from_archive_dir)?;
+    for file_path in &files {
+        let data = database_cipher::read_file(file_path, from_cipher)?;
+        let target_path = to_archive_dir.join(file_path.strip_prefix(&from_archive_dir)?);
+        if let Some(parent) = target_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        database_cipher::write_file_atomically(&target_path, &data, to_cipher)?;
+        crate::util::remove_backup_file(&target_path)?;
+    }
+    Ok(files.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::{
+        ChannelEntity, ChannelID, CommentEntity, CommentID,
+    };
+
+    use crate::data::db::dev_flex_chat_database::DevFlexChatTable;
+
+    use super::*;
+
+    #[test]
+    fn test_migrate_storage() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let from = StorageLocation {
+            storage: StorageType::Toml,
+            database_dir: dir.path().join("from"),
+        };
+        let to = StorageLocation {
+            storage: StorageType::Sqlite,
+            database_dir: dir.path().join("to"),
+        };
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let mut comment_ids = vec![];
+        {
+            let repo = DevFlexChatRepository::prepare(
+                &from.storage,
+                Some(from.database_dir.clone()),
+                None,
+            )?;
+            repo.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            for message in &["one", "two", "three"] {
+                let comment = repo.save_comment(CommentEntity {
+                    id: CommentID(uuid::Uuid::new_v4()),
+                    channel_id: channel_id.clone(),
+                    name: "name".into(),
+                    message: message.to_string(),
+                    sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })?;
+                comment_ids.push(comment.id);
+            }
+            repo.archive_comments_until(&channel_id, 1)?;
+        }
+
+        migrate_storage(from.clone(), to.clone(), None)?;
+
+        let repo =
+            DevFlexChatRepository::prepare(&to.storage, Some(to.database_dir.clone()), None)?;
+        let comments = repo.comments(&channel_id)?;
+        assert_eq!(
+            comments
+                .iter()
+                .map(|data| data.id.clone())
+                .collect::<Vec<_>>(),
+            comment_ids[1..].to_vec()
+        );
+        assert_eq!(
+            comments
+                .iter()
+                .map(|data| data.sequence)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(repo.archived_comments(&channel_id, 0, None)?.len(), 1);
+        drop(repo);
+
+        // not empty.
+        assert!(migrate_storage(from, to, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrate_storage_batches() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let from = StorageLocation {
+            storage: StorageType::Sqlite,
+            database_dir: dir.path().join("from"),
+        };
+        let to = StorageLocation {
+            storage: StorageType::Wal,
+            database_dir: dir.path().join("to"),
+        };
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let count = BATCH_SIZE as u64 * 2 + 1;
+        let mut table = DevFlexChatTable::new()?;
+        table.channels.push(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: count + 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        });
+        for sequence in 1..=count {
+            table.comments.push(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: sequence.to_string(),
+                sequence,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            });
+        }
+        DevFlexChatRepository::write_snapshot(
+            &from.storage,
+            Some(from.database_dir.clone()),
+            &table,
+            None,
+        )?;
+
+        migrate_storage(from, to.clone(), None)?;
+
+        let repo =
+            DevFlexChatRepository::prepare(&to.storage, Some(to.database_dir.clone()), None)?;
+        let comments = repo.comments(&channel_id)?;
+        assert_eq!(comments.len() as u64, count);
+        assert!(comments
+            .iter()
+            .enumerate()
+            .all(|(index, data)| data.sequence == index as u64 + 1));
+
+        // the last sequence of the deleted comment is not reused.
+        let entity = repo.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id,
+            name: "name".into(),
+            message: "new".into(),
+            sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        assert_eq!(entity.sequence, count + 2);
+        Ok(())
+    }
+}
@@ -14,18 +14,270 @@
  * limitations under the License.
  */
 
-use std::io::prelude::*;
+use std::collections::{BTreeSet, HashMap};
 use std::path::{Path, PathBuf};
 
+use chrono::Utc;
 use log::info;
 
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::database_lock::DatabaseLock;
 use crate::data::db::dev_flex_chat_database::{
     convert_to_version, convert_to_version_code, generate_latest_database_version,
 };
-use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, ChannelID};
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelID, MigrationHistoryEntity};
+use crate::data::db::sqlite_chat_database;
+use crate::data::db::wal_chat_database::{merge_log_into_snapshot, read_value_with_log};
+use crate::data::repository::dev_flex_chat_repository::ensure_cipher_supported;
+use crate::model::storage_type::StorageType;
+use crate::model::version::Version;
 use crate::prelude::*;
 
-pub fn migration(database_dir: Option<PathBuf>) -> Fallible<()> {
+type MigrationFn = fn(&mut toml::Value) -> Fallible<()>;
+
+/// A step of the `database.toml` schema.
+///
+/// The `up` transforms the database of the previous step to the `version_code`, and the `down`
+/// transforms it back. The version-code is updated by the caller.
+struct MigrationStep {
+    version_code: u64,
+    up: MigrationFn,
+    down: MigrationFn,
+}
+
+/// A step of the migration from the database to the target.
+struct PlannedStep {
+    description: String,
+    migrate: Option<MigrationFn>,
+
+    /// Version-code after the step. `None` to remove it.
+    version_code: Option<u64>,
+}
+
+/// Migrates the database to the `to`, or the version of the app if it is `None`.
+///
+/// The `to` can be older than the database to roll back. The migrations are recorded to the
+/// `migration-history` of the database. The sqlite storage can be migrated only to the version of
+/// the app.
+///
+/// With the `dry_run`, the steps are applied to a copy of the database in memory and the changes
+/// of each step are printed. The database is not modified and not locked, and this fails if a step
+/// fails.
+pub fn migration(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    wait: bool,
+    to: Option<Version>,
+    dry_run: bool,
+    cipher: Option<DatabaseCipher>,
+) -> Fallible<()> {
+    ensure_cipher_supported(&storage, cipher.as_ref())?;
+
+    match storage {
+        StorageType::Toml | StorageType::Wal => (),
+        StorageType::Sqlite => {
+            if to.is_some() {
+                failure::bail!("--to is supported for the toml and wal storage");
+            }
+            if dry_run {
+                failure::bail!("--dry-run is supported for the toml and wal storage");
+            }
+        }
+        StorageType::Memory => failure::bail!("memory storage has nothing to migrate"),
+    }
+
+    if dry_run {
+        return dry_run_toml(database_dir, to.as_ref(), cipher.as_ref());
+    }
+
+    let _database_lock = if wait {
+        DatabaseLock::acquire(database_dir.clone())?
+    } else {
+        DatabaseLock::try_acquire(database_dir.clone())?
+    };
+
+    migrate(database_dir, &storage, to.as_ref(), cipher.as_ref())
+}
+
+/// Returns the descriptions of the steps to migrate the database to the version of the app. Empty
+/// if the database is up to date or not created.
+pub fn pending_migrations(
+    database_dir: Option<PathBuf>,
+    storage: &StorageType,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<Vec<String>> {
+    match storage {
+        StorageType::Toml | StorageType::Wal => {
+            let database_file_path = crate::util::get_database_file_path(database_dir.clone());
+            if !database_file_path.exists() {
+                return Ok(vec![]);
+            }
+
+            let value = read_value_with_log(
+                &database_file_path,
+                &crate::util::get_database_log_file_path(database_dir),
+                cipher,
+            )?;
+            Ok(prepare_plan(&value, None)?
+                .2
+                .into_iter()
+                .map(|data| data.description)
+                .collect())
+        }
+        StorageType::Sqlite => sqlite_chat_database::pending_migrations(
+            &crate::util::get_database_sqlite_file_path(database_dir),
+        ),
+        StorageType::Memory => Ok(vec![]),
+    }
+}
+
+/// Copies the database files to the `migration-backups` and migrates the database to the version
+/// of the app if it has pending migrations.
+///
+/// The caller must hold the `DatabaseLock`.
+pub fn auto_migrate(
+    database_dir: Option<PathBuf>,
+    storage: &StorageType,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let pending = pending_migrations(database_dir.clone(), storage, cipher)?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+
+    info!("pending migrations: {}", pending.join(", "));
+    backup_database_files(database_dir.clone())?;
+    migrate(database_dir, storage, None, cipher)
+}
+
+fn migrate(
+    database_dir: Option<PathBuf>,
+    storage: &StorageType,
+    to: Option<&Version>,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    match storage {
+        StorageType::Sqlite => {
+            sqlite_chat_database::migrate(&crate::util::get_database_sqlite_file_path(database_dir))
+        }
+        _ => migrate_toml(database_dir, to, cipher),
+    }
+}
+
+/// Copies the database files as is, so that the database of any version can be backed up.
+fn backup_database_files(database_dir: Option<PathBuf>) -> Fallible<()> {
+    let backup_dir = crate::util::get_migration_backup_dir_path(database_dir.clone())
+        .join(Utc::now().format("%Y%m%dT%H%M%S%.fZ").to_string());
+    std::fs::create_dir_all(&backup_dir)?;
+
+    for file_path in &[
+        crate::util::get_database_file_path(database_dir.clone()),
+        crate::util::get_database_log_file_path(database_dir.clone()),
+        crate::util::get_database_sqlite_file_path(database_dir),
+    ] {
+        if file_path.exists() {
+            std::fs::copy(
+                file_path,
+                backup_dir.join(file_path.file_name().ok_or_err()?),
+            )?;
+        }
+    }
+
+    info!("backed up the database to {:?}", backup_dir);
+    Ok(())
+}
+
+fn migrate_toml(
+    database_dir: Option<PathBuf>,
+    to: Option<&Version>,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    let database_file_path = crate::util::get_database_file_path(database_dir.clone());
+    let database_log_file_path = crate::util::get_database_log_file_path(database_dir);
+    merge_log_into_snapshot(&database_file_path, &database_log_file_path, cipher)?;
+
+    let mut value = read_file_as_toml_value(&database_file_path, cipher)?;
+    let (database_version_code, target_version_code, plan) = prepare_plan(&value, to)?;
+    if plan.is_empty() {
+        info!(
+            "database is already v{}",
+            convert_to_version(database_version_code).0
+        );
+        return Ok(());
+    }
+
+    for step in &plan {
+        info!("{}", step.description);
+        apply_step(&mut value, step)?;
+    }
+
+    let history = value
+        .as_table_mut()
+        .ok_or_err()?
+        .entry("migration-history")
+        .or_insert_with(|| toml::Value::Array(vec![]))
+        .as_array_mut()
+        .ok_or_err()?;
+    history.push(toml::Value::try_from(MigrationHistoryEntity {
+        from_version_code: database_version_code,
+        to_version_code: target_version_code,
+        migrated_at: Utc::now(),
+    })?);
+
+    info!("write data");
+    database_cipher::write_file_atomically(
+        &database_file_path,
+        toml::to_string(&value)?.as_bytes(),
+        cipher,
+    )?;
+
+    info!(
+        "succeeded to migrate to v{}",
+        convert_to_version(target_version_code).0
+    );
+    Ok(())
+}
+
+fn dry_run_toml(
+    database_dir: Option<PathBuf>,
+    to: Option<&Version>,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    // the log is merged in memory as well.
+    let mut value = read_value_with_log(
+        &crate::util::get_database_file_path(database_dir.clone()),
+        &crate::util::get_database_log_file_path(database_dir),
+        cipher,
+    )?;
+    let (database_version_code, target_version_code, plan) = prepare_plan(&value, to)?;
+    println!(
+        "v{} -> v{}: {} steps",
+        convert_to_version(database_version_code).0,
+        convert_to_version(target_version_code).0,
+        plan.len()
+    );
+
+    for step in &plan {
+        println!("{}", step.description);
+        let previous_value = value.clone();
+        if let Err(e) = apply_step(&mut value, step) {
+            println!("  failed: {}", e);
+            failure::bail!("dry run failed: {}", step.description);
+        }
+        for line in describe_changes(&previous_value, &value)? {
+            println!("  {}", line);
+        }
+    }
+
+    println!("no changes were written");
+    Ok(())
+}
+
+/// Returns the version-code of the database, the target version-code and the steps between them.
+fn prepare_plan(
+    value: &toml::Value,
+    to: Option<&Version>,
+) -> Fallible<(u64, u64, Vec<PlannedStep>)> {
     let (current_version, current_version_flush_code) = generate_latest_database_version()?;
     let current_version_code =
         convert_to_version_code(&current_version, current_version_flush_code);
@@ -35,8 +287,15 @@ pub fn migration(database_dir: Option<PathBuf>) -> Fallible<()> {
         current_version, current_version_flush_code
     );
 
-    let database_file_path = crate::util::get_database_file_path(database_dir);
-    let database_version_code = retrieve_database_version_code(&database_file_path)?;
+    let target_version_code = match to {
+        Some(to) if *to != current_version => convert_to_version_code(to, 0),
+        _ => current_version_code,
+    };
+    if current_version_code < target_version_code {
+        failure::bail!("need to upgrade the app to migrate to v{}", to.ok_or_err()?);
+    }
+
+    let database_version_code = retrieve_database_version_code(value)?;
     let (database_version, database_flush_code) = convert_to_version(database_version_code);
 
     info!(
@@ -48,86 +307,665 @@ pub fn migration(database_dir: Option<PathBuf>) -> Fallible<()> {
         failure::bail!("need to upgrade the app to migrate")
     }
 
-    let map = [(convert_to_version_code(&[0, 3, 0].into(), 0), migrate_0_3_0)];
-    if current_version_code < map[map.len() - 1].0 {
+    let steps = migration_steps();
+    if current_version_code < steps[steps.len() - 1].version_code {
         failure::bail!("need to update a version in Cargo");
     }
 
-    for (target_code, ref f) in &map {
-        if database_version_code < *target_code {
-            f(&database_file_path)?;
+    Ok((
+        database_version_code,
+        target_version_code,
+        plan_migrations(&steps, database_version_code, target_version_code),
+    ))
+}
+
+fn migration_steps() -> Vec<MigrationStep> {
+    vec![
+        MigrationStep {
+            version_code: convert_to_version_code(&[0, 3, 0].into(), 0),
+            up: up_0_3_0,
+            down: down_0_3_0,
+        },
+        MigrationStep {
+            version_code: convert_to_version_code(&[0, 4, 0].into(), 0),
+            up: up_0_4_0,
+            down: down_0_4_0,
+        },
+        MigrationStep {
+            version_code: convert_to_version_code(&[0, 5, 0].into(), 0),
+            up: up_0_5_0,
+            down: down_0_5_0,
+        },
+    ]
+}
+
+/// Returns the `up` of the steps in (`from`, `to`], or the `down` of the steps in (`to`, `from`]
+/// in reverse order.
+fn plan_migrations(steps: &[MigrationStep], from: u64, to: u64) -> Vec<PlannedStep> {
+    let mut plan = vec![];
+    if from < to {
+        for step in steps
+            .iter()
+            .filter(|data| from < data.version_code && data.version_code <= to)
+        {
+            plan.push(PlannedStep {
+                description: format!("migrate up to v{}", convert_to_version(step.version_code).0),
+                migrate: Some(step.up),
+                version_code: Some(step.version_code),
+            });
+        }
+    } else {
+        for (index, step) in steps
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, data)| to < data.version_code && data.version_code <= from)
+        {
+            plan.push(PlannedStep {
+                description: format!(
+                    "migrate down from v{}",
+                    convert_to_version(step.version_code).0
+                ),
+                migrate: Some(step.down),
+                // the databases before the first step do not have the version-code.
+                version_code: match index {
+                    0 => None,
+                    _ => Some(steps[index - 1].version_code),
+                },
+            });
         }
     }
 
-    set_database_version_code(database_file_path, current_version_code)?;
-    Ok(())
+    // e.g. a patch version or the flush code that has no step.
+    let version_code = match plan.last() {
+        Some(data) => data.version_code,
+        None => Some(from),
+    };
+    if steps[0].version_code <= to && version_code != Some(to) {
+        plan.push(PlannedStep {
+            description: format!("set version to v{}", convert_to_version(to).0),
+            migrate: None,
+            version_code: Some(to),
+        });
+    }
+
+    plan
 }
 
-fn retrieve_database_version_code<T: AsRef<Path>>(file_path: T) -> Fallible<u64> {
-    match read_file_as_toml_value(file_path)?.get("version-code") {
-        Some(data) => Ok(data.as_integer().ok_or_err()? as u64),
-        None => Ok(convert_to_version_code(&[0, 1, 0].into(), 0)),
+fn apply_step(value: &mut toml::Value, step: &PlannedStep) -> Fallible<()> {
+    if let Some(migrate) = step.migrate {
+        migrate(value)?;
     }
+    set_database_version_code(value, step.version_code)
 }
 
-fn set_database_version_code<T: AsRef<Path>>(file_path: T, version_code: u64) -> Fallible<()> {
-    info!("set database version-code: {}", version_code);
+/// Returns the lines of the changes from the `previous` to the `value`.
+///
+/// The arrays of the entries are compared by the index, so that the steps must not reorder them.
+fn describe_changes(previous: &toml::Value, value: &toml::Value) -> Fallible<Vec<String>> {
+    let previous = previous.as_table().ok_or_err()?;
+    let value = value.as_table().ok_or_err()?;
+    let mut lines = vec![];
 
-    let mut value = read_file_as_toml_value(file_path.as_ref())?;
-    value["version-code"] = toml::Value::Integer(version_code as i64);
+    let format_version_code =
+        |data: Option<&toml::Value>| match data.and_then(|data| data.as_integer()) {
+            Some(data) => format!("v{}", convert_to_version(data as u64).0),
+            None => "none".to_string(),
+        };
+    if previous.get("version-code") != value.get("version-code") {
+        lines.push(format!(
+            "version-code: {} -> {}",
+            format_version_code(previous.get("version-code")),
+            format_version_code(value.get("version-code"))
+        ));
+    }
 
-    info!("write data");
-    let twitter_file_string = toml::to_string(&value)?;
-    let twitter_file = std::fs::File::create(file_path)?;
-    let mut writer = std::io::BufWriter::new(twitter_file);
-    writer.write_all(twitter_file_string.as_bytes())?;
+    for (key, entry) in value {
+        if key == "version-code" || previous.contains_key(key) {
+            continue;
+        }
+        match entry.as_array() {
+            Some(entries) => lines.push(format!("{}: added with {} entries", key, entries.len())),
+            None => lines.push(format!("{}: added", key)),
+        }
+    }
+
+    for key in previous.keys() {
+        if key != "version-code" && !value.contains_key(key) {
+            lines.push(format!("{}: removed", key));
+        }
+    }
+
+    for (key, entry) in value {
+        let (previous_entries, entries) = match (
+            previous.get(key).and_then(|data| data.as_array()),
+            entry.as_array(),
+        ) {
+            (Some(previous_entries), Some(entries)) => (previous_entries, entries),
+            _ => continue,
+        };
+        if previous_entries.len() != entries.len() {
+            lines.push(format!(
+                "{}: {} -> {} entries",
+                key,
+                previous_entries.len(),
+                entries.len()
+            ));
+            continue;
+        }
+
+        let mut rewritten = 0;
+        let mut inserted_fields = BTreeSet::new();
+        let mut removed_fields = BTreeSet::new();
+        for (previous_entry, entry) in previous_entries.iter().zip(entries) {
+            if previous_entry == entry {
+                continue;
+            }
+            rewritten += 1;
+
+            if let (Some(previous_entry), Some(entry)) =
+                (previous_entry.as_table(), entry.as_table())
+            {
+                inserted_fields.extend(
+                    entry
+                        .keys()
+                        .filter(|data| !previous_entry.contains_key(*data)),
+                );
+                removed_fields.extend(
+                    previous_entry
+                        .keys()
+                        .filter(|data| !entry.contains_key(*data)),
+                );
+            }
+        }
+        if rewritten == 0 {
+            continue;
+        }
+
+        let mut line = format!(
+            "{}: {} of {} entries rewritten",
+            key,
+            rewritten,
+            entries.len()
+        );
+        if !inserted_fields.is_empty() {
+            line.push_str(&format!(
+                ", inserted fields: {}",
+                inserted_fields
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        if !removed_fields.is_empty() {
+            line.push_str(&format!(
+                ", removed fields: {}",
+                removed_fields
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        lines.push(line);
+    }
+
+    Ok(lines)
+}
 
-    info!("succeeded to set a version to the database");
+fn retrieve_database_version_code(value: &toml::Value) -> Fallible<u64> {
+    match value.get("version-code") {
+        Some(data) => Ok(data.as_integer().ok_or_err()? as u64),
+        None => Ok(convert_to_version_code(&[0, 1, 0].into(), 0)),
+    }
+}
 
+fn set_database_version_code(value: &mut toml::Value, version_code: Option<u64>) -> Fallible<()> {
+    let table = value.as_table_mut().ok_or_err()?;
+    match version_code {
+        Some(version_code) => {
+            table.insert(
+                "version-code".into(),
+                toml::Value::Integer(version_code as i64),
+            );
+        }
+        None => {
+            table.remove("version-code");
+        }
+    }
     Ok(())
 }
 
-fn read_file_as_toml_value<T: AsRef<Path>>(file_path: T) -> Fallible<toml::Value> {
-    let file = std::fs::File::open(file_path)?;
-    let mut reader = std::io::BufReader::new(file);
-    let mut data = String::new();
-    reader.read_to_string(&mut data)?;
+fn read_file_as_toml_value<T: AsRef<Path>>(
+    file_path: T,
+    cipher: Option<&DatabaseCipher>,
+) -> Fallible<toml::Value> {
+    let data = database_cipher::read_file_to_string(file_path.as_ref(), cipher)?;
     Ok(toml::from_str(&data)?)
 }
 
-fn migrate_0_3_0(file_path: &Path) -> Fallible<()> {
-    info!("migrate to v0.3.0");
-
-    let mut table = read_file_as_toml_value(file_path)?;
-    let table_table = table.as_table_mut().ok_or_err()?;
-    table_table.insert(
-        "version-code".into(),
-        toml::Value::Integer(convert_to_version_code(&[0, 3, 0].into(), 0) as i64),
-    );
+fn remove_keys(value: &mut toml::Value, array_keys: &[&str], keys: &[&str]) -> Fallible<()> {
+    for array_key in array_keys {
+        for entry in value[*array_key].as_array_mut().ok_or_err()? {
+            let entry = entry.as_table_mut().ok_or_err()?;
+            for key in keys {
+                entry.remove(*key);
+            }
+        }
+    }
+    Ok(())
+}
 
+fn up_0_3_0(value: &mut toml::Value) -> Fallible<()> {
     let general_channel_uuid = ChannelID(uuid::Uuid::new_v4());
 
-    table_table.insert(
+    let mut general_channel = toml::value::Table::new();
+    general_channel.insert("id".into(), toml::Value::try_from(&general_channel_uuid.0)?);
+    general_channel.insert("name".into(), toml::Value::String("General".into()));
+    value.as_table_mut().ok_or_err()?.insert(
         "channels".into(),
-        toml::Value::Array(vec![toml::Value::try_from(ChannelEntity {
-            id: general_channel_uuid.clone(),
-            name: "General".into(),
-        })?]),
+        toml::Value::Array(vec![toml::Value::Table(general_channel)]),
     );
 
-    for entry in table["comments"].as_array_mut().ok_or_err()? {
+    for entry in value["comments"].as_array_mut().ok_or_err()? {
         entry.as_table_mut().ok_or_err()?.insert(
             "channel-id".into(),
             toml::Value::try_from(&general_channel_uuid.0)?,
         );
     }
+    Ok(())
+}
 
-    info!("write data");
-    let twitter_file_string = toml::to_string(&table)?;
-    let twitter_file = std::fs::File::create(file_path)?;
-    let mut writer = std::io::BufWriter::new(twitter_file);
-    writer.write_all(twitter_file_string.as_bytes())?;
+fn down_0_3_0(value: &mut toml::Value) -> Fallible<()> {
+    // the comments of the channels cannot be distinguished before v0.3.0.
+    let channel_count = value["channels"].as_array().ok_or_err()?.len();
+    if 1 < channel_count {
+        failure::bail!(
+            "cannot migrate {} channels to before v0.3.0 that has only one channel",
+            channel_count
+        );
+    }
+
+    value.as_table_mut().ok_or_err()?.remove("channels");
+    remove_keys(value, &["comments"], &["channel-id"])
+}
 
-    info!("succeeded migrate to v0.3.0");
+fn up_0_4_0(value: &mut toml::Value) -> Fallible<()> {
+    // the posted time of the existing entities is unknown.
+    let now = toml::Value::try_from(Utc::now())?;
+    for key in &["channels", "comments"] {
+        for entry in value[*key].as_array_mut().ok_or_err()? {
+            let entry = entry.as_table_mut().ok_or_err()?;
+            entry.insert("created-at".into(), now.clone());
+            entry.insert("updated-at".into(), now.clone());
+        }
+    }
     Ok(())
 }
+
+fn down_0_4_0(value: &mut toml::Value) -> Fallible<()> {
+    remove_keys(
+        value,
+        &["channels", "comments"],
+        &["created-at", "updated-at"],
+    )
+}
+
+fn up_0_5_0(value: &mut toml::Value) -> Fallible<()> {
+    // the comments are stored in the posted order.
+    let mut last_sequences = HashMap::<String, i64>::new();
+    for entry in value["comments"].as_array_mut().ok_or_err()? {
+        let entry = entry.as_table_mut().ok_or_err()?;
+        let channel_id = entry["channel-id"].as_str().ok_or_err()?.to_owned();
+        let last_sequence = last_sequences.entry(channel_id).or_default();
+        *last_sequence += 1;
+        entry.insert("sequence".into(), toml::Value::Integer(*last_sequence));
+    }
+
+    for entry in value["channels"].as_array_mut().ok_or_err()? {
+        let entry = entry.as_table_mut().ok_or_err()?;
+        let last_sequence = last_sequences
+            .get(entry["id"].as_str().ok_or_err()?)
+            .cloned()
+            .unwrap_or_default();
+        entry.insert("last-sequence".into(), toml::Value::Integer(last_sequence));
+    }
+    Ok(())
+}
+
+fn down_0_5_0(value: &mut toml::Value) -> Fallible<()> {
+    remove_keys(value, &["comments"], &["sequence"])?;
+    remove_keys(value, &["channels"], &["last-sequence"])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::db::chat_store::ChatStore;
+    use crate::data::db::dev_flex_chat_database::{read_table, DevFlexChatDatabase};
+    use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, CommentEntity, CommentID};
+    use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+
+    use super::*;
+
+    /// Versions of the databases in the `tests/fixtures` that are written by the past releases.
+    const FIXTURES: [&str; 4] = ["0.1.0", "0.2.0", "0.3.1", "0.4.0"];
+
+    fn fixture_path(version: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(format!("database-{}.toml", version))
+    }
+
+    /// Migrates a copy of the fixture to the version of the app, and opens it.
+    fn migrate_fixture(version: &str) -> Fallible<(tempfile::TempDir, DevFlexChatDatabase)> {
+        let dir = tempfile::tempdir()?;
+        let database_path = dir.path().join("database.toml");
+        std::fs::copy(fixture_path(version), &database_path)?;
+
+        migration(
+            Some(dir.path().to_owned()),
+            StorageType::Toml,
+            false,
+            None,
+            false,
+            None,
+        )?;
+
+        let db = DevFlexChatDatabase::create(&database_path, None)?;
+        Ok((dir, db))
+    }
+
+    /// The name of the channels with the message and the sequence of their comments.
+    type Summary = Vec<(String, Vec<(String, u64)>)>;
+
+    fn summarize(db: &DevFlexChatDatabase) -> Fallible<Summary> {
+        let mut summary = vec![];
+        for channel in db.channels_created_asc()? {
+            let comments = db
+                .retrieve_comments(&channel.id)?
+                .into_iter()
+                .map(|data| (data.message, data.sequence))
+                .collect();
+            summary.push((channel.name, comments));
+        }
+        Ok(summary)
+    }
+
+    fn summary(channels: &[(&str, &[(&str, u64)])]) -> Summary {
+        channels
+            .iter()
+            .map(|(name, comments)| {
+                (
+                    name.to_string(),
+                    comments
+                        .iter()
+                        .map(|(message, sequence)| (message.to_string(), *sequence))
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fixture_0_1_0() -> Fallible<()> {
+        let (_dir, db) = migrate_fixture("0.1.0")?;
+        assert_eq!(
+            summarize(&db)?,
+            summary(&[("General", &[("hello", 1), ("hi", 2)])])
+        );
+
+        let channel = &db.channels_created_asc()?[0];
+        assert_eq!(channel.last_sequence, 2);
+        let comment_id = CommentID("9f0e5d3c-1f4b-4c59-9a0a-3b8f3f6a2c02".parse()?);
+        assert!(db.find_comment(&channel.id, &comment_id)?.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_0_2_0() -> Fallible<()> {
+        let (_dir, db) = migrate_fixture("0.2.0")?;
+        assert_eq!(
+            summarize(&db)?,
+            summary(&[("General", &[("first", 1), ("second", 2), ("third", 3)])])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_0_3_1() -> Fallible<()> {
+        let (_dir, db) = migrate_fixture("0.3.1")?;
+        assert_eq!(
+            summarize(&db)?,
+            summary(&[
+                ("General", &[("general 1", 1), ("general 2", 2)]),
+                ("Random", &[("random 1", 1)]),
+            ])
+        );
+
+        let channel_id = ChannelID("1d2c3b4a-5e6f-4708-9a1b-2c3d4e5f6a02".parse()?);
+        assert_eq!(db.find_channel(&channel_id)?.ok_or_err()?.last_sequence, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixture_0_4_0() -> Fallible<()> {
+        let (_dir, db) = migrate_fixture("0.4.0")?;
+        assert_eq!(
+            summarize(&db)?,
+            summary(&[("General", &[("general 1", 1), ("general 2", 2)])])
+        );
+
+        let channel = &db.channels_created_asc()?[0];
+        assert_eq!(
+            db.retrieve_comments(&channel.id)?[1]
+                .created_at
+                .to_rfc3339(),
+            "2020-04-02T00:00:00+00:00"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixtures_round_trip() -> Fallible<()> {
+        for version in &FIXTURES {
+            let fixture = read_file_as_toml_value(fixture_path(version), None)?;
+            let version_code = retrieve_database_version_code(&fixture)?;
+            let (fixture_version, flush_code) = convert_to_version(version_code);
+            assert_eq!(
+                convert_to_version_code(&fixture_version, flush_code),
+                version_code
+            );
+
+            let (dir, db) = migrate_fixture(version)?;
+            assert_eq!(db.database_version()?, generate_latest_database_version()?);
+            drop(db);
+
+            // the rollback restores the fixture except the history.
+            migration(
+                Some(dir.path().to_owned()),
+                StorageType::Toml,
+                false,
+                Some(version.parse()?),
+                false,
+                None,
+            )?;
+            let mut value = read_file_as_toml_value(dir.path().join("database.toml"), None)?;
+            let history = value
+                .as_table_mut()
+                .ok_or_err()?
+                .remove("migration-history")
+                .ok_or_err()?;
+            assert_eq!(history.as_array().ok_or_err()?.len(), 2);
+            assert_eq!(value, fixture, "{}", version);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_rollback() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+        let database_path = dir.path().join("database.toml");
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        {
+            let repo =
+                DevFlexChatRepository::prepare(&StorageType::Wal, database_dir.clone(), None)?;
+            repo.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            repo.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: "message".into(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+
+        migration(
+            database_dir.clone(),
+            StorageType::Wal,
+            false,
+            Some([0, 3, 0].into()),
+            false,
+            None,
+        )?;
+        let value = read_file_as_toml_value(&database_path, None)?;
+        assert_eq!(
+            retrieve_database_version_code(&value)?,
+            convert_to_version_code(&[0, 3, 0].into(), 0)
+        );
+        assert!(value["comments"][0].get("sequence").is_none());
+        assert!(value["comments"][0].get("created-at").is_none());
+        assert!(read_table(&database_path, None).is_err());
+
+        migration(
+            database_dir.clone(),
+            StorageType::Toml,
+            false,
+            None,
+            false,
+            None,
+        )?;
+        let table = read_table(&database_path, None)?;
+        assert_eq!(table.comments[0].sequence, 1);
+        assert_eq!(table.channels[0].last_sequence, 1);
+        assert_eq!(
+            table
+                .migration_history
+                .iter()
+                .map(|data| convert_to_version(data.to_version_code).0)
+                .collect::<Vec<_>>(),
+            vec![[0, 3, 0].into(), generate_latest_database_version()?.0]
+        );
+
+        assert!(migration(
+            database_dir,
+            StorageType::Toml,
+            false,
+            Some([9, 0, 0].into()),
+            false,
+            None,
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+        let database_path = dir.path().join("database.toml");
+        {
+            let repo =
+                DevFlexChatRepository::prepare(&StorageType::Toml, database_dir.clone(), None)?;
+            for name in &["General", "Random"] {
+                repo.save_channel(ChannelEntity {
+                    id: ChannelID(uuid::Uuid::new_v4()),
+                    name: name.to_string(),
+                    last_sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })?;
+            }
+        }
+        let data = std::fs::read(&database_path)?;
+
+        migration(
+            database_dir.clone(),
+            StorageType::Toml,
+            false,
+            Some([0, 3, 0].into()),
+            true,
+            None,
+        )?;
+        assert_eq!(std::fs::read(&database_path)?, data);
+
+        // the channels cannot be merged.
+        assert!(migration(
+            database_dir,
+            StorageType::Toml,
+            false,
+            Some([0, 2, 0].into()),
+            true,
+            None,
+        )
+        .is_err());
+
+        let previous_value = read_file_as_toml_value(&database_path, None)?;
+        let mut value = previous_value.clone();
+        down_0_5_0(&mut value)?;
+        set_database_version_code(
+            &mut value,
+            Some(convert_to_version_code(&[0, 4, 0].into(), 0)),
+        )?;
+        assert_eq!(
+            describe_changes(&previous_value, &value)?,
+            vec![
+                "version-code: v0.5.0 -> v0.4.0".to_string(),
+                "channels: 2 of 2 entries rewritten, removed fields: last-sequence".to_string(),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_auto_migrate() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+        DevFlexChatRepository::prepare(&StorageType::Toml, database_dir.clone(), None)?;
+        migration(
+            database_dir.clone(),
+            StorageType::Toml,
+            false,
+            Some([0, 4, 0].into()),
+            false,
+            None,
+        )?;
+        assert_eq!(
+            pending_migrations(database_dir.clone(), &StorageType::Toml, None)?,
+            vec!["migrate up to v0.5.0".to_string()]
+        );
+
+        auto_migrate(database_dir.clone(), &StorageType::Toml, None)?;
+        assert!(pending_migrations(database_dir.clone(), &StorageType::Toml, None)?.is_empty());
+        let backup_dir = std::fs::read_dir(dir.path().join("migration-backups"))?
+            .next()
+            .ok_or_err()??
+            .path();
+        let value = read_file_as_toml_value(backup_dir.join("database.toml"), None)?;
+        assert_eq!(
+            retrieve_database_version_code(&value)?,
+            convert_to_version_code(&[0, 4, 0].into(), 0)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::data::db::comment_archive;
+use crate::data::db::database_cipher::{self, DatabaseCipher};
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::db::wal_chat_database::merge_log_into_snapshot;
+use crate::data::repository::dev_flex_chat_repository::ensure_cipher_supported;
+use crate::model::storage_type::StorageType;
+use crate::prelude::*;
+
+/// Re-encrypts the files of the database from the `cipher` to the `new_cipher`.
+///
+/// The `cipher` is `None` to encrypt a plaintext database, and the `new_cipher` is `None` to
+/// decrypt it. The log is merged into the snapshot first. The files that are already encrypted
+/// with the `new_cipher` are skipped, so that an interrupted rekey can be run again. The backup
+/// files next to the database are removed because they have the previous key.
+///
+/// The copies of the `migration-backups` and the archives of the `backup` that are given by the
+/// `backups` are also re-encrypted. The SQLite storage is rejected because it is not encrypted,
+/// see `ensure_cipher_supported`. Fails with the list of the files that are not re-encrypted,
+/// after the other files are re-encrypted.
+pub fn rekey(
+    database_dir: Option<PathBuf>,
+    storage: StorageType,
+    cipher: Option<DatabaseCipher>,
+    new_cipher: Option<DatabaseCipher>,
+    backups: &[PathBuf],
+) -> Fallible<()> {
+    match storage {
+        StorageType::Toml | StorageType::Wal => (),
+        StorageType::Sqlite => {
+            ensure_cipher_supported(&storage, cipher.as_ref().or(new_cipher.as_ref()))?
+        }
+        StorageType::Memory => failure::bail!("memory storage has nothing to rekey"),
+    }
+
+    if cipher.is_none() && new_cipher.is_none() {
+        failure::bail!("database is not encrypted. specify the new key to encrypt it");
+    }
+
+    let _database_lock = DatabaseLock::try_acquire(database_dir.clone())?;
+
+    let cipher = cipher.as_ref();
+    let new_cipher = new_cipher.as_ref();
+
+    let database_path = crate::util::get_database_file_path(database_dir.clone());
+    let log_path = crate::util::get_database_log_file_path(database_dir.clone());
+    with_either_cipher(cipher, new_cipher, |data| {
+        merge_log_into_snapshot(&database_path, &log_path, data)
+    })?;
+    rekey_file(&database_path, cipher, new_cipher)?;
+
+    let quarantine_path = crate::util::get_database_quarantine_file_path(database_dir.clone());
+    if quarantine_path.exists() {
+        rekey_lines(&quarantine_path, cipher, new_cipher)?;
+    }
+
+    let archive_files =
+        comment_archive::list_all_files(&crate::util::get_archive_dir_path(database_dir.clone()))?;
+    info!("rekey {} archive files", archive_files.len());
+    for file_path in &archive_files {
+        rekey_file(file_path, cipher, new_cipher)?;
+    }
+
+    let mut failed_files = vec![];
+    for file_path in list_migration_backup_files(database_dir)? {
+        let ret = match file_path.file_name().and_then(|data| data.to_str()) {
+            Some("database.toml") => rekey_file(&file_path, cipher, new_cipher),
+            Some("database.log") => rekey_lines(&file_path, cipher, new_cipher),
+            // the sqlite storage is not encrypted.
+            Some("database.sqlite") => Ok(()),
+            _ => Err(failure::format_err!("unknown file")),
+        };
+        if let Err(e) = ret {
+            failed_files.push(format!("{:?} ({})", file_path, e));
+        }
+    }
+
+    info!("rekey {} backup archives", backups.len());
+    for file_path in backups {
+        if let Err(e) = rekey_file(file_path, cipher, new_cipher) {
+            failed_files.push(format!("{:?} ({})", file_path, e));
+        }
+    }
+
+    if !failed_files.is_empty() {
+        failure::bail!(
+            "failed to rekey {} files: {}",
+            failed_files.len(),
+            failed_files.join(", ")
+        );
+    }
+
+    info!("succeeded to rekey");
+    Ok(())
+}
+
+/// Returns the files of the `backup_database_files` of the migration.
+fn list_migration_backup_files(database_dir: Option<PathBuf>) -> Fallible<Vec<PathBuf>> {
+    let backup_dir = crate::util::get_migration_backup_dir_path(database_dir);
+    if !backup_dir.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut files = vec![];
+    for entry in std::fs::read_dir(&backup_dir)? {
+        let dir_path = entry?.path();
+        if !dir_path.is_dir() {
+            files.push(dir_path);
+            continue;
+        }
+        for entry in std::fs::read_dir(&dir_path)? {
+            files.push(entry?.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn rekey_file(
+    file_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+    new_cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    info!("rekey {:?}", file_path);
+
+    let data = with_either_cipher(cipher, new_cipher, |data| {
+        database_cipher::read_file(file_path, data)
+    })?;
+    database_cipher::write_file_atomically(file_path, &data, new_cipher)?;
+    crate::util::remove_backup_file(file_path)
+}
+
+fn rekey_lines(
+    file_path: &Path,
+    cipher: Option<&DatabaseCipher>,
+    new_cipher: Option<&DatabaseCipher>,
+) -> Fallible<()> {
+    info!("rekey {:?}", file_path);
+
+    let mut data = vec![];
+    for line in std::fs::read_to_string(file_path)?.lines() {
+        let line = with_either_cipher(cipher, new_cipher, |data| {
+            database_cipher::decode_line(line, data)
+        })?;
+        data.extend(database_cipher::encode_line(line.as_bytes(), new_cipher)?);
+        data.push(b'\n');
+    }
+    crate::util::write_file_atomically(file_path, &data)?;
+    crate::util::remove_backup_file(file_path)
+}
+
+/// Calls the `f` with the `cipher`, and with the `new_cipher` if it fails. Returns the error of
+/// the `cipher` if both fail.
+fn with_either_cipher<T, F>(
+    cipher: Option<&DatabaseCipher>,
+    new_cipher: Option<&DatabaseCipher>,
+    f: F,
+) -> Fallible<T>
+where
+    F: Fn(Option<&DatabaseCipher>) -> Fallible<T>,
+{
+    match f(cipher) {
+        Ok(data) => Ok(data),
+        Err(e) => f(new_cipher).map_err(|_| e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::{
+        ChannelEntity, ChannelID, CommentEntity, CommentID,
+    };
+    use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+    use crate::feature::backup::{backup, restore};
+
+    use super::*;
+
+    #[test]
+    fn test_rekey() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+        let cipher = DatabaseCipher::new(&[1; 32])?;
+        let new_cipher = DatabaseCipher::new(&[2; 32])?;
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        {
+            let repo = DevFlexChatRepository::prepare(
+                &StorageType::Wal,
+                database_dir.clone(),
+                Some(cipher.clone()),
+            )?;
+            repo.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            for message in &["one", "two"] {
+                repo.save_comment(CommentEntity {
+                    id: CommentID(uuid::Uuid::new_v4()),
+                    channel_id: channel_id.clone(),
+                    name: "name".into(),
+                    message: message.to_string(),
+                    sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                })?;
+            }
+            repo.archive_comments_until(&channel_id, 1)?;
+        }
+        let data = std::fs::read(dir.path().join("database.toml"))?;
+        assert!(database_cipher::is_encrypted(&data));
+
+        let migration_backup_dir = dir
+            .path()
+            .join("migration-backups")
+            .join("20200101T000000Z");
+        std::fs::create_dir_all(&migration_backup_dir)?;
+        std::fs::write(migration_backup_dir.join("database.toml"), &data)?;
+        let backup_archive = dir.path().join("backup.toml");
+        backup(
+            database_dir.clone(),
+            StorageType::Wal,
+            &backup_archive,
+            Some(&cipher),
+        )?;
+        let backups = vec![backup_archive.clone()];
+
+        rekey(
+            database_dir.clone(),
+            StorageType::Wal,
+            Some(cipher.clone()),
+            Some(new_cipher.clone()),
+            &backups,
+        )?;
+        assert!(!dir.path().join("database.log").exists());
+        assert!(!dir.path().join("database.toml.bak").exists());
+        assert!(DevFlexChatRepository::prepare(
+            &StorageType::Toml,
+            database_dir.clone(),
+            Some(cipher)
+        )
+        .is_err());
+
+        // interrupted and run again.
+        rekey(
+            database_dir.clone(),
+            StorageType::Wal,
+            None,
+            Some(new_cipher.clone()),
+            &backups,
+        )?;
+
+        rekey(
+            database_dir.clone(),
+            StorageType::Toml,
+            Some(new_cipher),
+            None,
+            &backups,
+        )?;
+        let data = std::fs::read(migration_backup_dir.join("database.toml"))?;
+        assert!(!database_cipher::is_encrypted(&data));
+        let restore_dir = tempfile::tempdir()?;
+        restore(
+            Some(restore_dir.path().to_owned()),
+            StorageType::Toml,
+            &backup_archive,
+            None,
+        )?;
+
+        let repo = DevFlexChatRepository::prepare(&StorageType::Toml, database_dir, None)?;
+        assert_eq!(repo.comments(&channel_id)?[0].message, "two");
+        assert_eq!(
+            repo.archived_comments(&channel_id, 0, None)?[0].message,
+            "one"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_rekey_unknown_migration_backup() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let database_dir = Some(dir.path().to_owned());
+        let cipher = DatabaseCipher::new(&[1; 32])?;
+        DevFlexChatRepository::prepare(&StorageType::Toml, database_dir.clone(), None)?;
+        let migration_backup_dir = dir
+            .path()
+            .join("migration-backups")
+            .join("20200101T000000Z");
+        std::fs::create_dir_all(&migration_backup_dir)?;
+        std::fs::write(migration_backup_dir.join("unknown"), "data")?;
+
+        let err = rekey(
+            database_dir.clone(),
+            StorageType::Toml,
+            None,
+            Some(cipher.clone()),
+            &[],
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("unknown"));
+        DevFlexChatRepository::prepare(&StorageType::Toml, database_dir, Some(cipher))?;
+        Ok(())
+    }
+}
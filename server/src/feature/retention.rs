@@ -0,0 +1,263 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use log::{info, warn};
+use serde_derive::Deserialize;
+use uuid::Uuid;
+
+use crate::data::db::entity::dev_flex_chat_entity::CommentEntity;
+use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::model::juniper_object::Context;
+use crate::prelude::*;
+
+/// Contents of the `retention.toml`.
+///
+/// ```toml
+/// # applied to the channels that are not listed.
+/// [default]
+/// max-age-days = 365
+///
+/// [[channels]]
+/// id = "1ad9a8a0-5a47-4b2e-8e3a-7f0b7e6a0e61"
+/// max-count = 1000
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct RetentionConfig {
+    #[serde(default)]
+    default: RetentionPolicy,
+
+    #[serde(default)]
+    channels: Vec<ChannelRetention>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RetentionPolicy {
+    #[serde(rename = "max-age-days")]
+    max_age_days: Option<u32>,
+
+    #[serde(rename = "max-count")]
+    max_count: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelRetention {
+    id: Uuid,
+
+    #[serde(rename = "max-age-days")]
+    max_age_days: Option<u32>,
+
+    #[serde(rename = "max-count")]
+    max_count: Option<u32>,
+}
+
+impl RetentionConfig {
+    /// Reads the `retention.toml`, or returns the empty config if it does not exist.
+    pub fn read(file_path: &Path) -> Fallible<Self> {
+        if !file_path.exists() {
+            return Ok(Default::default());
+        }
+
+        let file = std::fs::File::open(file_path)?;
+        let mut reader = std::io::BufReader::new(file);
+        let mut data = String::new();
+        reader.read_to_string(&mut data)?;
+        Ok(toml::from_str(&data)?)
+    }
+
+    fn policy(&self, channel_id: &Uuid) -> RetentionPolicy {
+        match self.channels.iter().find(|data| data.id == *channel_id) {
+            Some(data) => RetentionPolicy {
+                max_age_days: data.max_age_days,
+                max_count: data.max_count,
+            },
+            None => self.default.clone(),
+        }
+    }
+}
+
+/// Moves the expired comments of all channels to the archive, and returns the number of them.
+pub fn sweep(
+    repo: &DevFlexChatRepository,
+    config: &RetentionConfig,
+    now: DateTime<Utc>,
+) -> Fallible<usize> {
+    let mut count = 0;
+    for channel in repo.channels()? {
+        let policy = config.policy(&channel.id.0);
+        if policy.max_age_days.is_none() && policy.max_count.is_none() {
+            continue;
+        }
+
+        let comments = repo.comments(&channel.id)?;
+        if let Some(sequence) = find_expired_sequence(&comments, &policy, now) {
+            count += repo.archive_comments_until(&channel.id, sequence)?;
+        }
+    }
+
+    Ok(count)
+}
+
+/// Runs the `sweep` with the `retention.toml` of the `database_dir` at each `interval`.
+///
+/// The `retention.toml` is read at each sweep so that it can be edited without restarting.
+pub fn spawn_sweeper(
+    context: Arc<Context>,
+    database_dir: Option<PathBuf>,
+    interval: Duration,
+) -> std::thread::JoinHandle<()> {
+    let retention_path = crate::util::get_retention_file_path(database_dir);
+    std::thread::spawn(move || loop {
+        std::thread::sleep(interval);
+
+        let ret = RetentionConfig::read(&retention_path)
+            .and_then(|config| sweep(&context.chat_repo, &config, Utc::now()));
+        match ret {
+            Ok(0) => (),
+            Ok(count) => info!("archived {} comments", count),
+            Err(e) => warn!("failed to sweep: {:?}", e),
+        }
+    })
+}
+
+/// Returns the last `sequence` of the comments to expire.
+///
+/// The comments are expired in the `sequence` order, so that a comment whose `created_at` is
+/// newer than the expired one is expired together.
+fn find_expired_sequence(
+    comments: &[CommentEntity],
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+) -> Option<u64> {
+    let by_count = match policy.max_count {
+        Some(max_count) if comments.len() > max_count as usize => {
+            Some(comments[comments.len() - max_count as usize - 1].sequence)
+        }
+        _ => None,
+    };
+
+    let by_age = policy.max_age_days.and_then(|max_age_days| {
+        let threshold = now - chrono::Duration::days(max_age_days as i64);
+        comments
+            .iter()
+            .rev()
+            .find(|data| data.created_at < threshold)
+            .map(|data| data.sequence)
+    });
+
+    by_count.max(by_age)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use futures::Stream;
+
+    use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, ChannelID, CommentID};
+    use crate::data::event_bus::{ChatEvent, EventFilter};
+    use crate::model::storage_type::StorageType;
+
+    use super::*;
+
+    #[test]
+    fn test_sweep() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let repo =
+            DevFlexChatRepository::prepare(&StorageType::Toml, Some(dir.path().to_owned()), None)?;
+        let mut channel_ids = vec![];
+        for name in &["General", "Random"] {
+            let channel_id = ChannelID(uuid::Uuid::new_v4());
+            repo.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: name.to_string(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+            for day in 1..=5 {
+                let created_at = Utc.ymd(2020, 4, day).and_hms(0, 0, 0);
+                repo.save_comment(CommentEntity {
+                    id: CommentID(uuid::Uuid::new_v4()),
+                    channel_id: channel_id.clone(),
+                    name: "name".into(),
+                    message: day.to_string(),
+                    sequence: 0,
+                    created_at,
+                    updated_at: created_at,
+                })?;
+            }
+            channel_ids.push(channel_id);
+        }
+
+        let config = toml::from_str::<RetentionConfig>(&format!(
+            r#"
+[default]
+max-count = 4
+
+[[channels]]
+id = "{}"
+max-age-days = 2
+"#,
+            channel_ids[1].0
+        ))?;
+        let now = Utc.ymd(2020, 4, 6).and_hms(0, 0, 0);
+        let events = repo.subscribe(EventFilter::Channel(channel_ids[1].clone()))?;
+        assert_eq!(sweep(&repo, &config, now)?, 1 + 3);
+        assert_eq!(sweep(&repo, &config, now)?, 0);
+
+        let sequences = |comments: Vec<CommentEntity>| {
+            comments
+                .into_iter()
+                .map(|data| data.sequence)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequences(repo.comments(&channel_ids[0])?), vec![2, 3, 4, 5]);
+        assert_eq!(sequences(repo.comments(&channel_ids[1])?), vec![4, 5]);
+        assert_eq!(
+            sequences(repo.archived_comments(&channel_ids[1], 0, None)?),
+            vec![1, 2, 3]
+        );
+
+        // the subscribers know that the comments are removed from the channel.
+        let deleted = events
+            .take(3)
+            .wait()
+            .map(|data| match data {
+                Ok(ChatEvent::CommentDeleted(data)) => Ok(data),
+                _ => Err(failure::format_err!("unexpected event")),
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        assert_eq!(sequences(deleted), vec![1, 2, 3]);
+
+        // the sequence continues after the archival.
+        let entity = repo.save_comment(CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_ids[1].clone(),
+            name: "name".into(),
+            message: "new".into(),
+            sequence: 0,
+            created_at: now,
+            updated_at: now,
+        })?;
+        assert_eq!(entity.sequence, 6);
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use futures::Stream;
+use log::{info, warn};
+use serde_derive::Serialize;
+use url::Url;
+
+use crate::data::db::entity::dev_flex_chat_entity::ChannelID;
+use crate::data::event_bus::{ChatEvent, EventFilter};
+use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::prelude::*;
+
+/// Body of the request to the webhook.
+#[derive(Serialize)]
+struct WebhookPayload<'a> {
+    event: &'static str,
+
+    #[serde(rename = "channel-id")]
+    channel_id: &'a ChannelID,
+
+    data: &'a ChatEvent,
+}
+
+/// Starts a thread that posts the events of the `chat_repo` to the `url` as JSON. The changes of
+/// the presence are not posted because they are not saved to the storage.
+///
+/// The events are posted in order. An event that fails to be posted is logged and dropped so
+/// that the webhook does not block the following events.
+pub fn spawn_webhook(
+    chat_repo: &DevFlexChatRepository,
+    url: Url,
+) -> Fallible<std::thread::JoinHandle<()>> {
+    info!("webhook: {}", url);
+    let events = chat_repo.subscribe(EventFilter::All)?;
+    let client = reqwest::Client::new();
+    Ok(std::thread::spawn(move || {
+        for event in events.wait() {
+            let event = match event {
+                Ok(ChatEvent::PresenceChanged(_)) => continue,
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            if let Err(e) = post(&client, &url, &event) {
+                warn!("failed to post the {} to {}: {:?}", event.name(), url, e);
+            }
+        }
+    }))
+}
+
+fn post(client: &reqwest::Client, url: &Url, event: &ChatEvent) -> Fallible<()> {
+    client
+        .post(url.as_str())
+        .json(&WebhookPayload {
+            event: event.name(),
+            channel_id: event.channel_id(),
+            data: event,
+        })
+        .send()?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::io::BufReader;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::ChannelEntity;
+    use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+    use crate::model::presence::ChannelPresence;
+
+    use super::*;
+
+    #[test]
+    fn test_post() -> Fallible<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = Url::parse(&format!("http://{}/hook", listener.local_addr()?))?;
+        let repo = DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new()));
+        spawn_webhook(&repo, url)?;
+
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        repo.publish_presence(ChannelPresence {
+            channel_id: channel_id.clone(),
+            typing_users: vec!["alice".into()],
+            active_users: vec!["alice".into()],
+        })?;
+        repo.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+
+        let (stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let mut reader = BufReader::new(stream);
+        let mut content_length = 0;
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line)?;
+            let line = line.trim_end().to_lowercase();
+            if line.is_empty() {
+                break;
+            }
+            let mut header = line.splitn(2, ':');
+            if header.next() == Some("content-length") {
+                content_length = header.next().ok_or_err()?.trim().parse()?;
+            }
+        }
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body)?;
+        reader
+            .get_mut()
+            .write_all(b"HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n")?;
+
+        let body = serde_json::from_slice::<serde_json::Value>(&body)?;
+        assert_eq!(body["event"], "channel-created");
+        assert_eq!(body["channel-id"], channel_id.0.to_string());
+        assert_eq!(body["data"]["name"], "General");
+        Ok(())
+    }
+}
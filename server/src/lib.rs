@@ -14,7 +14,7 @@
  * limitations under the License.
  */
 
-pub(crate) mod data;
+pub mod data;
 pub mod feature;
 pub mod model;
 pub mod prelude;
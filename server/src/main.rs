@@ -15,10 +15,19 @@
  */
 
 use std::path::PathBuf;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use log::{debug, info};
 use structopt::StructOpt;
+use url::Url;
+use uuid::Uuid;
 
+use chat::data::db::database_cipher::{DatabaseCipher, KEY_ENV, NEW_KEY_ENV};
+use chat::model::export_format::ExportFormat;
+use chat::model::storage_location::StorageLocation;
+use chat::model::storage_type::StorageType;
+use chat::model::version::Version;
 use chat::prelude::*;
 
 #[derive(Debug, StructOpt)]
@@ -34,6 +43,10 @@ enum Command {
         /// Database directory path
         database_dir: Option<PathBuf>,
 
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal", "sqlite" or "memory"
+        storage: StorageType,
+
         #[structopt(short, long)]
         /// Server address
         address: String,
@@ -41,10 +54,201 @@ enum Command {
         #[structopt(short, long)]
         /// Hostname to use json result
         hostname: String,
+
+        #[structopt(long, default_value = "3600")]
+        /// Interval in seconds to archive the expired comments by retention.toml
+        retention_interval: u64,
+
+        #[structopt(long, default_value = "30")]
+        /// Timeout in seconds of the long polling unless the request specifies it
+        long_polling_timeout: u64,
+
+        #[structopt(long)]
+        /// Backs up and migrates the database if it is not up to date
+        auto_migrate: bool,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// URL to post the chat events as JSON. Can be specified multiple times
+        webhook_url: Vec<Url>,
     },
     Migration {
         /// Database directory path
         database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(long)]
+        /// Wait for the server to release the database instead of failing
+        wait: bool,
+
+        #[structopt(long)]
+        /// Version to migrate to. Older than the database to roll back. Defaults to the app
+        to: Option<Version>,
+
+        #[structopt(long)]
+        /// Prints the changes of each step without writing the database
+        dry_run: bool,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Writes a snapshot of the database, the archived comments and the retention. Can be run while
+    /// the server is running
+    Backup {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(short, long, parse(from_os_str))]
+        /// Archive file path
+        out: PathBuf,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Replaces the database with an archive of the backup
+    Restore {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(parse(from_os_str))]
+        /// Archive file path
+        archive: PathBuf,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Validates the database
+    Check {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(long)]
+        /// Moves the broken entries to database.quarantine instead of failing
+        repair: bool,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Writes the channels and the comments. Can be run while the server is running
+    Export {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(long, default_value = "jsonl")]
+        /// Output format. "jsonl" or "csv"
+        format: ExportFormat,
+
+        #[structopt(long)]
+        /// Channel ID to export. All channels are exported if not specified
+        channel: Vec<Uuid>,
+
+        #[structopt(long)]
+        /// Exports the comments created at or after the RFC 3339 date-time
+        since: Option<DateTime<Utc>>,
+
+        #[structopt(long)]
+        /// Exports the comments created before the RFC 3339 date-time
+        until: Option<DateTime<Utc>>,
+
+        #[structopt(short, long, parse(from_os_str))]
+        /// Output file path. Writes to the stdout if not specified
+        out: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Saves the channels and the comments of an exported file to the database
+    Import {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml", "wal" or "sqlite"
+        storage: StorageType,
+
+        #[structopt(long, default_value = "jsonl")]
+        /// Input format. "jsonl" or "csv"
+        format: ExportFormat,
+
+        #[structopt(parse(from_os_str))]
+        /// Input file path
+        input: PathBuf,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key to encrypt the database. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Copies the channels and the comments to another storage
+    MigrateStorage {
+        #[structopt(long)]
+        /// Source storage, e.g. "toml:./data"
+        from: StorageLocation,
+
+        #[structopt(long)]
+        /// Empty target storage, e.g. "sqlite:./data"
+        to: StorageLocation,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the base64 key of the toml and wal storage. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+    },
+    /// Re-encrypts the database, the migration backups and the given backups with a new key
+    Rekey {
+        #[structopt(short, long, parse(from_os_str))]
+        /// Database directory path
+        database_dir: Option<PathBuf>,
+
+        #[structopt(long, default_value = "toml")]
+        /// Storage backend. "toml" or "wal". The sqlite storage does not support the encryption
+        storage: StorageType,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the current base64 key. Defaults to CHAT_DATABASE_KEY
+        key_file: Option<PathBuf>,
+
+        #[structopt(long, parse(from_os_str))]
+        /// File of the new base64 key. Defaults to CHAT_DATABASE_NEW_KEY
+        new_key_file: Option<PathBuf>,
+
+        #[structopt(long)]
+        /// Decrypts the database instead of using a new key
+        decrypt: bool,
+
+        #[structopt(long = "backup", parse(from_os_str))]
+        /// Archive file of the backup to re-encrypt. Can be specified multiple times
+        backups: Vec<PathBuf>,
     },
 }
 
@@ -61,10 +265,133 @@ fn main() -> Fallible<()> {
     match opt.cmd {
         Command::Server {
             database_dir,
+            storage,
+            address,
+            hostname,
+            retention_interval,
+            long_polling_timeout,
+            auto_migrate,
+            key_file,
+            webhook_url,
+        } => chat::server::server(
+            database_dir,
+            storage,
             address,
             hostname,
-        } => chat::server::server(database_dir, address, hostname)?,
-        Command::Migration { database_dir } => chat::feature::migration::migration(database_dir)?,
+            Duration::from_secs(retention_interval),
+            Duration::from_secs(long_polling_timeout),
+            auto_migrate,
+            load_cipher(key_file, KEY_ENV)?,
+            webhook_url,
+        )?,
+        Command::Migration {
+            database_dir,
+            storage,
+            wait,
+            to,
+            dry_run,
+            key_file,
+        } => chat::feature::migration::migration(
+            database_dir,
+            storage,
+            wait,
+            to,
+            dry_run,
+            load_cipher(key_file, KEY_ENV)?,
+        )?,
+        Command::Backup {
+            database_dir,
+            storage,
+            out,
+            key_file,
+        } => chat::feature::backup::backup(
+            database_dir,
+            storage,
+            &out,
+            load_cipher(key_file, KEY_ENV)?.as_ref(),
+        )?,
+        Command::Restore {
+            database_dir,
+            storage,
+            archive,
+            key_file,
+        } => chat::feature::backup::restore(
+            database_dir,
+            storage,
+            &archive,
+            load_cipher(key_file, KEY_ENV)?.as_ref(),
+        )?,
+        Command::Check {
+            database_dir,
+            storage,
+            repair,
+            key_file,
+        } => chat::feature::check::check(
+            database_dir,
+            storage,
+            repair,
+            load_cipher(key_file, KEY_ENV)?,
+        )?,
+        Command::Export {
+            database_dir,
+            storage,
+            format,
+            channel,
+            since,
+            until,
+            out,
+            key_file,
+        } => chat::feature::export::export(
+            database_dir,
+            storage,
+            format,
+            &channel,
+            since,
+            until,
+            out.as_ref().map(AsRef::as_ref),
+            load_cipher(key_file, KEY_ENV)?.as_ref(),
+        )?,
+        Command::Import {
+            database_dir,
+            storage,
+            format,
+            input,
+            key_file,
+        } => chat::feature::export::import(
+            database_dir,
+            storage,
+            format,
+            &input,
+            load_cipher(key_file, KEY_ENV)?,
+        )?,
+        Command::MigrateStorage { from, to, key_file } => {
+            chat::feature::migrate_storage::migrate_storage(
+                from,
+                to,
+                load_cipher(key_file, KEY_ENV)?,
+            )?
+        }
+        Command::Rekey {
+            database_dir,
+            storage,
+            key_file,
+            new_key_file,
+            decrypt,
+            backups,
+        } => {
+            let new_cipher = load_cipher(new_key_file, NEW_KEY_ENV)?;
+            if decrypt == new_cipher.is_some() {
+                failure::bail!("specify either the new key or the decrypt");
+            }
+
+            chat::feature::rekey::rekey(
+                database_dir,
+                storage,
+                load_cipher(key_file, KEY_ENV)?,
+                new_cipher,
+                &backups,
+            )?
+        }
     }
 
     info!("Bye");
@@ -72,6 +399,10 @@ fn main() -> Fallible<()> {
     Ok(())
 }
 
+fn load_cipher(key_file: Option<PathBuf>, key_env: &str) -> Fallible<Option<DatabaseCipher>> {
+    DatabaseCipher::load(key_file.as_ref().map(AsRef::as_ref), key_env)
+}
+
 fn print_env() {
     debug!("CARGO: {}", env!("CARGO"));
     debug!("CARGO_MANIFEST_DIR: {}", env!("CARGO_MANIFEST_DIR"));
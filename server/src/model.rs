@@ -14,6 +14,11 @@
  * limitations under the License.
  */
 
+pub mod export_format;
 pub(crate) mod hello_model;
 pub(crate) mod juniper_object;
-pub(crate) mod version;
+pub(crate) mod metrics;
+pub(crate) mod presence;
+pub mod storage_location;
+pub mod storage_type;
+pub mod version;
@@ -14,10 +14,38 @@
  * limitations under the License.
  */
 
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::Future;
+use tokio_timer::Delay;
+
+use crate::data::event_bus::ChatEvent;
 use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::model::metrics::ChatMetrics;
+use crate::model::presence::Presence;
+use crate::prelude::*;
+
+/// Future of the event that the long polling waits for. `None` if the long polling timed out.
+pub type Notification = Box<dyn Future<Item = Option<ChatEvent>, Error = ()> + Send>;
 
 pub struct Context {
-    pub chat_repo: DevFlexChatRepository,
+    pub chat_repo: Arc<DevFlexChatRepository>,
+
+    /// Timeout of the long polling unless the request specifies it.
+    pub long_polling_timeout: Duration,
+
+    pub presence: Arc<Presence>,
+
+    pub metrics: Arc<ChatMetrics>,
+
+    /// Events that the long polling of the request received before this execution.
+    received_events: Vec<ChatEvent>,
+
+    /// Whether the long polling of the request timed out before this execution.
+    timed_out: bool,
+
+    notifications: Mutex<Vec<Notification>>,
 }
 
 impl juniper::Context for Context {
@@ -25,8 +53,66 @@ impl juniper::Context for Context {
 }
 
 impl Context {
-    pub fn new(chat_repo: DevFlexChatRepository) -> Self {
-        Self { chat_repo }
+    pub fn new(chat_repo: DevFlexChatRepository, long_polling_timeout: Duration) -> Self {
+        Self {
+            chat_repo: Arc::new(chat_repo),
+            long_polling_timeout,
+            presence: Default::default(),
+            metrics: Default::default(),
+            received_events: vec![],
+            timed_out: false,
+            notifications: Default::default(),
+        }
+    }
+
+    /// Returns the context of an execution of a request that shares the `chat_repo`.
+    pub fn with_received_events(&self, received_events: Vec<ChatEvent>, timed_out: bool) -> Self {
+        Self {
+            chat_repo: self.chat_repo.clone(),
+            long_polling_timeout: self.long_polling_timeout,
+            presence: self.presence.clone(),
+            metrics: self.metrics.clone(),
+            received_events,
+            timed_out,
+            notifications: Default::default(),
+        }
+    }
+
+    pub fn received_events(&self) -> &[ChatEvent] {
+        &self.received_events
+    }
+
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// Makes the request wait for the `notification` instead of blocking the thread. The request
+    /// is executed again with the received event after the execution, or is executed as timed out
+    /// if nothing is received within the `timeout`.
+    pub fn wait_for<F>(&self, notification: F, timeout: Duration) -> Fallible<()>
+    where
+        F: Future<Item = ChatEvent> + Send + 'static,
+    {
+        let timeout = Delay::new(Instant::now() + timeout).then(|_| Ok(None));
+        let notification = notification
+            .map(Some)
+            .map_err(|_| ())
+            .select(timeout)
+            .map(|(data, _)| data)
+            .map_err(|_| ());
+        match self.notifications.lock() {
+            Ok(mut notifications) => notifications.push(Box::new(notification)),
+            Err(e) => failure::bail!("failed to wait for notification: {:?}", e),
+        }
+
+        Ok(())
+    }
+
+    pub fn take_notifications(&self) -> Fallible<Vec<Notification>> {
+        match self.notifications.lock() {
+            Ok(mut notifications) => Ok(notifications.drain(..).collect()),
+            Err(e) => failure::bail!("failed to take notifications: {:?}", e),
+        }
     }
 }
 
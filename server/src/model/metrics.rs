@@ -0,0 +1,89 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+use crate::data::event_bus::ChatEvent;
+use crate::prelude::*;
+
+/// Numbers of the `ChatEvent`s that are published since the server started.
+#[derive(Default)]
+pub struct ChatMetrics {
+    events: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+impl ChatMetrics {
+    pub fn record(&self, event: &ChatEvent) -> Fallible<()> {
+        match self.events.lock() {
+            Ok(mut events) => {
+                *events.entry(event.name()).or_default() += 1;
+                Ok(())
+            }
+            Err(e) => failure::bail!("failed to record the metrics: {:?}", e),
+        }
+    }
+
+    /// Returns the metrics in the text format of the Prometheus.
+    pub fn render(&self) -> Fallible<String> {
+        let events = match self.events.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to render the metrics: {:?}", e),
+        };
+
+        let mut text = String::new();
+        writeln!(text, "# HELP chat_events_total Number of the chat events.")?;
+        writeln!(text, "# TYPE chat_events_total counter")?;
+        for (name, count) in events.iter() {
+            writeln!(text, "chat_events_total{{event=\"{}\"}} {}", name, count)?;
+        }
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, ChannelID};
+
+    use super::*;
+
+    #[test]
+    fn test_render() -> Fallible<()> {
+        let metrics = ChatMetrics::default();
+        let channel = ChannelEntity {
+            id: ChannelID(uuid::Uuid::new_v4()),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        metrics.record(&ChatEvent::ChannelCreated(channel.clone()))?;
+        metrics.record(&ChatEvent::ChannelRenamed(channel.clone()))?;
+        metrics.record(&ChatEvent::ChannelRenamed(channel))?;
+
+        assert_eq!(
+            metrics.render()?,
+            "# HELP chat_events_total Number of the chat events.\n\
+             # TYPE chat_events_total counter\n\
+             chat_events_total{event=\"channel-created\"} 1\n\
+             chat_events_total{event=\"channel-renamed\"} 2\n"
+        );
+        Ok(())
+    }
+}
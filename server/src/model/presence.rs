@@ -0,0 +1,264 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde_derive::Serialize;
+
+use crate::data::db::entity::dev_flex_chat_entity::ChannelID;
+use crate::prelude::*;
+
+/// Users who are typing or active in a channel, that is published when they change.
+#[derive(Clone, Serialize)]
+pub struct ChannelPresence {
+    #[serde(rename = "channel-id")]
+    pub channel_id: ChannelID,
+
+    #[serde(rename = "typing-users")]
+    pub typing_users: Vec<String>,
+
+    #[serde(rename = "active-users")]
+    pub active_users: Vec<String>,
+}
+
+/// Names of the users of each channel that expire after the `ttl` since they are touched.
+struct ExpiringNames {
+    ttl: Duration,
+    names: Mutex<HashMap<ChannelID, HashMap<String, Instant>>>,
+}
+
+impl ExpiringNames {
+    fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            names: Default::default(),
+        }
+    }
+
+    /// Extends the deadline of the `name`, and returns the channels whose names are changed by
+    /// the new name or the expiration.
+    fn touch(&self, channel_id: &ChannelID, name: String) -> Fallible<Vec<ChannelID>> {
+        let now = Instant::now();
+        let mut names = match self.names.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to touch name: {:?}", e),
+        };
+
+        let mut changed_channel_ids = expire(&mut names, now);
+        let is_new = names
+            .entry(channel_id.clone())
+            .or_default()
+            .insert(name, now + self.ttl)
+            .is_none();
+        if is_new && !changed_channel_ids.contains(channel_id) {
+            changed_channel_ids.push(channel_id.clone());
+        }
+        Ok(changed_channel_ids)
+    }
+
+    /// Removes the expired names, and returns the channels whose names are changed.
+    fn sweep(&self) -> Fallible<Vec<ChannelID>> {
+        match self.names.lock() {
+            Ok(mut names) => Ok(expire(&mut names, Instant::now())),
+            Err(e) => failure::bail!("failed to sweep names: {:?}", e),
+        }
+    }
+
+    /// Returns the names that are not expired in the ascending order, and removes the expired
+    /// ones.
+    fn names(&self, channel_id: &ChannelID) -> Fallible<Vec<String>> {
+        let now = Instant::now();
+        let mut names = match self.names.lock() {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to retrieve names: {:?}", e),
+        };
+
+        let mut ret = match names.get_mut(channel_id) {
+            Some(channel_names) => {
+                channel_names.retain(|_, deadline| now < *deadline);
+                channel_names.keys().cloned().collect::<Vec<_>>()
+            }
+            None => return Ok(vec![]),
+        };
+        if ret.is_empty() {
+            names.remove(channel_id);
+        }
+
+        ret.sort();
+        Ok(ret)
+    }
+}
+
+/// Removes the names that are expired at the `now`, and returns the channels of them. The
+/// channels that have no name are removed so that the map does not keep the inactive channels.
+fn expire(
+    names: &mut HashMap<ChannelID, HashMap<String, Instant>>,
+    now: Instant,
+) -> Vec<ChannelID> {
+    let mut changed_channel_ids = vec![];
+    names.retain(|channel_id, channel_names| {
+        let len = channel_names.len();
+        channel_names.retain(|_, deadline| now < *deadline);
+        if channel_names.len() != len {
+            changed_channel_ids.push(channel_id.clone());
+        }
+        !channel_names.is_empty()
+    });
+    changed_channel_ids
+}
+
+/// Users who are typing or active in the channels. The states are not saved to the storage and
+/// expire unless the clients send them again.
+pub struct Presence {
+    typing: ExpiringNames,
+    active: ExpiringNames,
+}
+
+impl Default for Presence {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(5), Duration::from_secs(30))
+    }
+}
+
+impl Presence {
+    pub fn new(typing_ttl: Duration, active_ttl: Duration) -> Self {
+        Self {
+            typing: ExpiringNames::new(typing_ttl),
+            active: ExpiringNames::new(active_ttl),
+        }
+    }
+
+    /// Marks the user of the `name` as typing and active in the channel, and returns the channels
+    /// whose users are changed.
+    pub fn set_typing(&self, channel_id: &ChannelID, name: String) -> Fallible<Vec<ChannelID>> {
+        let changed_channel_ids = self.active.touch(channel_id, name.clone())?;
+        Ok(merge_channel_ids(
+            changed_channel_ids,
+            self.typing.touch(channel_id, name)?,
+        ))
+    }
+
+    /// Marks the user of the `name` as active in the channel, and returns the channels whose
+    /// users are changed.
+    pub fn heartbeat(&self, channel_id: &ChannelID, name: String) -> Fallible<Vec<ChannelID>> {
+        self.active.touch(channel_id, name)
+    }
+
+    /// Removes the expired users, and returns the channels whose users are changed.
+    pub fn sweep(&self) -> Fallible<Vec<ChannelID>> {
+        Ok(merge_channel_ids(
+            self.typing.sweep()?,
+            self.active.sweep()?,
+        ))
+    }
+
+    pub fn typing_users(&self, channel_id: &ChannelID) -> Fallible<Vec<String>> {
+        self.typing.names(channel_id)
+    }
+
+    pub fn active_users(&self, channel_id: &ChannelID) -> Fallible<Vec<String>> {
+        self.active.names(channel_id)
+    }
+
+    pub fn channel_presence(&self, channel_id: &ChannelID) -> Fallible<ChannelPresence> {
+        Ok(ChannelPresence {
+            channel_id: channel_id.clone(),
+            typing_users: self.typing_users(channel_id)?,
+            active_users: self.active_users(channel_id)?,
+        })
+    }
+}
+
+fn merge_channel_ids(mut channel_ids: Vec<ChannelID>, other: Vec<ChannelID>) -> Vec<ChannelID> {
+    for channel_id in other {
+        if !channel_ids.contains(&channel_id) {
+            channel_ids.push(channel_id);
+        }
+    }
+    channel_ids
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presence() -> Fallible<()> {
+        let presence = Presence::new(Duration::from_millis(100), Duration::from_millis(300));
+        let channel_ids = (0..2)
+            .map(|_| ChannelID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            presence.heartbeat(&channel_ids[0], "bob".into())?,
+            vec![channel_ids[0].clone()]
+        );
+        assert_eq!(
+            presence.set_typing(&channel_ids[0], "alice".into())?,
+            vec![channel_ids[0].clone()]
+        );
+        presence.heartbeat(&channel_ids[1], "carol".into())?;
+        assert!(presence
+            .heartbeat(&channel_ids[1], "carol".into())?
+            .is_empty());
+        assert_eq!(presence.typing_users(&channel_ids[0])?, vec!["alice"]);
+        assert_eq!(
+            presence.active_users(&channel_ids[0])?,
+            vec!["alice", "bob"]
+        );
+        assert_eq!(presence.active_users(&channel_ids[1])?, vec!["carol"]);
+
+        std::thread::sleep(Duration::from_millis(200));
+        presence.heartbeat(&channel_ids[0], "bob".into())?;
+        assert!(presence.typing_users(&channel_ids[0])?.is_empty());
+        assert_eq!(
+            presence.active_users(&channel_ids[0])?,
+            vec!["alice", "bob"]
+        );
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(presence.active_users(&channel_ids[0])?, vec!["bob"]);
+        assert!(presence.active_users(&channel_ids[1])?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep() -> Fallible<()> {
+        let presence = Presence::new(Duration::from_millis(100), Duration::from_millis(100));
+        let channel_ids = (0..2)
+            .map(|_| ChannelID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+
+        presence.set_typing(&channel_ids[0], "alice".into())?;
+        std::thread::sleep(Duration::from_millis(200));
+
+        // the expired channel is changed and removed by the touch of the other channel.
+        assert_eq!(
+            presence.heartbeat(&channel_ids[1], "bob".into())?,
+            channel_ids
+        );
+        assert_eq!(presence.active.names.lock().unwrap().len(), 1);
+        assert_eq!(presence.sweep()?, vec![channel_ids[0].clone()]);
+        assert!(presence.typing.names.lock().unwrap().is_empty());
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(presence.sweep()?, vec![channel_ids[1].clone()]);
+        assert!(presence.active.names.lock().unwrap().is_empty());
+        Ok(())
+    }
+}
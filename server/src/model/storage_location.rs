@@ -0,0 +1,50 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::model::storage_type::StorageType;
+
+/// Storage and its database directory in the form of `<storage>:<database-dir>`, e.g.
+/// `toml:./data`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StorageLocation {
+    pub storage: StorageType,
+    pub database_dir: PathBuf,
+}
+
+impl FromStr for StorageLocation {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let index = match s.find(':') {
+            Some(data) => data,
+            None => failure::bail!("expected <storage>:<database-dir>: {}", s),
+        };
+
+        Ok(Self {
+            storage: s[..index].parse()?,
+            database_dir: PathBuf::from(&s[index + 1..]),
+        })
+    }
+}
+
+impl std::fmt::Display for StorageLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.storage, self.database_dir.display())
+    }
+}
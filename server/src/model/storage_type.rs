@@ -0,0 +1,57 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::str::FromStr;
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum StorageType {
+    /// `database.toml` in the database directory.
+    Toml,
+
+    /// `database.log` and the `database.toml` snapshot in the database directory.
+    Wal,
+
+    /// `database.sqlite` in the database directory.
+    Sqlite,
+
+    /// Volatile storage. All data is lost when the server stops.
+    Memory,
+}
+
+impl FromStr for StorageType {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "toml" => Ok(StorageType::Toml),
+            "wal" => Ok(StorageType::Wal),
+            "sqlite" => Ok(StorageType::Sqlite),
+            "memory" => Ok(StorageType::Memory),
+            _ => failure::bail!("unsupported storage: {}", s),
+        }
+    }
+}
+
+impl std::fmt::Display for StorageType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StorageType::Toml => write!(f, "toml"),
+            StorageType::Wal => write!(f, "wal"),
+            StorageType::Sqlite => write!(f, "sqlite"),
+            StorageType::Memory => write!(f, "memory"),
+        }
+    }
+}
@@ -14,21 +14,48 @@
  * limitations under the License.
  */
 
+use std::cmp::Ordering;
 use std::str::FromStr;
 
 use crate::prelude::*;
 
-#[derive(Debug, Eq, Ord, PartialEq)]
+/// Semantic version with the pre-release and the build metadata.
+///
+/// The build metadata is ignored by the comparison as the precedence of the semver, so that
+/// `1.2.3+a` equals to `1.2.3+b`.
+#[derive(Clone, Debug)]
 pub struct Version {
     pub major: u16,
     pub minor: u16,
     pub patch: u16,
+
+    /// Identifiers of the pre-release, e.g. `beta` and `1` of `1.2.3-beta.1`.
+    pub pre: Vec<Identifier>,
+
+    /// Identifiers of the build metadata, e.g. `20200401` of `1.2.3+20200401`.
+    pub build: Vec<String>,
+}
+
+/// Identifier of the pre-release.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Identifier {
+    Numeric(u64),
+    AlphaNumeric(String),
 }
 
 impl Version {
     pub fn parse<T: AsRef<str>>(version: T) -> Fallible<Self> {
         Ok(version.as_ref().parse()?)
     }
+
+    pub fn is_prerelease(&self) -> bool {
+        !self.pre.is_empty()
+    }
+
+    /// Returns the release of the version without the pre-release and the build metadata.
+    pub fn to_release(&self) -> Self {
+        [self.major, self.minor, self.patch].into()
+    }
 }
 
 impl AsRef<Version> for Version {
@@ -41,11 +68,41 @@ impl FromStr for Version {
     type Err = failure::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, build) = match s.find('+') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+        let (s, pre) = match s.find('-') {
+            Some(index) => (&s[..index], Some(&s[index + 1..])),
+            None => (s, None),
+        };
+
         let v: Vec<&str> = s.split('.').collect();
+        if v.len() != 3 {
+            failure::bail!("expected major.minor.patch: {}", s);
+        }
+
+        let pre = match pre {
+            Some(pre) => split_identifiers(pre)?
+                .into_iter()
+                .map(Identifier::parse)
+                .collect::<Fallible<Vec<_>>>()?,
+            None => vec![],
+        };
+        let build = match build {
+            Some(build) => split_identifiers(build)?
+                .into_iter()
+                .map(ToOwned::to_owned)
+                .collect(),
+            None => vec![],
+        };
+
         Ok(Self {
-            major: v.get(0).ok_or_err()?.parse()?,
-            minor: v.get(1).ok_or_err()?.parse()?,
-            patch: v.get(2).ok_or_err()?.parse()?,
+            major: parse_numeric(v[0])?,
+            minor: parse_numeric(v[1])?,
+            patch: parse_numeric(v[2])?,
+            pre,
+            build,
         })
     }
 }
@@ -56,34 +113,101 @@ impl Into<Version> for [u16; 3] {
             major: self[0],
             minor: self[1],
             patch: self[2],
+            pre: vec![],
+            build: vec![],
         }
     }
 }
 
 impl std::fmt::Display for Version {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)?;
+        if !self.pre.is_empty() {
+            let pre = self.pre.iter().map(ToString::to_string).collect::<Vec<_>>();
+            write!(f, "-{}", pre.join("."))?;
+        }
+        if !self.build.is_empty() {
+            write!(f, "+{}", self.build.join("."))?;
+        }
+        Ok(())
     }
 }
 
+impl PartialEq for Version {
+    fn eq(&self, other: &Version) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Version {}
+
 impl PartialOrd<Version> for Version {
-    fn partial_cmp(&self, other: &Version) -> Option<std::cmp::Ordering> {
+    fn partial_cmp(&self, other: &Version) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Version) -> Ordering {
         let major = self.major.cmp(&other.major);
-        if major != std::cmp::Ordering::Equal {
-            return Some(major);
+        if major != Ordering::Equal {
+            return major;
         }
 
         let minor = self.minor.cmp(&other.minor);
-        if minor != std::cmp::Ordering::Equal {
-            return Some(minor);
+        if minor != Ordering::Equal {
+            return minor;
         }
 
         let patch = self.patch.cmp(&other.patch);
-        if patch != std::cmp::Ordering::Equal {
-            return Some(patch);
+        if patch != Ordering::Equal {
+            return patch;
+        }
+
+        // a pre-release has lower precedence than the release.
+        match (self.pre.is_empty(), other.pre.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Greater,
+            (false, true) => Ordering::Less,
+            (false, false) => self.pre.cmp(&other.pre),
+        }
+    }
+}
+
+impl Identifier {
+    fn parse(s: &str) -> Fallible<Self> {
+        if s.bytes().all(|data| data.is_ascii_digit()) {
+            Ok(Identifier::Numeric(parse_numeric(s)?))
+        } else {
+            Ok(Identifier::AlphaNumeric(s.to_owned()))
+        }
+    }
+}
+
+impl std::fmt::Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Identifier::Numeric(data) => write!(f, "{}", data),
+            Identifier::AlphaNumeric(data) => write!(f, "{}", data),
         }
+    }
+}
 
-        Some(std::cmp::Ordering::Equal)
+impl PartialOrd for Identifier {
+    fn partial_cmp(&self, other: &Identifier) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Identifier {
+    fn cmp(&self, other: &Identifier) -> Ordering {
+        // numeric identifiers have lower precedence than alphanumeric identifiers.
+        match (self, other) {
+            (Identifier::Numeric(a), Identifier::Numeric(b)) => a.cmp(b),
+            (Identifier::Numeric(_), Identifier::AlphaNumeric(_)) => Ordering::Less,
+            (Identifier::AlphaNumeric(_), Identifier::Numeric(_)) => Ordering::Greater,
+            (Identifier::AlphaNumeric(a), Identifier::AlphaNumeric(b)) => a.cmp(b),
+        }
     }
 }
 
@@ -92,7 +216,7 @@ impl serde::Serialize for Version {
     where
         S: serde::Serializer,
     {
-        serializer.serialize_str(&format!("{}.{}.{}", self.major, self.minor, self.patch))
+        serializer.serialize_str(&self.to_string())
     }
 }
 
@@ -126,6 +250,31 @@ impl<'de> serde::de::Visitor<'de> for StrVisitor {
     }
 }
 
+fn split_identifiers(s: &str) -> Fallible<Vec<&str>> {
+    let identifiers = s.split('.').collect::<Vec<_>>();
+    for identifier in &identifiers {
+        if identifier.is_empty()
+            || !identifier
+                .bytes()
+                .all(|data| data.is_ascii_alphanumeric() || data == b'-')
+        {
+            failure::bail!("invalid identifier: {:?}", s);
+        }
+    }
+    Ok(identifiers)
+}
+
+/// Parses the digits without the leading zeros.
+fn parse_numeric<T: FromStr<Err = std::num::ParseIntError>>(s: &str) -> Fallible<T> {
+    if s.is_empty() || !s.bytes().all(|data| data.is_ascii_digit()) {
+        failure::bail!("invalid number: {:?}", s);
+    }
+    if 1 < s.len() && s.starts_with('0') {
+        failure::bail!("leading zero: {:?}", s);
+    }
+    Ok(s.parse()?)
+}
+
 #[cfg(test)]
 mod tests {
     use crate::prelude::*;
@@ -140,6 +289,8 @@ mod tests {
                 major: 1,
                 minor: 2,
                 patch: 3,
+                pre: vec![],
+                build: vec![],
             },
         )
     }
@@ -152,8 +303,33 @@ mod tests {
                 major: 1,
                 minor: 2,
                 patch: 3,
+                pre: vec![],
+                build: vec![],
             }
         );
+
+        let version = "1.2.3-beta.1+20200401.sha-5114f85".parse::<Version>()?;
+        assert_eq!(
+            version.pre,
+            vec![
+                Identifier::AlphaNumeric("beta".into()),
+                Identifier::Numeric(1)
+            ]
+        );
+        assert_eq!(version.build, vec!["20200401", "sha-5114f85"]);
+        assert_eq!(version.to_release(), [1, 2, 3].into());
+
+        for data in &[
+            "1.2",
+            "1.2.3.4",
+            "01.2.3",
+            "1.2.3-",
+            "1.2.3-beta..1",
+            "1.2.3-01",
+            "1.2.3+a_b",
+        ] {
+            assert!(data.parse::<Version>().is_err(), "{}", data);
+        }
         Ok(())
     }
 
@@ -163,11 +339,19 @@ mod tests {
             Version {
                 major: 1,
                 minor: 2,
-                patch: 3
+                patch: 3,
+                pre: vec![],
+                build: vec![],
             }
             .to_string(),
             "1.2.3"
-        )
+        );
+        assert_eq!(
+            Version::parse("1.2.3-rc.1+build.5")
+                .map(|data| data.to_string())
+                .ok(),
+            Some("1.2.3-rc.1+build.5".to_string())
+        );
     }
 
     #[test]
@@ -176,6 +360,26 @@ mod tests {
         assert!("1.2.3".parse::<Version>()? < "1.2.4".parse()?);
         assert!("1.2.3".parse::<Version>()? < "1.3.2".parse()?);
         assert!("1.2.3".parse::<Version>()? < "2.1.2".parse()?);
+
+        // the example of the semver 2.0.0.
+        let versions = [
+            "1.0.0-alpha",
+            "1.0.0-alpha.1",
+            "1.0.0-alpha.beta",
+            "1.0.0-beta",
+            "1.0.0-beta.2",
+            "1.0.0-beta.11",
+            "1.0.0-rc.1",
+            "1.0.0",
+        ]
+        .iter()
+        .map(Version::parse)
+        .collect::<Fallible<Vec<_>>>()?;
+        for pair in versions.windows(2) {
+            assert!(pair[0] < pair[1], "{} < {}", pair[0], pair[1]);
+        }
+
+        assert!("1.2.3+a".parse::<Version>()? == "1.2.3+b".parse()?);
         Ok(())
     }
 }
@@ -16,21 +16,34 @@
 
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::Future;
+use hyper::server::conn::AddrIncoming;
+use hyper::server::Builder;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Method, Request, Response, StatusCode};
 use juniper::{FieldResult, ID};
 use log::{error, info, warn};
 use url::Url;
 
+use crate::data::db::database_cipher::DatabaseCipher;
+use crate::data::db::database_lock::DatabaseLock;
+use crate::data::event_bus::ChatEvent;
 use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
 use crate::feature::dev_flex_chat::{
-    self, Channel, ChannelInput, ChannelOrder, ChannelResponse, CommentInput, CommentResponse,
+    self, Channel, ChannelInput, ChannelOrder, ChannelResponse, Comment, CommentInput,
+    CommentResponse, PresenceResponse,
 };
+use crate::feature::{metrics, migration, retention, webhook};
 use crate::model::juniper_object::Context;
+use crate::model::storage_type::StorageType;
 use crate::prelude::*;
 
+mod channel_events;
+mod graphql;
+mod graphql_ws;
+
 type BoxFut = Box<dyn Future<Item = Response<Body>, Error = hyper::Error> + Send>;
 
 struct Query {
@@ -53,8 +66,9 @@ impl Query {
         context: &Context,
         id: Option<ID>,
         order_by: ChannelOrder,
+        timeout_seconds: Option<i32>,
     ) -> FieldResult<Vec<Channel>> {
-        dev_flex_chat::channel_long_polling(&context.chat_repo, id, order_by).map_err(|e| {
+        dev_flex_chat::channel_long_polling(context, id, order_by, timeout_seconds).map_err(|e| {
             warn!("failed to poll channel: {:?}", e);
             e
         })
@@ -110,16 +124,125 @@ impl Mutation {
             }
         }
     }
+
+    fn rename_channel(
+        context: &Context,
+        channel_id: ID,
+        name: String,
+    ) -> FieldResult<ChannelResponse> {
+        dev_flex_chat::rename_channel(&context.chat_repo, channel_id, name).map_err(|e| {
+            warn!("failed to execute the rename_channel: {:?}", e);
+            e
+        })
+    }
+
+    fn edit_comment(
+        context: &Context,
+        channel_id: ID,
+        id: ID,
+        message: String,
+    ) -> FieldResult<CommentResponse> {
+        dev_flex_chat::edit_comment(&context.chat_repo, channel_id, id, message).map_err(|e| {
+            warn!("failed to execute the edit_comment: {:?}", e);
+            e
+        })
+    }
+
+    fn delete_comment(context: &Context, channel_id: ID, id: ID) -> FieldResult<bool> {
+        dev_flex_chat::delete_comment(&context.chat_repo, channel_id, id).map_err(|e| {
+            warn!("failed to execute the delete_comment: {:?}", e);
+            e
+        })
+    }
+
+    fn set_typing(context: &Context, channel_id: ID, name: String) -> FieldResult<bool> {
+        dev_flex_chat::set_typing(context, channel_id, name).map_err(|e| {
+            warn!("failed to execute the set_typing: {:?}", e);
+            e
+        })
+    }
+
+    fn heartbeat(context: &Context, channel_id: ID, name: String) -> FieldResult<bool> {
+        dev_flex_chat::heartbeat(context, channel_id, name).map_err(|e| {
+            warn!("failed to execute the heartbeat: {:?}", e);
+            e
+        })
+    }
+}
+
+/// Root of the subscriptions that is executed for each `event`.
+///
+/// juniper 0.14 cannot execute the subscription operation, so that the `graphql_ws` executes the
+/// operation as a query of this root, and sends the result if a field is not null.
+struct Subscription {
+    event: ChatEvent,
+}
+
+#[juniper::object(Context = Context)]
+impl Subscription {
+    fn comment_added(&self, channel_id: ID) -> FieldResult<Option<Comment>> {
+        dev_flex_chat::comment_added(&self.event, channel_id)
+    }
+
+    fn comment_edited(&self, channel_id: ID) -> FieldResult<Option<Comment>> {
+        dev_flex_chat::comment_edited(&self.event, channel_id)
+    }
+
+    fn comment_deleted(&self, channel_id: ID) -> FieldResult<Option<Comment>> {
+        dev_flex_chat::comment_deleted(&self.event, channel_id)
+    }
+
+    fn presence_changed(&self, channel_id: ID) -> FieldResult<Option<PresenceResponse>> {
+        dev_flex_chat::presence_changed(&self.event, channel_id)
+    }
+
+    fn channel_added(&self) -> Option<Channel> {
+        dev_flex_chat::channel_added(&self.event)
+    }
+
+    fn channel_renamed(&self) -> Option<Channel> {
+        dev_flex_chat::channel_renamed(&self.event)
+    }
 }
 
-pub fn server(database: Option<PathBuf>, address: String, hostname: String) -> Fallible<()> {
-    let database_path = crate::util::get_database_file_path(database);
+#[allow(clippy::too_many_arguments)]
+pub fn server(
+    database: Option<PathBuf>,
+    storage: StorageType,
+    address: String,
+    hostname: String,
+    retention_interval: Duration,
+    long_polling_timeout: Duration,
+    auto_migrate: bool,
+    cipher: Option<DatabaseCipher>,
+    webhook_urls: Vec<Url>,
+) -> Fallible<()> {
     let socket_address = address.parse()?;
-    info!("database_path: {:?}", database_path);
+    info!("database_dir: {:?}", database);
+    info!("storage: {}", storage);
     info!("socket_address: {:?}", socket_address);
-    let chat_repo = DevFlexChatRepository::prepare(database_path)?;
+    info!("retention_interval: {:?}", retention_interval);
+    info!("long_polling_timeout: {:?}", long_polling_timeout);
+    let _database_lock = match storage {
+        StorageType::Memory => None,
+        _ => Some(DatabaseLock::try_acquire(database.clone())?),
+    };
+
+    if auto_migrate {
+        migration::auto_migrate(database.clone(), &storage, cipher.as_ref())?;
+    } else {
+        let pending = migration::pending_migrations(database.clone(), &storage, cipher.as_ref())?;
+        if !pending.is_empty() {
+            failure::bail!(
+                "need to migrate the database: {}. run the migration or start with --auto-migrate",
+                pending.join(", ")
+            );
+        }
+    }
 
-    let context = Arc::new(Context::new(chat_repo));
+    let chat_repo = DevFlexChatRepository::prepare(&storage, database.clone(), cipher)?;
+
+    let context = Arc::new(Context::new(chat_repo, long_polling_timeout));
     let root_node = Arc::new(juniper::RootNode::new(
         Query::default(),
         Mutation::default(),
@@ -130,30 +253,50 @@ pub fn server(database: Option<PathBuf>, address: String, hostname: String) -> F
         context.chat_repo.database_version()?
     );
 
-    hyper::rt::run(
-        hyper::Server::bind(&socket_address)
-            .serve(make_service_fn(move |_| {
-                let context = context.clone();
-                let root_node = root_node.clone();
-                service_fn(
-                    move |req| match on_request(context.clone(), root_node.clone(), req) {
-                        Ok(data) => data,
-                        Err(e) => {
-                            warn!("5xx: {:?}", e);
-                            let mut response = Response::new(Body::empty());
-                            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-                            *response.body_mut() = hyper::Body::from(format!("{:?}", e));
-                            Box::new(futures::future::ok(response))
-                        }
-                    },
-                )
-            }))
-            .map_err(|e| error!("fatal error: {:?}", e)),
-    );
+    if storage != StorageType::Memory {
+        retention::spawn_sweeper(context.clone(), database, retention_interval);
+    }
+
+    dev_flex_chat::spawn_presence_sweeper(context.clone(), Duration::from_secs(1));
+    metrics::spawn_collector(context.clone())?;
+    for url in webhook_urls {
+        webhook::spawn_webhook(&context.chat_repo, url)?;
+    }
+
+    hyper::rt::run(serve(
+        context,
+        root_node,
+        hyper::Server::try_bind(&socket_address)?,
+    ));
 
     Ok(())
 }
 
+fn serve(
+    context: Arc<Context>,
+    root_node: Arc<juniper::RootNode<'static, Query, Mutation>>,
+    builder: Builder<AddrIncoming>,
+) -> impl Future<Item = (), Error = ()> {
+    builder
+        .serve(make_service_fn(move |_| {
+            let context = context.clone();
+            let root_node = root_node.clone();
+            service_fn(
+                move |req| match on_request(context.clone(), root_node.clone(), req) {
+                    Ok(data) => data,
+                    Err(e) => {
+                        warn!("5xx: {:?}", e);
+                        let mut response = Response::new(Body::empty());
+                        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                        *response.body_mut() = hyper::Body::from(format!("{:?}", e));
+                        Box::new(futures::future::ok(response))
+                    }
+                },
+            )
+        }))
+        .map_err(|e| error!("fatal error: {:?}", e))
+}
+
 fn on_request(
     context: Arc<Context>,
     root_node: Arc<juniper::RootNode<'static, Query, Mutation>>,
@@ -162,7 +305,8 @@ fn on_request(
     info!("{:?}", req);
 
     let url = Url::parse(&format!("http://authority{}", req.uri()))?;
-    match (req.method(), url.path_segments().ok_or_err()?.next()) {
+    let mut segments = url.path_segments().ok_or_err()?;
+    match (req.method(), segments.next()) {
         (&Method::GET, Some("graphiql")) => Ok(Box::new(
             juniper_hyper::graphiql("/graphql").map(append_access_control_allow_origin_all),
         )),
@@ -170,10 +314,10 @@ fn on_request(
             warn!("TODO: Support OPTIONS method for juniper");
             Err(failure::format_err!("TODO: Support OPTIONS method"))
         }
-        (&Method::GET, Some("graphql")) => Ok(Box::new(
-            juniper_hyper::graphql(root_node, context, req)
-                .map(append_access_control_allow_origin_all),
-        )),
+        (&Method::GET, Some("graphql")) if graphql_ws::is_upgrade_request(&req) => {
+            graphql_ws::upgrade(context, root_node, req)
+        }
+        (&Method::GET, Some("graphql")) => Ok(graphql::graphql(root_node, context, req)),
         (&Method::POST, Some("graphql")) => {
             // TODO: support oauth.
             let root_node = match req.headers().get(hyper::header::AUTHORIZATION) {
@@ -191,20 +335,42 @@ fn on_request(
                 None => root_node,
             };
 
-            Ok(Box::new(
-                juniper_hyper::graphql(root_node, context, req)
-                    .map(append_access_control_allow_origin_all),
-            ))
+            Ok(graphql::graphql(root_node, context, req))
         }
-        _ => {
-            info!("404");
-            let mut response = Response::new(Body::empty());
-            *response.status_mut() = StatusCode::NOT_FOUND;
+        (&Method::GET, Some("metrics")) => {
+            let mut response = Response::new(Body::from(context.metrics.render()?));
+            response.headers_mut().insert(
+                hyper::header::CONTENT_TYPE,
+                "text/plain; version=0.0.4".parse()?,
+            );
             Ok(Box::new(futures::future::ok(response)))
         }
+        (&Method::GET, Some("channels")) => {
+            match (segments.next(), segments.next(), segments.next()) {
+                (Some(id), Some("events"), None) => {
+                    channel_events::channel_events(context, id, req)
+                }
+                _ => Ok(not_found()),
+            }
+        }
+        _ => Ok(not_found()),
     }
 }
 
+fn not_found() -> BoxFut {
+    info!("404");
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    Box::new(futures::future::ok(response))
+}
+
+fn internal_server_error(e: failure::Error) -> BoxFut {
+    warn!("5xx: {:?}", e);
+    let mut response = Response::new(Body::from(format!("{:?}", e)));
+    *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+    Box::new(futures::future::ok(response))
+}
+
 fn append_access_control_allow_origin_all(mut response: Response<Body>) -> Response<Body> {
     response.headers_mut().append(
         hyper::header::ACCESS_CONTROL_ALLOW_ORIGIN,
@@ -212,3 +378,266 @@ fn append_access_control_allow_origin_all(mut response: Response<Body>) -> Respo
     );
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::prelude::*;
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::time::Instant;
+
+    use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+
+    use super::*;
+
+    fn post(socket_address: &SocketAddr, query: &str) -> Fallible<TcpStream> {
+        let body = serde_json::json!({ "query": query }).to_string();
+        let mut stream = TcpStream::connect(socket_address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        write!(
+            stream,
+            "POST /graphql HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )?;
+        Ok(stream)
+    }
+
+    fn read_body(mut stream: TcpStream) -> Fallible<String> {
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        match response.find("\r\n\r\n") {
+            Some(index) => Ok(response[index + 4..].to_owned()),
+            None => failure::bail!("unexpected response: {}", response),
+        }
+    }
+
+    fn start_server(long_polling_timeout: Duration) -> Fallible<SocketAddr> {
+        // the server takes over the listener so that another test does not bind the port between
+        // them.
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let socket_address = listener.local_addr()?;
+        let context = Arc::new(Context::new(
+            DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new())),
+            long_polling_timeout,
+        ));
+        let root_node = Arc::new(juniper::RootNode::new(
+            Query::default(),
+            Mutation::default(),
+        ));
+        let server = serve(context, root_node, hyper::Server::from_tcp(listener)?);
+        std::thread::spawn(move || hyper::rt::run(server));
+        Ok(socket_address)
+    }
+
+    #[test]
+    fn test_long_polling_holds_no_thread() -> Fallible<()> {
+        let socket_address = start_server(Duration::from_secs(30))?;
+
+        let add_channel = |name: &str| -> Fallible<String> {
+            let body = read_body(post(
+                &socket_address,
+                &format!(
+                    r#"mutation {{ addChannel(channel: {{name: "{}"}}) {{ id }} }}"#,
+                    name
+                ),
+            )?)?;
+            let response = serde_json::from_str::<serde_json::Value>(&body)?;
+            Ok(response["data"]["addChannel"]["id"]
+                .as_str()
+                .ok_or_err()?
+                .to_owned())
+        };
+
+        let id = add_channel("General")?;
+        let polls = (0..300)
+            .map(|_| {
+                post(
+                    &socket_address,
+                    &format!(
+                        r#"{{ channelLongPolling(id: "{}", orderBy: {{direction: ASC}}) {{ name }} }}"#,
+                        id
+                    ),
+                )
+            })
+            .collect::<Fallible<Vec<_>>>()?;
+        std::thread::sleep(Duration::from_millis(500));
+
+        let now = Instant::now();
+        for _ in 0..10 {
+            assert_eq!(
+                read_body(post(&socket_address, "{ channels { name } }")?)?,
+                r#"{"data":{"channels":[{"name":"General"}]}}"#
+            );
+        }
+        assert!(now.elapsed() < Duration::from_secs(5));
+
+        add_channel("Random")?;
+        for poll in polls {
+            assert_eq!(
+                read_body(poll)?,
+                r#"{"data":{"channelLongPolling":[{"name":"Random"}]}}"#
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_presence_long_polling() -> Fallible<()> {
+        let socket_address = start_server(Duration::from_secs(30))?;
+        let body = read_body(post(
+            &socket_address,
+            r#"mutation { addChannel(channel: {name: "General"}) { id } }"#,
+        )?)?;
+        let response = serde_json::from_str::<serde_json::Value>(&body)?;
+        let id = response["data"]["addChannel"]["id"].as_str().ok_or_err()?;
+
+        let poll = post(
+            &socket_address,
+            &format!(
+                r#"{{ channel(id: "{}") {{ presenceLongPolling {{ typingUsers }} }} }}"#,
+                id
+            ),
+        )?;
+        std::thread::sleep(Duration::from_millis(500));
+
+        let now = Instant::now();
+        read_body(post(
+            &socket_address,
+            &format!(
+                r#"mutation {{ setTyping(channelId: "{}", name: "alice") }}"#,
+                id
+            ),
+        )?)?;
+        assert_eq!(
+            read_body(poll)?,
+            r#"{"data":{"channel":{"presenceLongPolling":{"typingUsers":["alice"]}}}}"#
+        );
+        assert!(now.elapsed() < Duration::from_secs(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_channel_events() -> Fallible<()> {
+        let socket_address = start_server(Duration::from_secs(30))?;
+        let body = read_body(post(
+            &socket_address,
+            r#"mutation { addChannel(channel: {name: "General"}) { id } }"#,
+        )?)?;
+        let response = serde_json::from_str::<serde_json::Value>(&body)?;
+        let id = response["data"]["addChannel"]["id"].as_str().ok_or_err()?;
+        read_body(post(
+            &socket_address,
+            &format!(
+                r#"mutation {{ addComment(comment: {{
+                    channelId: "{}", name: "alice", message: "hello"
+                }}) {{ id }} }}"#,
+                id
+            ),
+        )?)?;
+
+        let get = |path: &str| -> Fallible<TcpStream> {
+            let mut stream = TcpStream::connect(socket_address)?;
+            stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+            write!(
+                stream,
+                "GET {} HTTP/1.1\r\nHost: localhost\r\nLast-Event-ID: 0\r\n\
+                 Connection: close\r\n\r\n",
+                path
+            )?;
+            Ok(stream)
+        };
+
+        let mut response = String::new();
+        get(&format!("/channels/{}/events", uuid::Uuid::new_v4()))?
+            .read_to_string(&mut response)?;
+        assert!(response.starts_with("HTTP/1.1 404"));
+
+        let mut stream = get(&format!("/channels/{}/events", id))?;
+        let mut response = vec![];
+        while !String::from_utf8_lossy(&response).contains("\n\n") {
+            let mut buf = [0; 1024];
+            let len = stream.read(&mut buf)?;
+            if len == 0 {
+                failure::bail!("unexpected eof");
+            }
+            response.extend_from_slice(&buf[..len]);
+        }
+        let response = String::from_utf8(response)?;
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.contains("id: 1\nevent: comment\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_graphql_ws_operation() -> Fallible<()> {
+        let socket_address = start_server(Duration::from_secs(30))?;
+        let stream = TcpStream::connect(socket_address)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let request = tungstenite::http::Request::get(format!("ws://{}/graphql", socket_address))
+            .header("Sec-WebSocket-Protocol", "graphql-ws")
+            .body(())?;
+        let (mut socket, _) = match tungstenite::client(request, stream) {
+            Ok(data) => data,
+            Err(e) => failure::bail!("failed to connect: {:?}", e),
+        };
+
+        let mut send = |message: serde_json::Value| {
+            socket.write_message(tungstenite::Message::Text(message.to_string()))?;
+            let message = match socket.read_message()? {
+                tungstenite::Message::Text(data) => data,
+                data => failure::bail!("unexpected message: {:?}", data),
+            };
+            Ok(serde_json::from_str::<serde_json::Value>(&message)?)
+        };
+        assert_eq!(
+            send(serde_json::json!({"type": "connection_init", "payload": {}}))?,
+            serde_json::json!({"type": "connection_ack"})
+        );
+        assert_eq!(
+            send(serde_json::json!({
+                "type": "start",
+                "id": "1",
+                "payload": {"query": r#"mutation { addChannel(channel: {name: "General"}) { name } }"#},
+            }))?,
+            serde_json::json!({
+                "type": "data",
+                "id": "1",
+                "payload": {"data": {"addChannel": {"name": "General"}}},
+            })
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_polling_timeout() -> Fallible<()> {
+        let socket_address = start_server(Duration::from_secs(1))?;
+
+        let now = Instant::now();
+        assert_eq!(
+            read_body(post(
+                &socket_address,
+                "{ channelLongPolling(orderBy: {direction: ASC}) { name } }"
+            )?)?,
+            r#"{"data":{"channelLongPolling":[]}}"#
+        );
+        assert!(Duration::from_secs(1) <= now.elapsed());
+
+        let now = Instant::now();
+        assert_eq!(
+            read_body(post(
+                &socket_address,
+                "{ channelLongPolling(orderBy: {direction: ASC}, timeoutSeconds: 2) { name } }"
+            )?)?,
+            r#"{"data":{"channelLongPolling":[]}}"#
+        );
+        assert!(Duration::from_secs(2) <= now.elapsed());
+
+        assert!(read_body(post(
+            &socket_address,
+            "{ channelLongPolling(orderBy: {direction: ASC}, timeoutSeconds: 0) { name } }"
+        )?)?
+        .contains("timeoutSeconds must be positive"));
+        Ok(())
+    }
+}
@@ -0,0 +1,273 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use hyper::header::{ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE};
+use hyper::{Body, Request, Response, StatusCode};
+use log::info;
+use serde_json::json;
+use tokio_timer::Interval;
+
+use crate::data::db::entity::dev_flex_chat_entity::{ChannelID, CommentEntity};
+use crate::data::event_bus::{ChatEvent, EventFilter};
+use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+use crate::model::juniper_object::{Context, OrderDirection};
+use crate::prelude::*;
+use crate::server::{internal_server_error, not_found, BoxFut};
+
+/// Interval of the comment lines that keep the connection alive through the proxies.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Streams the comments of the channel of the `id` as the `text/event-stream`.
+///
+/// The `id` of the events is the `sequence` of the comments, so that the comments following the
+/// `Last-Event-ID` are sent first when the client reconnects. A comment line is sent every
+/// `KEEPALIVE_INTERVAL`. The storage is read on the blocking section of the thread pool like the
+/// GraphQL requests.
+pub fn channel_events(context: Arc<Context>, id: &str, req: Request<Body>) -> Fallible<BoxFut> {
+    let channel_id = match id.parse() {
+        Ok(data) => ChannelID(data),
+        Err(_) => return Ok(not_found()),
+    };
+
+    let last_event_id = match req.headers().get("last-event-id").map(|data| data.to_str()) {
+        Some(Ok(data)) => match data.parse() {
+            Ok(data) => Some(data),
+            Err(_) => return Ok(bad_request()),
+        },
+        Some(Err(_)) => return Ok(bad_request()),
+        None => None,
+    };
+
+    let events = futures::future::poll_fn(move || {
+        tokio_threadpool::blocking(|| -> Fallible<_> {
+            if context.chat_repo.find_channel(&channel_id)?.is_none() {
+                return Ok(None);
+            }
+            let events = comment_events(&context.chat_repo, channel_id.clone(), last_event_id)?;
+            Ok(Some(events))
+        })
+    });
+
+    Ok(Box::new(events.map_err(failure::Error::from).then(
+        |ret| -> BoxFut {
+            let events = match ret.and_then(|data| data) {
+                Ok(Some(data)) => data,
+                Ok(None) => return not_found(),
+                Err(e) => return internal_server_error(e),
+            };
+
+            let keepalive = Interval::new_interval(KEEPALIVE_INTERVAL)
+                .map(|_| ":\n\n".to_owned())
+                .map_err(|_| ());
+            let events = events
+                .select(keepalive)
+                .map_err(|_| failure::err_msg("failed to receive event").compat());
+            match Response::builder()
+                .header(CONTENT_TYPE, "text/event-stream")
+                .header(CACHE_CONTROL, "no-cache")
+                .header(ACCESS_CONTROL_ALLOW_ORIGIN, "*")
+                .body(Body::wrap_stream(events))
+            {
+                Ok(data) => Box::new(futures::future::ok(data)),
+                Err(e) => internal_server_error(e.into()),
+            }
+        },
+    )))
+}
+
+/// Returns the events of the comments following the `last_event_id`, and the comments that are
+/// saved later. The comments that are moved to the archive by the retention are also sent.
+fn comment_events(
+    repo: &DevFlexChatRepository,
+    channel_id: ChannelID,
+    last_event_id: Option<u64>,
+) -> Fallible<impl Stream<Item = String, Error = ()>> {
+    // subscribe before the retrieval so that the comments between them are not missed.
+    let events = repo.subscribe(EventFilter::Channel(channel_id.clone()))?;
+    let comments = match last_event_id {
+        Some(data) => {
+            let comments = repo.retrieve_after(&channel_id, data, None, &OrderDirection::ASC)?;
+            match comments.first() {
+                Some(comment) if comment.sequence == data + 1 => comments,
+                _ => {
+                    // the archive is read after the comments so that the comments archived between
+                    // them are not missed.
+                    let first_sequence = comments.first().map(|comment| comment.sequence);
+                    let mut archived_comments = repo
+                        .archived_comments(&channel_id, data, None)?
+                        .into_iter()
+                        .filter(|comment| match first_sequence {
+                            Some(first_sequence) => comment.sequence < first_sequence,
+                            None => true,
+                        })
+                        .collect::<Vec<_>>();
+                    archived_comments.extend(comments);
+                    archived_comments
+                }
+            }
+        }
+        None => vec![],
+    };
+
+    let last_sequence = match comments.last() {
+        Some(data) => data.sequence,
+        None => last_event_id.unwrap_or(0),
+    };
+    let comments = comments.into_iter().map(format_event).collect::<Vec<_>>();
+    let events = events.filter_map(move |event| match event {
+        ChatEvent::CommentPosted(data) if last_sequence < data.sequence => Some(format_event(data)),
+        _ => None,
+    });
+
+    Ok(futures::stream::iter_ok(comments).chain(events))
+}
+
+fn format_event(comment: CommentEntity) -> String {
+    let data = json!({
+        "id": comment.id.0.to_string(),
+        "name": comment.name,
+        "message": comment.message,
+        "sequence": comment.sequence,
+        "createdAt": comment.created_at,
+        "updatedAt": comment.updated_at,
+    });
+    format!(
+        "id: {}\nevent: comment\ndata: {}\n\n",
+        comment.sequence, data
+    )
+}
+
+fn bad_request() -> BoxFut {
+    info!("400");
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = StatusCode::BAD_REQUEST;
+    Box::new(futures::future::ok(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use futures::Future;
+
+    use crate::data::db::comment_archive::CommentArchive;
+    use crate::data::db::entity::dev_flex_chat_entity::{ChannelEntity, CommentID};
+    use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+
+    use super::*;
+
+    #[test]
+    fn test_comment_events() -> Fallible<()> {
+        let repo = DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new()));
+        let channel_ids = (0..2)
+            .map(|_| ChannelID(uuid::Uuid::new_v4()))
+            .collect::<Vec<_>>();
+        for channel_id in &channel_ids {
+            repo.save_channel(ChannelEntity {
+                id: channel_id.clone(),
+                name: "General".into(),
+                last_sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+        let save = |channel_id: &ChannelID, message: &str| {
+            repo.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: message.into(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })
+        };
+        let event_ids = |events: Vec<String>| {
+            events
+                .iter()
+                .map(|data| data.lines().next().unwrap_or_default().to_owned())
+                .collect::<Vec<_>>()
+        };
+
+        save(&channel_ids[0], "one")?;
+        save(&channel_ids[0], "two")?;
+
+        let resumed = comment_events(&repo, channel_ids[0].clone(), Some(1))?;
+        let new = comment_events(&repo, channel_ids[0].clone(), None)?;
+        save(&channel_ids[1], "other")?;
+        save(&channel_ids[0], "three")?;
+
+        let events = resumed
+            .take(2)
+            .collect()
+            .wait()
+            .map_err(|_| failure::format_err!("failed to receive events"))?;
+        assert_eq!(event_ids(events.clone()), vec!["id: 2", "id: 3"]);
+        assert!(events[0].contains(r#""message":"two""#));
+
+        let events = new
+            .take(1)
+            .collect()
+            .wait()
+            .map_err(|_| failure::format_err!("failed to receive events"))?;
+        assert_eq!(event_ids(events), vec!["id: 3"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment_events_archived() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let repo = DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new()))
+            .with_archive(CommentArchive::new(dir.path().to_owned(), None));
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        repo.save_channel(ChannelEntity {
+            id: channel_id.clone(),
+            name: "General".into(),
+            last_sequence: 0,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        })?;
+        for message in &["one", "two", "three"] {
+            repo.save_comment(CommentEntity {
+                id: CommentID(uuid::Uuid::new_v4()),
+                channel_id: channel_id.clone(),
+                name: "name".into(),
+                message: message.to_string(),
+                sequence: 0,
+                created_at: Utc::now(),
+                updated_at: Utc::now(),
+            })?;
+        }
+        assert_eq!(repo.archive_comments_until(&channel_id, 2)?, 2);
+
+        let events = comment_events(&repo, channel_id, Some(0))?
+            .take(3)
+            .collect()
+            .wait()
+            .map_err(|_| failure::format_err!("failed to receive events"))?;
+        assert_eq!(
+            events
+                .iter()
+                .map(|data| data.lines().next().unwrap_or_default())
+                .collect::<Vec<_>>(),
+            vec!["id: 1", "id: 2", "id: 3"]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,195 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::sync::Arc;
+
+use futures::future::Either;
+use futures::{Future, Stream};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use juniper::http::GraphQLRequest;
+use juniper::{InputValue, RootNode};
+use log::info;
+use url::Url;
+
+use crate::data::event_bus::ChatEvent;
+use crate::model::juniper_object::{Context, Notification};
+use crate::prelude::*;
+use crate::server::{
+    append_access_control_allow_origin_all, internal_server_error, BoxFut, Mutation, Query,
+};
+
+/// Result of an execution of a request.
+struct Execution {
+    status: StatusCode,
+    body: String,
+    notifications: Vec<Notification>,
+}
+
+/// Executes the GraphQL request of the `req` like the `juniper_hyper::graphql`.
+///
+/// The resolvers of juniper cannot wait for the long polling without blocking the thread, so that
+/// they make the `Context` wait for a `Notification` and return nothing. The request is executed
+/// again with the received event when one of the notifications is received, or as timed out when
+/// the notification times out, so that the waiting requests hold no thread.
+pub fn graphql(
+    root_node: Arc<RootNode<'static, Query, Mutation>>,
+    context: Arc<Context>,
+    req: Request<Body>,
+) -> BoxFut {
+    Box::new(parse_request(req).and_then(move |request| match request {
+        Ok(request) => execute(root_node, context, Arc::new(request), vec![], false),
+        Err(e) => {
+            info!("400: {:?}", e);
+            let mut response = Response::new(Body::from(e.to_string()));
+            *response.status_mut() = StatusCode::BAD_REQUEST;
+            Box::new(futures::future::ok(response))
+        }
+    }))
+}
+
+fn parse_request(
+    req: Request<Body>,
+) -> impl Future<Item = Fallible<GraphQLRequest>, Error = hyper::Error> {
+    if req.method() == Method::GET {
+        return Either::A(futures::future::ok(parse_get_request(&req)));
+    }
+
+    let is_graphql = match req.headers().get(CONTENT_TYPE).map(|data| data.to_str()) {
+        Some(Ok(data)) => data.starts_with("application/graphql"),
+        _ => false,
+    };
+    Either::B(req.into_body().concat2().map(move |chunk| {
+        if is_graphql {
+            Ok(GraphQLRequest::new(
+                String::from_utf8(chunk.to_vec())?,
+                None,
+                None,
+            ))
+        } else {
+            Ok(serde_json::from_slice(&chunk)?)
+        }
+    }))
+}
+
+fn parse_get_request(req: &Request<Body>) -> Fallible<GraphQLRequest> {
+    let url = Url::parse(&format!("http://authority{}", req.uri()))?;
+    let mut query = None;
+    let mut operation_name = None;
+    let mut variables = None;
+    for (key, value) in url.query_pairs() {
+        match key.as_ref() {
+            "query" => query = Some(value.into_owned()),
+            "operationName" => operation_name = Some(value.into_owned()),
+            "variables" => variables = Some(serde_json::from_str::<InputValue>(&value)?),
+            _ => (),
+        }
+    }
+
+    match query {
+        Some(query) => Ok(GraphQLRequest::new(query, operation_name, variables)),
+        None => failure::bail!("query not found"),
+    }
+}
+
+/// Executes the `request` on the blocking section of the thread pool, and executes it again after
+/// a notification if the long polling waits for it.
+fn execute(
+    root_node: Arc<RootNode<'static, Query, Mutation>>,
+    context: Arc<Context>,
+    request: Arc<GraphQLRequest>,
+    mut received_events: Vec<ChatEvent>,
+    timed_out: bool,
+) -> BoxFut {
+    let execution = {
+        let root_node = root_node.clone();
+        let context = context.with_received_events(received_events.clone(), timed_out);
+        let request = request.clone();
+        futures::future::poll_fn(move || {
+            tokio_threadpool::blocking(|| execute_once(&root_node, &context, &request))
+        })
+    };
+
+    Box::new(
+        execution
+            .map_err(failure::Error::from)
+            .then(move |ret| -> BoxFut {
+                let execution = match ret.and_then(|data| data) {
+                    Ok(data) => data,
+                    Err(e) => return internal_server_error(e),
+                };
+
+                if execution.notifications.is_empty() {
+                    let mut response = Response::new(Body::from(execution.body));
+                    *response.status_mut() = execution.status;
+                    response
+                        .headers_mut()
+                        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+                    return Box::new(futures::future::ok(append_access_control_allow_origin_all(
+                        response,
+                    )));
+                }
+
+                Box::new(futures::future::select_all(execution.notifications).then(
+                    move |ret| -> BoxFut {
+                        match ret {
+                            Ok((Some(event), _, _)) => {
+                                received_events.push(event);
+                                execute(root_node, context, request, received_events, false)
+                            }
+                            Ok((None, _, notifications)) => {
+                                // drop the subscriptions before the pruning.
+                                drop(notifications);
+                                if let Err(e) = context.chat_repo.prune_subscribers() {
+                                    return internal_server_error(e);
+                                }
+                                execute(root_node, context, request, received_events, true)
+                            }
+                            Err(_) => {
+                                internal_server_error(failure::err_msg("notification was canceled"))
+                            }
+                        }
+                    },
+                ))
+            }),
+    )
+}
+
+fn execute_once(
+    root_node: &RootNode<'static, Query, Mutation>,
+    context: &Context,
+    request: &GraphQLRequest,
+) -> Fallible<Execution> {
+    let response = request.execute(root_node, context);
+    let status = if response.is_ok() {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+    let body = serde_json::to_string(&response)?;
+
+    // the notifications of the failed execution are dropped so that the error is returned.
+    let mut notifications = context.take_notifications()?;
+    if status != StatusCode::OK {
+        notifications.clear();
+    }
+
+    Ok(Execution {
+        status,
+        body,
+        notifications,
+    })
+}
@@ -0,0 +1,473 @@
+/*
+ * Copyright 2020 sukawasatoru
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::sync::Arc;
+
+use futures::sync::mpsc::UnboundedReceiver;
+use futures::{Async, Future, Poll, Stream};
+use hyper::header::{
+    CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, UPGRADE,
+};
+use hyper::upgrade::Upgraded;
+use hyper::{Body, Request, Response, StatusCode};
+use juniper::http::GraphQLRequest;
+use juniper::parser::{Lexer, Token};
+use juniper::{EmptyMutation, InputValue, RootNode};
+use log::{info, warn};
+use serde_derive::Deserialize;
+use serde_json::json;
+use sha1::{Digest, Sha1};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::data::event_bus::{ChatEvent, EventFilter};
+use crate::model::juniper_object::Context;
+use crate::prelude::*;
+use crate::server::{BoxFut, Mutation, Query, Subscription};
+
+const PROTOCOL: &str = "graphql-ws";
+
+/// GUID of the `Sec-WebSocket-Accept` that is defined by the RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {},
+    Start { id: String, payload: StartPayload },
+    Stop { id: String },
+    ConnectionTerminate {},
+}
+
+#[derive(Deserialize)]
+struct StartPayload {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    variables: Option<InputValue>,
+}
+
+pub fn is_upgrade_request(req: &Request<Body>) -> bool {
+    match req.headers().get(UPGRADE).map(|data| data.to_str()) {
+        Some(Ok(data)) => data.eq_ignore_ascii_case("websocket"),
+        _ => false,
+    }
+}
+
+/// Accepts the WebSocket handshake of the `req`, and serves the graphql-ws protocol of the
+/// subscriptions-transport-ws on the connection.
+pub fn upgrade(
+    context: Arc<Context>,
+    root_node: Arc<RootNode<'static, Query, Mutation>>,
+    req: Request<Body>,
+) -> Fallible<BoxFut> {
+    let protocols = match req.headers().get(SEC_WEBSOCKET_PROTOCOL) {
+        Some(data) => data.to_str()?,
+        None => "",
+    };
+    if protocols.split(',').all(|data| data.trim() != PROTOCOL) {
+        info!("400: unsupported protocol: {:?}", protocols);
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return Ok(Box::new(futures::future::ok(response)));
+    }
+
+    let key = req.headers().get(SEC_WEBSOCKET_KEY).ok_or_err()?.to_str()?;
+    let accept = base64::encode(Sha1::digest(
+        format!("{}{}", key, WEBSOCKET_GUID).as_bytes(),
+    ));
+
+    // subscribe before the response so that the client does not miss the events after it.
+    let events = context.chat_repo.subscribe(EventFilter::All)?;
+    hyper::rt::spawn(
+        req.into_body()
+            .on_upgrade()
+            .map_err(|e| warn!("failed to upgrade: {:?}", e))
+            .and_then(move |upgraded| Connection {
+                socket: WebSocket::from_raw_socket(upgraded, Role::Server, None),
+                context,
+                root_node,
+                events,
+                subscriptions: HashMap::new(),
+                operations: VecDeque::new(),
+            }),
+    );
+
+    let response = Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header(UPGRADE, "websocket")
+        .header(CONNECTION, "Upgrade")
+        .header(SEC_WEBSOCKET_ACCEPT, accept)
+        .header(SEC_WEBSOCKET_PROTOCOL, PROTOCOL)
+        .body(Body::empty())?;
+    Ok(Box::new(futures::future::ok(response)))
+}
+
+/// A WebSocket connection that runs the operations of the client.
+///
+/// The queries and the mutations are executed once on the blocking section of the thread pool
+/// like the `graphql`. The subscriptions are executed as a query of the `Subscription` for each
+/// event until the client stops them.
+struct Connection {
+    socket: WebSocket<Upgraded>,
+    context: Arc<Context>,
+    root_node: Arc<RootNode<'static, Query, Mutation>>,
+    events: UnboundedReceiver<ChatEvent>,
+    subscriptions: HashMap<String, GraphQLRequest>,
+    /// Queries and mutations that wait for the blocking section.
+    operations: VecDeque<(String, GraphQLRequest)>,
+}
+
+impl Future for Connection {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.poll_connection().or_else(|e| {
+            info!("close graphql-ws connection: {:?}", e);
+            Ok(Async::Ready(()))
+        })
+    }
+}
+
+impl Connection {
+    fn poll_connection(&mut self) -> Fallible<Async<()>> {
+        loop {
+            match self.socket.read_message() {
+                Ok(Message::Text(data)) => self.on_message(&data)?,
+                Ok(_) => (),
+                Err(tungstenite::Error::ConnectionClosed) => return Ok(Async::Ready(())),
+                Err(ref e) if is_would_block(e) => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        // the task is notified when the blocking section is available.
+        while let Some((id, request)) = self.operations.front() {
+            let response = {
+                let root_node = &self.root_node;
+                // the long polling returns nothing since the operation is executed once.
+                let context = self.context.with_received_events(vec![], false);
+                match tokio_threadpool::blocking(|| {
+                    serde_json::to_value(request.execute(root_node, &context))
+                })? {
+                    Async::Ready(data) => data?,
+                    Async::NotReady => break,
+                }
+            };
+            let id = id.to_owned();
+            self.operations.pop_front();
+            self.send(json!({"type": "data", "id": id, "payload": response}))?;
+            self.send(json!({"type": "complete", "id": id}))?;
+        }
+
+        loop {
+            match self.events.poll() {
+                Ok(Async::Ready(Some(event))) => self.on_event(event)?,
+                Ok(Async::Ready(None)) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+                Err(e) => failure::bail!("failed to receive event: {:?}", e),
+            }
+        }
+
+        match self.socket.write_pending() {
+            Ok(()) => Ok(Async::NotReady),
+            Err(tungstenite::Error::ConnectionClosed) => Ok(Async::Ready(())),
+            Err(ref e) if is_would_block(e) => Ok(Async::NotReady),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn on_message(&mut self, data: &str) -> Fallible<()> {
+        let message = match serde_json::from_str::<ClientMessage>(data) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("unsupported message: {:?}", e);
+                return self.send(json!({
+                    "type": "connection_error",
+                    "payload": {"message": e.to_string()},
+                }));
+            }
+        };
+
+        match message {
+            ClientMessage::ConnectionInit {} => self.send(json!({"type": "connection_ack"})),
+            ClientMessage::Start { id, payload } => {
+                match convert_subscription_to_query(&payload.query) {
+                    Some(query) => {
+                        let request =
+                            GraphQLRequest::new(query, payload.operation_name, payload.variables);
+                        self.subscriptions.insert(id, request);
+                        Ok(())
+                    }
+                    None => {
+                        let request = GraphQLRequest::new(
+                            payload.query,
+                            payload.operation_name,
+                            payload.variables,
+                        );
+                        self.operations.push_back((id, request));
+                        Ok(())
+                    }
+                }
+            }
+            ClientMessage::Stop { id } => {
+                self.subscriptions.remove(&id);
+                self.send(json!({"type": "complete", "id": id}))
+            }
+            ClientMessage::ConnectionTerminate {} => match self.socket.close(None) {
+                Err(ref e) if is_would_block(e) => Ok(()),
+                ret => Ok(ret?),
+            },
+        }
+    }
+
+    fn on_event(&mut self, event: ChatEvent) -> Fallible<()> {
+        if self.subscriptions.is_empty() {
+            return Ok(());
+        }
+
+        let root_node = RootNode::new(Subscription { event }, EmptyMutation::new());
+        let context = self.context.with_received_events(vec![], false);
+        let mut messages = vec![];
+        let mut failed_ids = vec![];
+        for (id, request) in &self.subscriptions {
+            match execute_subscription(request, &root_node, &context)? {
+                Ok(Some(payload)) => {
+                    messages.push(json!({"type": "data", "id": id, "payload": payload}))
+                }
+                Ok(None) => (),
+                Err(payload) => {
+                    messages.push(json!({"type": "error", "id": id, "payload": payload}));
+                    failed_ids.push(id.to_owned());
+                }
+            }
+        }
+
+        for id in failed_ids {
+            self.subscriptions.remove(&id);
+        }
+        for message in messages {
+            self.send(message)?;
+        }
+        Ok(())
+    }
+
+    /// Queues the `message`. The queue is flushed by the `poll`.
+    fn send(&mut self, message: serde_json::Value) -> Fallible<()> {
+        match self
+            .socket
+            .write_message(Message::Text(message.to_string()))
+        {
+            Err(ref e) if is_would_block(e) => Ok(()),
+            ret => Ok(ret?),
+        }
+    }
+}
+
+/// Returns the payload of the `data` message, or `None` if the event does not match any field of
+/// the `request`. The inner `Err` is the payload of the `error` message if the `request` is not a
+/// valid operation.
+fn execute_subscription(
+    request: &GraphQLRequest,
+    root_node: &RootNode<'static, Subscription, EmptyMutation<Context>>,
+    context: &Context,
+) -> Fallible<Result<Option<serde_json::Value>, serde_json::Value>> {
+    let response = request.execute(root_node, context);
+    let is_ok = response.is_ok();
+    let payload = serde_json::to_value(response)?;
+    if !is_ok {
+        return Ok(Err(payload));
+    }
+
+    let is_empty = payload.get("errors").is_none()
+        && match payload.get("data").and_then(|data| data.as_object()) {
+            Some(data) => data.values().all(|data| data.is_null()),
+            None => true,
+        };
+    if is_empty {
+        Ok(Ok(None))
+    } else {
+        Ok(Ok(Some(payload)))
+    }
+}
+
+/// Returns the `query` whose `subscription` operations are replaced with the `query` operations, or
+/// `None` if the `query` has no `subscription` operation.
+///
+/// The `RootNode` of juniper 0.14 has no subscription type, e.g. the `concrete_subscription_type`
+/// of its schema always returns `None`, so that the `Subscription` cannot be a part of the schema.
+fn convert_subscription_to_query(query: &str) -> Option<String> {
+    let mut converted = String::new();
+    let mut last_index = 0;
+    let mut depth = 0;
+    // the operation type is the first token of a definition, the others are names of the
+    // variables, the fragments, etc.
+    let mut is_definition_start = true;
+    for token in Lexer::new(query) {
+        // the invalid query is reported by the execution.
+        let token = match token {
+            Ok(data) => data,
+            Err(_) => break,
+        };
+        match token.item {
+            Token::CurlyOpen => depth += 1,
+            Token::CurlyClose => depth -= 1,
+            Token::Name("subscription") if depth == 0 && is_definition_start => {
+                converted.push_str(&query[last_index..token.start.index()]);
+                converted.push_str("query");
+                last_index = token.end.index();
+            }
+            Token::EndOfFile => break,
+            _ => (),
+        }
+        is_definition_start = depth == 0 && token.item == Token::CurlyClose;
+    }
+
+    if last_index == 0 {
+        return None;
+    }
+
+    converted.push_str(&query[last_index..]);
+    Some(converted)
+}
+
+fn is_would_block(e: &tungstenite::Error) -> bool {
+    match e {
+        tungstenite::Error::Io(e) => e.kind() == ErrorKind::WouldBlock,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use crate::data::db::entity::dev_flex_chat_entity::{
+        ChannelEntity, ChannelID, CommentEntity, CommentID,
+    };
+    use crate::data::db::in_memory_chat_database::InMemoryChatDatabase;
+    use crate::data::repository::dev_flex_chat_repository::DevFlexChatRepository;
+
+    use super::*;
+
+    #[test]
+    fn test_convert_subscription_to_query() {
+        assert_eq!(
+            convert_subscription_to_query(
+                "subscription Added($id: ID!) { commentAdded(channelId: $id) { subscription: id } }"
+            ),
+            Some(
+                "query Added($id: ID!) { commentAdded(channelId: $id) { subscription: id } }"
+                    .into()
+            )
+        );
+        assert_eq!(
+            convert_subscription_to_query(
+                "query Q($subscription: ID!) { channel(id: $subscription) { id } }\n\
+                 fragment subscription on Channel { id }\n\
+                 subscription { channelAdded { ...subscription } }"
+            ),
+            Some(
+                "query Q($subscription: ID!) { channel(id: $subscription) { id } }\n\
+                 fragment subscription on Channel { id }\n\
+                 query { channelAdded { ...subscription } }"
+                    .into()
+            )
+        );
+        assert_eq!(convert_subscription_to_query("{ channels { id } }"), None);
+        assert_eq!(
+            convert_subscription_to_query("query { channels { id } }"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_execute_subscription() -> Fallible<()> {
+        let context = Context::new(
+            DevFlexChatRepository::new(Box::new(InMemoryChatDatabase::new())),
+            Duration::from_secs(30),
+        );
+        let channel_id = ChannelID(uuid::Uuid::new_v4());
+        let comment = CommentEntity {
+            id: CommentID(uuid::Uuid::new_v4()),
+            channel_id: channel_id.clone(),
+            name: "name".into(),
+            message: "hello".into(),
+            sequence: 1,
+            created_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        let request = GraphQLRequest::new(
+            convert_subscription_to_query(
+                "subscription($id: ID!) { commentAdded(channelId: $id) { message sequence } }",
+            )
+            .ok_or_err()?,
+            None,
+            Some(InputValue::object(
+                vec![("id", InputValue::scalar(channel_id.0.to_string()))]
+                    .into_iter()
+                    .collect(),
+            )),
+        );
+
+        let root_node = RootNode::new(
+            Subscription {
+                event: ChatEvent::CommentPosted(comment.clone()),
+            },
+            EmptyMutation::new(),
+        );
+        let payload = execute_subscription(&request, &root_node, &context)?.unwrap();
+        assert_eq!(
+            payload.ok_or_err()?,
+            json!({"data": {"commentAdded": {"message": "hello", "sequence": 1}}})
+        );
+
+        let root_node = RootNode::new(
+            Subscription {
+                event: ChatEvent::CommentPosted(CommentEntity {
+                    channel_id: ChannelID(uuid::Uuid::new_v4()),
+                    ..comment
+                }),
+            },
+            EmptyMutation::new(),
+        );
+        assert!(execute_subscription(&request, &root_node, &context)?
+            .unwrap()
+            .is_none());
+
+        let root_node = RootNode::new(
+            Subscription {
+                event: ChatEvent::ChannelCreated(ChannelEntity {
+                    id: channel_id,
+                    name: "General".into(),
+                    last_sequence: 0,
+                    created_at: Utc::now(),
+                    updated_at: Utc::now(),
+                }),
+            },
+            EmptyMutation::new(),
+        );
+        assert!(execute_subscription(&request, &root_node, &context)?
+            .unwrap()
+            .is_none());
+        Ok(())
+    }
+}
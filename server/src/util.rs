@@ -14,10 +14,183 @@
  * limitations under the License.
  */
 
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use crate::prelude::*;
+
+pub fn get_archive_dir_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("archives"))
+        .unwrap_or_else(|| std::path::Path::new("archives").to_owned())
+}
 
 pub fn get_database_file_path(database_path: Option<PathBuf>) -> PathBuf {
     database_path
         .map(|dir| dir.join("database.toml"))
         .unwrap_or_else(|| std::path::Path::new("database.toml").to_owned())
 }
+
+pub fn get_database_lock_file_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("database.lock"))
+        .unwrap_or_else(|| std::path::Path::new("database.lock").to_owned())
+}
+
+pub fn get_database_log_file_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("database.log"))
+        .unwrap_or_else(|| std::path::Path::new("database.log").to_owned())
+}
+
+pub fn get_database_quarantine_file_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("database.quarantine"))
+        .unwrap_or_else(|| std::path::Path::new("database.quarantine").to_owned())
+}
+
+pub fn get_database_sqlite_file_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("database.sqlite"))
+        .unwrap_or_else(|| std::path::Path::new("database.sqlite").to_owned())
+}
+
+pub fn get_migration_backup_dir_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("migration-backups"))
+        .unwrap_or_else(|| std::path::Path::new("migration-backups").to_owned())
+}
+
+pub fn get_retention_file_path(database_path: Option<PathBuf>) -> PathBuf {
+    database_path
+        .map(|dir| dir.join("retention.toml"))
+        .unwrap_or_else(|| std::path::Path::new("retention.toml").to_owned())
+}
+
+pub fn get_temporary_file_path(file_path: &Path) -> Fallible<PathBuf> {
+    append_file_extension(file_path, "tmp")
+}
+
+pub fn get_backup_file_path(file_path: &Path) -> Fallible<PathBuf> {
+    append_file_extension(file_path, "bak")
+}
+
+/// Removes the backup file of the `file_path`, e.g. that has the content of the previous key.
+pub fn remove_backup_file(file_path: &Path) -> Fallible<()> {
+    let backup_path = get_backup_file_path(file_path)?;
+    if backup_path.exists() {
+        std::fs::remove_file(&backup_path)?;
+    }
+    Ok(())
+}
+
+/// Replaces the `file_path` with the `data` atomically.
+///
+/// The `data` is written and fsynced to a temporary file in the same directory and then renamed
+/// over the `file_path`, so that a crash leaves either the previous or the new generation. The
+/// previous generation is kept as the backup file.
+pub fn write_file_atomically<T: AsRef<Path>>(file_path: T, data: &[u8]) -> Fallible<()> {
+    write_file_atomically_with(file_path.as_ref(), |file| Ok(file.write_all(data)?))
+}
+
+fn write_file_atomically_with<F>(file_path: &Path, write: F) -> Fallible<()>
+where
+    F: FnOnce(&mut File) -> Fallible<()>,
+{
+    let tmp_path = get_temporary_file_path(file_path)?;
+    let ret = File::create(&tmp_path)
+        .map_err(Into::into)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            Ok(file.sync_all()?)
+        });
+    if let Err(e) = ret {
+        std::fs::remove_file(&tmp_path).ok();
+        return Err(e);
+    }
+
+    replace_file(&tmp_path, file_path)
+}
+
+/// Renames the `tmp_path` over the `file_path`, keeping the previous generation as the backup
+/// file.
+pub fn replace_file(tmp_path: &Path, file_path: &Path) -> Fallible<()> {
+    if file_path.exists() {
+        let backup_path = get_backup_file_path(file_path)?;
+        if backup_path.exists() {
+            std::fs::remove_file(&backup_path)?;
+        }
+
+        // the hard link keeps the file_path during the replacement.
+        if std::fs::hard_link(file_path, &backup_path).is_err() {
+            std::fs::copy(file_path, &backup_path)?;
+        }
+    }
+
+    std::fs::rename(tmp_path, file_path)?;
+    sync_parent_dir(file_path)
+}
+
+#[cfg(unix)]
+fn sync_parent_dir(file_path: &Path) -> Fallible<()> {
+    match file_path.parent() {
+        Some(parent) if parent != Path::new("") => File::open(parent)?.sync_all()?,
+        _ => File::open(".")?.sync_all()?,
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_file_path: &Path) -> Fallible<()> {
+    Ok(())
+}
+
+fn append_file_extension(file_path: &Path, extension: &str) -> Fallible<PathBuf> {
+    let file_name = file_path.file_name().ok_or_err()?.to_string_lossy();
+    Ok(file_path.with_file_name(format!("{}.{}", file_name, extension)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_file_atomically() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("database.toml");
+
+        write_file_atomically(&file_path, b"first")?;
+        assert!(!get_backup_file_path(&file_path)?.exists());
+
+        write_file_atomically(&file_path, b"second")?;
+        assert_eq!(std::fs::read(&file_path)?, b"second");
+        assert_eq!(std::fs::read(get_backup_file_path(&file_path)?)?, b"first");
+        assert!(!get_temporary_file_path(&file_path)?.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_file_atomically_interrupted() -> Fallible<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join("database.toml");
+        write_file_atomically(&file_path, b"first")?;
+
+        // e.g. disk full.
+        let ret = write_file_atomically_with(&file_path, |file| {
+            file.write_all(b"sec")?;
+            failure::bail!("no space left on device")
+        });
+        assert!(ret.is_err());
+        assert_eq!(std::fs::read(&file_path)?, b"first");
+        assert!(!get_temporary_file_path(&file_path)?.exists());
+
+        // e.g. crash before the rename.
+        std::fs::write(get_temporary_file_path(&file_path)?, b"sec")?;
+        assert_eq!(std::fs::read(&file_path)?, b"first");
+
+        write_file_atomically(&file_path, b"second")?;
+        assert_eq!(std::fs::read(&file_path)?, b"second");
+        Ok(())
+    }
+}